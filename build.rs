@@ -9,9 +9,12 @@
 //! new memory settings.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -24,11 +27,72 @@ fn main() {
 
     println!("cargo:rustc-link-search={}", out.display());
 
+    // gzip the web control panel so it can be embedded with include_bytes!
+    // and served as-is with `Content-Encoding: gzip`
+    let mut gz = GzEncoder::new(
+        File::create(out.join("index.html.gz")).unwrap(),
+        Compression::best(),
+    );
+    gz.write_all(include_bytes!("web/index.html")).unwrap();
+    gz.finish().unwrap();
+
+    // credentials.rs is optional. without it the sign starts with no wifi
+    // and has to be set up over usb serial
+    let credentials = Path::new("credentials.rs");
+    let defaults = if credentials.exists() {
+        let path = fs::canonicalize(credentials).unwrap();
+        format!(
+            "include!({:?});\n\
+             const DEFAULT_CREDENTIALS: Option<(&str, &str)> =\n    \
+             Some((WIFI_NETWORK, WIFI_PASSWORD));\n",
+            path
+        )
+    } else {
+        "const DEFAULT_CREDENTIALS: Option<(&str, &str)> = None;\n".into()
+    };
+    fs::write(out.join("credentials.rs"), defaults).unwrap();
+
+    // firmware.pub is the raw 32 byte ed25519 key updates are signed with.
+    // without it, over-the-air updates are turned off
+    let key = Path::new("firmware.pub");
+    let key = if key.exists() {
+        let path = fs::canonicalize(key).unwrap();
+        format!(
+            "const PUBLIC_KEY: Option<&[u8; 32]> =\n    \
+             Some(include_bytes!({:?}));\n",
+            path
+        )
+    } else {
+        "const PUBLIC_KEY: Option<&[u8; 32]> = None;\n".into()
+    };
+    fs::write(out.join("firmware_key.rs"), key).unwrap();
+
+    // updates are refused unless they're newer, so the version is packed
+    // into one number that only goes up: 1.2.3 is 1_002_003
+    let part = |name| env::var(name).unwrap().parse::<u32>().unwrap();
+    let major = part("CARGO_PKG_VERSION_MAJOR");
+    let minor = part("CARGO_PKG_VERSION_MINOR");
+    let patch = part("CARGO_PKG_VERSION_PATCH");
+    assert!(
+        minor < 1000 && patch < 1000,
+        "minor and patch stay under 1000"
+    );
+    let release = (major * 1000 + minor) * 1000 + patch;
+    fs::write(
+        out.join("release.rs"),
+        format!("const RELEASE: u32 = {};\n", release),
+    )
+    .unwrap();
+
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
+    // any file in the project changes. By specifying `memory.x` and
+    // the web assets here, we ensure the build script is only re-run
+    // when one of them is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=web/index.html");
+    println!("cargo:rerun-if-changed=credentials.rs");
+    println!("cargo:rerun-if-changed=firmware.pub");
+    println!("cargo:rerun-if-changed=Cargo.toml");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
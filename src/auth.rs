@@ -0,0 +1,418 @@
+//! pre-shared keys for the tcp command port. the server sends a random
+//! nonce in its welcome banner, and the client answers with
+//! `auth <key name> <hex hmac-sha256 of the nonce>` before anything else
+
+use core::fmt::{self, Write};
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex,
+};
+use heapless::{String, Vec};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const MAX_KEYS: usize = 4;
+pub const MAX_NAME_LEN: usize = 16;
+pub const MAX_SECRET_LEN: usize = 64;
+pub const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+/// the first failed attempt locks that address out for this long; every
+/// failure after that doubles it, up to `MAX_LOCKOUT_MS`
+const BASE_LOCKOUT_MS: u64 = 1000;
+const MAX_LOCKOUT_MS: u64 = 64 * 1000;
+/// how many addresses' failures are remembered at once
+const MAX_PEERS: usize = 8;
+
+pub static KEYRING: Mutex<CriticalSectionRawMutex, Keyring> =
+    Mutex::new(Keyring::new());
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    /// can look, but not change anything
+    ReadOnly,
+    Full,
+}
+
+impl Permission {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ro" => Some(Self::ReadOnly),
+            "rw" => Some(Self::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ReadOnly => "ro",
+            Self::Full => "rw",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Key {
+    pub name: String<MAX_NAME_LEN>,
+    pub permission: Permission,
+    pub secret: String<MAX_SECRET_LEN>,
+}
+
+impl Key {
+    /// `<name> <ro|rw> <secret>`. neither name nor secret may contain
+    /// whitespace
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut fields = s.split_whitespace();
+        let key = Self {
+            name: String::try_from(fields.next()?).ok()?,
+            permission: Permission::from_name(fields.next()?)?,
+            secret: String::try_from(fields.next()?).ok()?,
+        };
+        fields.next().is_none().then_some(key)
+    }
+
+    /// the inverse of `parse`
+    pub fn write(&self, out: &mut impl Write) -> fmt::Result {
+        let permission = self.permission.name();
+        write!(out, "{} {} {}", self.name, permission, self.secret)
+    }
+}
+
+/// the hmac of `nonce` (as it appears in the banner) under `secret`
+pub fn sign(secret: &[u8], nonce: &str) -> [u8; MAC_LEN] {
+    let mut mac = HmacSha256::new_from_slice(secret)
+        .expect("hmac takes keys of any length");
+    mac.update(nonce.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// the nonce as it's sent in the banner
+pub fn nonce_hex(nonce: &[u8; NONCE_LEN]) -> String<{ NONCE_LEN * 2 }> {
+    let mut hex = String::new();
+    for b in nonce {
+        let _ = write!(hex, "{:02x}", b);
+    }
+    hex
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut out = [0; N];
+    for (b, pair) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *b = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(out)
+}
+
+pub struct Keyring {
+    keys: Vec<Key, MAX_KEYS>,
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Keyring {
+    pub const fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// with no keys, the command port is open to everyone
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// adds a key, or replaces the one with the same name
+    pub fn add(&mut self, key: Key) -> Result<(), crate::Error> {
+        let _ = self.remove(&key.name);
+        self.keys.push(key).map_err(|_| crate::Error::Full)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Key, crate::Error> {
+        let index = self
+            .keys
+            .iter()
+            .position(|k| k.name == name)
+            .ok_or(crate::Error::OutOfRange)?;
+        Ok(self.keys.remove(index))
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    /// checks an `auth <name> <hex mac>` line against `nonce`
+    pub fn verify(&self, line: &str, nonce: &str) -> Option<Permission> {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != "auth" {
+            return None;
+        }
+        let name = fields.next()?;
+        let tag: [u8; MAC_LEN] = from_hex(fields.next()?)?;
+        if fields.next().is_some() {
+            return None;
+        }
+
+        let key = self.keys.iter().find(|k| k.name == name)?;
+        let mut mac =
+            HmacSha256::new_from_slice(key.secret.as_bytes()).ok()?;
+        mac.update(nonce.as_bytes());
+        // constant time, so the tag can't be guessed a byte at a time
+        mac.verify_slice(&tag).ok()?;
+        Some(key.permission)
+    }
+}
+
+/// slows down guessing. each address gets its own backoff, so one client
+/// getting it wrong doesn't lock out the rest. time only comes in through
+/// the `now_ms` arguments
+pub struct Limiter<P> {
+    peers: Vec<Backoff<P>, MAX_PEERS>,
+}
+
+struct Backoff<P> {
+    peer: P,
+    failures: u8,
+    until_ms: u64,
+}
+
+impl<P: Copy + PartialEq> Default for Limiter<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Copy + PartialEq> Limiter<P> {
+    pub const fn new() -> Self {
+        Self { peers: Vec::new() }
+    }
+
+    /// how long until `peer` may try again, if it can't yet
+    pub fn wait_ms(&self, peer: P, now_ms: u64) -> Option<u64> {
+        let backoff = self.peers.iter().find(|b| b.peer == peer)?;
+        (now_ms < backoff.until_ms).then(|| backoff.until_ms - now_ms)
+    }
+
+    pub fn failed(&mut self, peer: P, now_ms: u64) {
+        let index = match self.peers.iter().position(|b| b.peer == peer) {
+            Some(index) => index,
+            None => {
+                if self.peers.is_full() {
+                    // forget whoever's lockout ended first
+                    let oldest = (0..self.peers.len())
+                        .min_by_key(|&i| self.peers[i].until_ms)
+                        .unwrap_or(0);
+                    self.peers.swap_remove(oldest);
+                }
+                let fresh = Backoff {
+                    peer,
+                    failures: 0,
+                    until_ms: 0,
+                };
+                let _ = self.peers.push(fresh);
+                self.peers.len() - 1
+            }
+        };
+        let backoff = &mut self.peers[index];
+        let lockout = BASE_LOCKOUT_MS << backoff.failures.min(16);
+        backoff.until_ms = now_ms + lockout.min(MAX_LOCKOUT_MS);
+        backoff.failures = backoff.failures.saturating_add(1);
+    }
+
+    pub fn succeeded(&mut self, peer: P) {
+        self.peers.retain(|b| b.peer != peer);
+    }
+}
+
+/// with no keys set up, anyone may change anything. otherwise only the
+/// command port may, once it's been given a key
+pub async fn open() -> bool {
+    KEYRING.lock().await.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: &str = "00112233445566778899aabbccddeeff";
+
+    fn keyring() -> Keyring {
+        let mut keyring = Keyring::new();
+        keyring
+            .add(Key::parse("admin rw hunter2").unwrap())
+            .unwrap();
+        keyring
+            .add(Key::parse("panel ro letmein").unwrap())
+            .unwrap();
+        keyring
+    }
+
+    /// what a client sends for `name` with `secret`
+    fn answer(name: &str, secret: &str) -> std::string::String {
+        let mut line = std::format!("auth {} ", name);
+        for b in sign(secret.as_bytes(), NONCE) {
+            line.push_str(&std::format!("{:02x}", b));
+        }
+        line
+    }
+
+    #[test]
+    fn signs_like_hmac_sha256() {
+        // rfc 4231, test case 2
+        let tag = sign(b"Jefe", "what do ya want for nothing?");
+        let hex: std::string::String =
+            tag.iter().map(|b| std::format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c7\
+             5a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(nonce_hex(&[0xab; NONCE_LEN]).as_str(), "ab".repeat(16));
+    }
+
+    #[test]
+    fn right_tags_get_their_keys_permission() {
+        let keyring = keyring();
+        let admin = answer("admin", "hunter2");
+        assert_eq!(keyring.verify(&admin, NONCE), Some(Permission::Full));
+        let panel = answer("panel", "letmein");
+        assert_eq!(keyring.verify(&panel, NONCE), Some(Permission::ReadOnly));
+        // hex digits in either case
+        let upper =
+            admin.to_uppercase().replacen("AUTH ADMIN", "auth admin", 1);
+        assert_eq!(keyring.verify(&upper, NONCE), Some(Permission::Full));
+    }
+
+    #[test]
+    fn wrong_tags_and_unknown_keys_get_nothing() {
+        let keyring = keyring();
+        // the other key's secret, or the right one for another nonce
+        let swapped = answer("admin", "letmein");
+        assert_eq!(keyring.verify(&swapped, NONCE), None);
+        let admin = answer("admin", "hunter2");
+        let other = "ffeeddccbbaa99887766554433221100";
+        assert_eq!(keyring.verify(&admin, other), None);
+        // a name nobody added, with a right tag for its secret
+        assert_eq!(keyring.verify(&answer("root", "hunter2"), NONCE), None);
+
+        let mut flipped = admin.clone();
+        let last = if flipped.ends_with('0') { "1" } else { "0" };
+        flipped.pop();
+        flipped.push_str(last);
+        assert_eq!(keyring.verify(&flipped, NONCE), None);
+    }
+
+    #[test]
+    fn malformed_answers_get_nothing() {
+        let keyring = keyring();
+        let admin = answer("admin", "hunter2");
+        for line in [
+            "",
+            "auth",
+            "auth admin",
+            &admin[..admin.len() - 2],
+            &std::format!("{} 00", admin),
+            &std::format!("{}0", admin),
+            &admin.replacen("auth", "AUTH", 1),
+            &admin.replacen("auth", "4", 1),
+            &std::format!("{}zz", &admin[..admin.len() - 2]),
+            &std::format!("{}é", &admin[..admin.len() - 2]),
+        ] {
+            assert_eq!(keyring.verify(line, NONCE), None, "{:?}", line);
+        }
+        assert_eq!(Keyring::new().verify(&admin, NONCE), None);
+    }
+
+    #[test]
+    fn keys_are_replaced_by_name_and_limited() {
+        let mut keyring = keyring();
+        keyring
+            .add(Key::parse("admin ro changed").unwrap())
+            .unwrap();
+        assert_eq!(keyring.keys().len(), 2);
+        let changed = answer("admin", "changed");
+        let permission = keyring.verify(&changed, NONCE);
+        assert_eq!(permission, Some(Permission::ReadOnly));
+
+        keyring.add(Key::parse("c ro c").unwrap()).unwrap();
+        keyring.add(Key::parse("d ro d").unwrap()).unwrap();
+        let full = keyring.add(Key::parse("e ro e").unwrap());
+        assert_eq!(full, Err(crate::Error::Full));
+        assert_eq!(keyring.remove("nobody"), Err(crate::Error::OutOfRange));
+        assert_eq!(keyring.remove("c").unwrap().secret.as_str(), "c");
+        keyring.clear();
+        assert!(keyring.is_empty());
+    }
+
+    #[test]
+    fn keys_parse_and_write_back() {
+        let key = Key::parse("admin rw hunter2").unwrap();
+        let mut out: String<64> = String::new();
+        key.write(&mut out).unwrap();
+        assert_eq!(out, "admin rw hunter2");
+        for bad in ["admin", "admin rw", "admin xx s", "a rw s extra"] {
+            assert_eq!(Key::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn failures_double_the_wait() {
+        let mut limiter = Limiter::new();
+        assert_eq!(limiter.wait_ms(1, 0), None);
+        let mut now = 0;
+        for lockout in [1, 2, 4, 8, 16, 32, 64, 64, 64] {
+            limiter.failed(1, now);
+            assert_eq!(limiter.wait_ms(1, now), Some(lockout * 1000));
+            assert_eq!(
+                limiter.wait_ms(1, now + 500),
+                Some(lockout * 1000 - 500)
+            );
+            now += lockout * 1000;
+            assert_eq!(limiter.wait_ms(1, now), None);
+        }
+        limiter.succeeded(1);
+        limiter.failed(1, now);
+        assert_eq!(limiter.wait_ms(1, now), Some(1000));
+    }
+
+    #[test]
+    fn each_peer_waits_on_its_own() {
+        let mut limiter = Limiter::new();
+        limiter.failed([10, 0, 0, 2], 0);
+        limiter.failed([10, 0, 0, 2], 1000);
+        assert_eq!(limiter.wait_ms([10, 0, 0, 2], 1000), Some(2000));
+        assert_eq!(limiter.wait_ms([10, 0, 0, 3], 1000), None);
+
+        limiter.failed([10, 0, 0, 3], 1000);
+        limiter.succeeded([10, 0, 0, 3]);
+        assert_eq!(limiter.wait_ms([10, 0, 0, 3], 1000), None);
+        assert_eq!(limiter.wait_ms([10, 0, 0, 2], 1000), Some(2000));
+    }
+
+    #[test]
+    fn the_longest_quiet_peer_is_forgotten_first() {
+        let mut limiter = Limiter::new();
+        for peer in 0..MAX_PEERS as u8 {
+            limiter.failed(peer, peer as u64 * 100);
+        }
+        // peer 0 keeps going, so it isn't the quietest any more
+        limiter.failed(0, 2000);
+        limiter.failed(MAX_PEERS as u8, 2000);
+        assert_eq!(limiter.wait_ms(1, 2000), None);
+        assert_eq!(limiter.wait_ms(0, 2000), Some(2000));
+        for peer in 2..=MAX_PEERS as u8 {
+            assert!(limiter.wait_ms(peer, 1000).is_some(), "{}", peer);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use core::cell::Cell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::String;
+
+/// unix time (ms) at the last sync, and when that was by our own clock
+#[derive(Clone, Copy)]
+struct Synced {
+    unix_ms: u64,
+    at: Instant,
+}
+
+static SYNC: Mutex<CriticalSectionRawMutex, Cell<Option<Synced>>> =
+    Mutex::new(Cell::new(None));
+
+static CONFIG: Mutex<CriticalSectionRawMutex, Cell<ClockConfig>> =
+    Mutex::new(Cell::new(ClockConfig::new()));
+
+/// daylight saving rules we know about
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Dst {
+    None,
+    /// last sunday of march to last sunday of october, 01:00 utc
+    Eu,
+    /// second sunday of march to first sunday of november, 02:00 local
+    Us,
+}
+
+impl Dst {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "eu" => Some(Self::Eu),
+            "us" => Some(Self::Us),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Eu => "eu",
+            Self::Us => "us",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HourFormat {
+    H12,
+    H24,
+}
+
+#[derive(Clone, Copy)]
+pub struct ClockConfig {
+    /// standard time offset from utc
+    pub offset_minutes: i16,
+    pub dst: Dst,
+    pub format: HourFormat,
+}
+
+impl ClockConfig {
+    pub const fn new() -> Self {
+        Self {
+            offset_minutes: 0,
+            dst: Dst::None,
+            format: HourFormat::H24,
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: i32,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 = sunday
+    pub weekday: u8,
+}
+
+pub fn set_time(unix_ms: u64) {
+    SYNC.lock(|s| {
+        s.set(Some(Synced {
+            unix_ms,
+            at: Instant::now(),
+        }))
+    });
+}
+
+/// seconds since the unix epoch, if we've been synced
+pub fn now() -> Option<u64> {
+    SYNC.lock(|s| s.get())
+        .map(|s| (s.unix_ms + s.at.elapsed().as_millis()) / 1000)
+}
+
+pub fn config() -> ClockConfig {
+    CONFIG.lock(|c| c.get())
+}
+
+pub fn set_config(config: ClockConfig) {
+    CONFIG.lock(|c| c.set(config));
+}
+
+/// the current local time, if we've been synced
+pub fn local_now() -> Option<DateTime> {
+    now().map(|t| local_time(t, &config()))
+}
+
+/// the current time as shown on the displays, if we've been synced
+pub fn face() -> Option<String<16>> {
+    let config = config();
+    now().map(|t| format(&local_time(t, &config), config.format))
+}
+
+/// days since 1970-01-01 to (year, month, day).
+/// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + (month <= 2) as i64) as i32;
+    (year, month, day)
+}
+
+/// (year, month, day) to days since 1970-01-01
+pub fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let y = year as i64 - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = month as i64;
+    let doy =
+        (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// 0 = sunday
+fn weekday(days: i64) -> u8 {
+    // 1970-01-01 was a thursday
+    (days + 4).rem_euclid(7) as u8
+}
+
+/// the day of month of the `n`th sunday (1-based) in a month,
+/// or the last one if `n` is 0
+fn nth_sunday(year: i32, month: u8, n: u8) -> u8 {
+    if n == 0 {
+        let next = if month == 12 {
+            days_from_civil(year + 1, 1, 1)
+        } else {
+            days_from_civil(year, month + 1, 1)
+        };
+        let last = next - 1;
+        let (_, _, day) = civil_from_days(last - weekday(last) as i64);
+        day
+    } else {
+        let first = days_from_civil(year, month, 1);
+        let first_sunday = (7 - weekday(first)) % 7;
+        1 + first_sunday + 7 * (n - 1)
+    }
+}
+
+/// utc seconds at `hour`:00 on a given day
+fn utc_secs(year: i32, month: u8, day: u8, hour: u8) -> i64 {
+    days_from_civil(year, month, day) * 86400 + hour as i64 * 3600
+}
+
+/// whether daylight saving applies at utc time `t`
+fn in_dst(t: i64, offset_secs: i64, dst: Dst) -> bool {
+    let (year, _, _) = civil_from_days((t + offset_secs).div_euclid(86400));
+    match dst {
+        Dst::None => false,
+        Dst::Eu => {
+            let start = utc_secs(year, 3, nth_sunday(year, 3, 0), 1);
+            let end = utc_secs(year, 10, nth_sunday(year, 10, 0), 1);
+            start <= t && t < end
+        }
+        Dst::Us => {
+            // 02:00 local standard time, and 02:00 local daylight time
+            let start =
+                utc_secs(year, 3, nth_sunday(year, 3, 2), 2) - offset_secs;
+            let end = utc_secs(year, 11, nth_sunday(year, 11, 1), 2)
+                - offset_secs
+                - 3600;
+            start <= t && t < end
+        }
+    }
+}
+
+pub fn local_time(unix_secs: u64, config: &ClockConfig) -> DateTime {
+    let t = unix_secs as i64;
+    let mut offset = config.offset_minutes as i64 * 60;
+    if in_dst(t, offset, config.dst) {
+        offset += 3600;
+    }
+
+    let local = t + offset;
+    let days = local.div_euclid(86400);
+    let secs = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+
+    DateTime {
+        year,
+        month,
+        day,
+        hour: (secs / 3600) as u8,
+        minute: (secs / 60 % 60) as u8,
+        second: (secs % 60) as u8,
+        weekday: weekday(days),
+    }
+}
+
+/// "13:05" or "1:05PM"
+pub fn format(time: &DateTime, format: HourFormat) -> String<16> {
+    let mut out = String::new();
+    let _ = match format {
+        HourFormat::H24 => write!(out, "{:02}:{:02}", time.hour, time.minute),
+        HourFormat::H12 => {
+            let hour = match time.hour % 12 {
+                0 => 12,
+                h => h,
+            };
+            let suffix = if time.hour < 12 { "AM" } else { "PM" };
+            write!(out, "{}:{:02}{}", hour, time.minute, suffix)
+        }
+    };
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(year: i32, month: u8, day: u8, hour: u8, minute: u8) -> u64 {
+        let secs = utc_secs(year, month, day, hour) + minute as i64 * 60;
+        secs as u64
+    }
+
+    fn config(offset_minutes: i16, dst: Dst) -> ClockConfig {
+        ClockConfig {
+            offset_minutes,
+            dst,
+            format: HourFormat::H24,
+        }
+    }
+
+    /// local (hour, minute, second) a second before and at `t`
+    fn around(t: u64, config: &ClockConfig) -> [(u8, u8, u8); 2] {
+        [t - 1, t].map(|t| {
+            let local = local_time(t, config);
+            (local.hour, local.minute, local.second)
+        })
+    }
+
+    #[test]
+    fn dates_and_weekdays() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 2, 29), 11016);
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        // 2024-10-18 was a friday
+        let friday =
+            local_time(utc(2024, 10, 18, 12, 0), &config(0, Dst::None));
+        assert_eq!(friday.weekday, 5);
+        assert_eq!((friday.year, friday.month, friday.day), (2024, 10, 18));
+    }
+
+    #[test]
+    fn sundays() {
+        assert_eq!(nth_sunday(2024, 3, 0), 31);
+        assert_eq!(nth_sunday(2024, 10, 0), 27);
+        assert_eq!(nth_sunday(2024, 3, 2), 10);
+        assert_eq!(nth_sunday(2024, 11, 1), 3);
+        // december rolls over into the next year
+        assert_eq!(nth_sunday(2023, 12, 0), 31);
+        // a month that starts on a sunday
+        assert_eq!(nth_sunday(2023, 10, 1), 1);
+    }
+
+    #[test]
+    fn eu_transitions_are_at_one_utc() {
+        let cet = config(60, Dst::Eu);
+        // spring forward: 01:59:59 cet, then 03:00 cest
+        let spring = around(utc(2024, 3, 31, 1, 0), &cet);
+        assert_eq!(spring, [(1, 59, 59), (3, 0, 0)]);
+        // fall back: 02:59:59 cest, then 02:00 cet
+        let fall = around(utc(2024, 10, 27, 1, 0), &cet);
+        assert_eq!(fall, [(2, 59, 59), (2, 0, 0)]);
+
+        // the same instant everywhere in the eu, whatever the offset
+        let wet = config(0, Dst::Eu);
+        let spring = around(utc(2024, 3, 31, 1, 0), &wet);
+        assert_eq!(spring, [(0, 59, 59), (2, 0, 0)]);
+    }
+
+    #[test]
+    fn us_transitions_are_at_two_local() {
+        let eastern = config(-300, Dst::Us);
+        // 01:59:59 est, then 03:00 edt
+        let spring = around(utc(2024, 3, 10, 7, 0), &eastern);
+        assert_eq!(spring, [(1, 59, 59), (3, 0, 0)]);
+        // 01:59:59 edt, then 01:00 est
+        let fall = around(utc(2024, 11, 3, 6, 0), &eastern);
+        assert_eq!(fall, [(1, 59, 59), (1, 0, 0)]);
+
+        let pacific = config(-480, Dst::Us);
+        let spring = around(utc(2024, 3, 10, 10, 0), &pacific);
+        assert_eq!(spring, [(1, 59, 59), (3, 0, 0)]);
+    }
+
+    #[test]
+    fn no_dst_is_just_the_offset() {
+        let jst = config(9 * 60, Dst::None);
+        let summer = local_time(utc(2024, 7, 1, 15, 30), &jst);
+        assert_eq!((summer.day, summer.hour, summer.minute), (2, 0, 30));
+        let india = config(5 * 60 + 30, Dst::Eu);
+        let winter = local_time(utc(2024, 1, 1, 0, 0), &india);
+        assert_eq!((winter.hour, winter.minute), (5, 30));
+    }
+
+    #[test]
+    fn twelve_and_twenty_four_hours() {
+        let at = |hour, minute| DateTime {
+            year: 2024,
+            month: 1,
+            day: 1,
+            hour,
+            minute,
+            second: 0,
+            weekday: 1,
+        };
+        let h12 = |hour, minute| format(&at(hour, minute), HourFormat::H12);
+        let h24 = |hour, minute| format(&at(hour, minute), HourFormat::H24);
+        assert_eq!(h12(0, 5), "12:05AM");
+        assert_eq!(h12(11, 59), "11:59AM");
+        assert_eq!(h12(12, 0), "12:00PM");
+        assert_eq!(h12(13, 5), "1:05PM");
+        assert_eq!(h24(0, 5), "00:05");
+        assert_eq!(h24(13, 5), "13:05");
+        assert_eq!(h24(23, 59), "23:59");
+    }
+}
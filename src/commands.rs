@@ -0,0 +1,584 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+use embassy_time::Instant;
+use heapless::String;
+
+use crate::auth::{self, KEYRING};
+use crate::clock::{self, ClockConfig, Dst, HourFormat};
+use crate::config::{self, Broker, Key, NetworkConfig, StaticIpv4};
+use crate::graphics::{self, Bitmap};
+use crate::playlist::{Content, Playlist, Slot};
+use crate::queue::{Message, URGENT};
+use crate::status;
+use crate::weather::{Unit, Weather};
+use crate::{
+    copy_str_bytes, Error, WeatherData, BRIGHTNESS, DATA, DISPLAYS,
+    MAX_BRIGHTNESS, PLAYLIST, QUEUE, QUEUED,
+};
+
+pub use crate::queue::Effect;
+
+/// enough to list a playlist of long texts. a listing that doesn't fit
+/// fails with `Error::Full`
+pub const MAX_STATUS_LEN: usize = 2048;
+
+pub struct CommandStatus {
+    pub mesg: String<MAX_STATUS_LEN>,
+}
+
+impl CommandStatus {
+    /// mesg may be up to `MAX_STATUS_LEN` chars
+    pub fn new(mesg: &str) -> Self {
+        let mut status = Self {
+            mesg: String::new(),
+        };
+        let _ = status.mesg.push_str(mesg);
+        status
+    }
+
+    /// `mesg` instead of nothing, e.g. for an empty list. clients can't
+    /// tell an empty reply from a slow one
+    fn or(self, mesg: &str) -> Self {
+        match self.mesg.is_empty() {
+            true => Self::new(mesg),
+            false => self,
+        }
+    }
+
+    /// `[*] error <code>: <what went wrong> :c`
+    pub fn error(e: Error) -> Self {
+        let mut status = Self::new("");
+        let _ = writeln!(status, "[*] error {}: {} :c", e.code(), e);
+        status
+    }
+}
+
+impl Write for CommandStatus {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.mesg.push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+/// queues a message for the animation loop and returns its id
+pub async fn enqueue(message: Message) -> Result<u16, Error> {
+    if let Content::Text(text) = &message.content {
+        graphics::check_text(text)?;
+    }
+    let now = Instant::now().as_secs();
+    let id = QUEUE
+        .lock()
+        .await
+        .push(message, now)
+        .inspect_err(|_| status::record_error("queue-full"))?;
+    QUEUED.signal(());
+    config::mark_dirty(Key::Messages);
+    Ok(id)
+}
+
+/// shows a message right away, interrupting whatever is playing
+pub async fn echo(text: &str, effect: Effect) -> Result<u16, Error> {
+    let mut message = Message::text(text, URGENT)?;
+    message.effect = effect;
+    enqueue(message).await
+}
+
+/// overrides the synced clock with a fixed string. an empty string
+/// goes back to the synced clock
+pub async fn set_clock(clock: &str) -> Result<(), Error> {
+    let clock = match clock {
+        "" => None,
+        s => {
+            graphics::check_text(s)?;
+            Some(copy_str_bytes(s.as_bytes())?)
+        }
+    };
+    DATA.lock().await.clock = clock;
+    config::mark_dirty(Key::Content);
+    Ok(())
+}
+
+/// `<utc offset in minutes> <none|eu|us> <12|24>`, e.g. `540 none 24`
+pub fn configure_clock(args: &str) -> Result<(), Error> {
+    let mut args = args.split_whitespace();
+    let mut next = || args.next().ok_or(Error::Malformed);
+
+    let offset_minutes: i32 = next()?.parse().map_err(|_| Error::Malformed)?;
+    let dst = Dst::from_name(next()?).ok_or(Error::Malformed)?;
+    let format = match next()? {
+        "12" => HourFormat::H12,
+        "24" => HourFormat::H24,
+        _ => return Err(Error::Malformed),
+    };
+    let offset_minutes = i16::try_from(offset_minutes)
+        .ok()
+        .filter(|m| (-12 * 60..=14 * 60).contains(m))
+        .ok_or(Error::OutOfRange)?;
+
+    clock::set_config(ClockConfig {
+        offset_minutes,
+        dst,
+        format,
+    });
+    config::mark_dirty(Key::Settings);
+    Ok(())
+}
+
+/// `=` followed by a compact report (see `Weather::decode`), or any
+/// other text to be scrolled as-is
+pub async fn set_weather(weather: &str) -> Result<(), Error> {
+    let weather = match weather.strip_prefix('=') {
+        Some(report) => WeatherData::Report(
+            Weather::decode(report).ok_or(Error::Malformed)?,
+        ),
+        None => {
+            graphics::check_text(weather)?;
+            WeatherData::Text(copy_str_bytes(weather.as_bytes())?)
+        }
+    };
+    DATA.lock().await.weather = Some(weather);
+    config::mark_dirty(Key::Content);
+    Ok(())
+}
+
+pub async fn set_weather_unit(unit: Unit) {
+    DATA.lock().await.weather_unit = unit;
+    config::mark_dirty(Key::Settings);
+}
+
+/// 0 (off) to `MAX_BRIGHTNESS`
+pub fn set_brightness(level: u8) -> Result<(), Error> {
+    if level > MAX_BRIGHTNESS {
+        return Err(Error::OutOfRange);
+    }
+    BRIGHTNESS.store(level, Ordering::Relaxed);
+    config::mark_dirty(Key::Settings);
+    Ok(())
+}
+
+/// shows a 32x8 picture across all panels for a few seconds,
+/// interrupting whatever is playing
+pub async fn draw_image(bitmap: &Bitmap) -> Result<u16, Error> {
+    let mut message = Message::new(Content::Image(*bitmap), URGENT);
+    message.effect = Effect::Scroll;
+    enqueue(message).await
+}
+
+/// `list`, `clear` or `del <id>`
+pub async fn edit_queue(args: &str) -> Result<CommandStatus, Error> {
+    let args = args.trim();
+    let (verb, rest) = args.split_once(' ').unwrap_or((args, ""));
+
+    let mut queue = QUEUE.lock().await;
+    match verb {
+        "" | "list" => {
+            let now = Instant::now().as_secs();
+            let mut status = CommandStatus::new("");
+            for message in queue.messages() {
+                message.write_status(&mut status, now)?;
+                status.write_char('\n')?;
+            }
+            return Ok(status.or("[*] nothing queued\n"));
+        }
+        "del" => {
+            let id = rest.trim().parse().map_err(|_| Error::OutOfRange);
+            queue.remove(id?)?;
+        }
+        "clear" => queue.clear(),
+        _ => return Err(Error::Malformed),
+    }
+    config::mark_dirty(Key::Messages);
+    Ok(CommandStatus::new("[*] queue updated! ^-^\n"))
+}
+
+/// `list`, `add <slot>`, `del <n>`, `on <n>`, `off <n>`, `clear` or
+/// `reset`. see `Slot::parse` for the slot format
+pub async fn edit_playlist(args: &str) -> Result<CommandStatus, Error> {
+    let args = args.trim();
+    let (verb, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let index = || rest.trim().parse().map_err(|_| Error::OutOfRange);
+
+    let mut playlist = PLAYLIST.lock().await;
+    match verb {
+        "" | "list" => {
+            let mut status = CommandStatus::new("");
+            for (i, slot) in playlist.slots().iter().enumerate() {
+                write!(status, "{} ", i)?;
+                slot.write(&mut status)?;
+                status.write_char('\n')?;
+            }
+            return Ok(status.or("[*] the playlist is empty\n"));
+        }
+        "add" => {
+            let slot = Slot::parse(rest).ok_or(Error::Malformed)?;
+            if let Content::Text(text) = &slot.content {
+                graphics::check_text(text)?;
+            }
+            playlist.add(slot)?;
+        }
+        "del" => {
+            playlist.remove(index()?)?;
+        }
+        "on" => playlist.set_enabled(index()?, true)?,
+        "off" => playlist.set_enabled(index()?, false)?,
+        "clear" => playlist.clear(),
+        "reset" => *playlist = Playlist::with_defaults(),
+        _ => return Err(Error::Malformed),
+    }
+    config::mark_dirty(Key::Playlist);
+    Ok(CommandStatus::new("[*] playlist updated! ^-^\n"))
+}
+
+/// `hex` (the default) or `art`. hex is the same format command `3`
+/// takes, so a readback can be drawn again as is
+pub async fn read_frame(args: &str) -> Result<CommandStatus, Error> {
+    let frame = DISPLAYS.snapshot().await;
+    let mut status = CommandStatus::new("");
+    match args.trim() {
+        "" | "hex" => {
+            graphics::write_hex(&mut status, &frame)?;
+            status.write_char('\n')?;
+        }
+        "art" => {
+            graphics::write_art(&mut status, &frame)?;
+        }
+        _ => return Err(Error::Malformed),
+    }
+    Ok(status)
+}
+
+/// `show`, `ssid <ssid>`, `pass [password]`, `ip <dhcp|address/prefix
+/// [gateway]>`, `id [id]`, `mqtt <none|address[:port]>`, `forget` or
+/// `reboot`. changes are used from the next boot
+pub async fn edit_network(args: &str) -> Result<CommandStatus, Error> {
+    let args = args.trim();
+    let (verb, rest) = args.split_once(' ').unwrap_or((args, ""));
+
+    let mut stored = config::NETWORK.lock().await;
+    let mut network = stored
+        .clone()
+        .or_else(NetworkConfig::compiled)
+        .unwrap_or_default();
+    match verb {
+        "" | "show" => {
+            let source = match *stored {
+                Some(_) => "saved",
+                None if NetworkConfig::compiled().is_some() => "compiled",
+                None => "none",
+            };
+            let password = match network.password.as_str() {
+                "" => "none",
+                _ => "set",
+            };
+            let mut status = CommandStatus::new("");
+            write!(
+                status,
+                "ssid={} pass={} source={} ip=",
+                network.ssid, password, source
+            )?;
+            match network.ipv4 {
+                Some(ipv4) => write!(status, "{}", ipv4)?,
+                None => write!(status, "dhcp")?,
+            }
+            let id = match network.id.as_str() {
+                "" => "chip",
+                id => id,
+            };
+            write!(status, " id={} mqtt=", id)?;
+            match network.mqtt {
+                Some(broker) => writeln!(status, "{}", broker)?,
+                None => writeln!(status, "none")?,
+            }
+            return Ok(status);
+        }
+        "ssid" if !rest.is_empty() => {
+            network.ssid =
+                String::try_from(rest).map_err(|_| Error::Truncated)?;
+        }
+        "pass" => {
+            network.password =
+                String::try_from(rest).map_err(|_| Error::Truncated)?;
+        }
+        "ip" => {
+            network.ipv4 = match rest {
+                "dhcp" => None,
+                ipv4 => Some(StaticIpv4::parse(ipv4).ok_or(Error::Malformed)?),
+            };
+        }
+        "id" => {
+            // nothing goes back to the chip's id
+            if !rest.is_empty() && !config::is_valid_id(rest) {
+                return Err(Error::Malformed);
+            }
+            network.id =
+                String::try_from(rest).map_err(|_| Error::Malformed)?;
+        }
+        "mqtt" => {
+            network.mqtt = match rest {
+                "none" => None,
+                broker => Some(Broker::parse(broker).ok_or(Error::Malformed)?),
+            };
+        }
+        "forget" => {
+            *stored = None;
+            config::mark_dirty(Key::Network);
+            return Ok(CommandStatus::new("[*] network forgotten\n"));
+        }
+        "reboot" => {
+            config::reboot();
+            return Ok(CommandStatus::new("[*] rebooting... ^-^\n"));
+        }
+        _ => return Err(Error::Malformed),
+    }
+    *stored = Some(network);
+    config::mark_dirty(Key::Network);
+    Ok(CommandStatus::new(
+        "[*] network saved! `wreboot` to use it ^-^\n",
+    ))
+}
+
+/// `list`, `add <name> <ro|rw> <secret>`, `del <name>` or `clear`. once
+/// there's a key, the tcp port wants one before it takes commands
+pub async fn edit_keys(args: &str) -> Result<CommandStatus, Error> {
+    let args = args.trim();
+    let (verb, rest) = args.split_once(' ').unwrap_or((args, ""));
+
+    let mut keyring = KEYRING.lock().await;
+    match verb {
+        "" | "list" => {
+            // never the secrets
+            let mut status = CommandStatus::new("");
+            for key in keyring.keys() {
+                let permission = key.permission.name();
+                writeln!(status, "{} {}", key.name, permission)?;
+            }
+            return Ok(status.or("[*] no keys\n"));
+        }
+        "add" => {
+            let key = auth::Key::parse(rest).ok_or(Error::Malformed)?;
+            keyring.add(key)?;
+        }
+        "del" => {
+            keyring.remove(rest.trim())?;
+        }
+        "clear" => keyring.clear(),
+        _ => return Err(Error::Malformed),
+    }
+    config::mark_dirty(Key::AuthKeys);
+    Ok(CommandStatus::new("[*] keys updated! ^-^\n"))
+}
+
+/// whether a command only looks at things, so read-only keys may use it
+pub fn is_read_only(argv: &str) -> bool {
+    if is_status(argv) {
+        return true;
+    }
+    let Some((c, a)) = split_command(argv) else {
+        return false;
+    };
+    matches!(
+        (c, a.trim()),
+        ('7' | '9' | 'k', "" | "list") | ('r', _) | ('w', "" | "show")
+    )
+}
+
+/// the command char, and the args after it. the char may be any char at
+/// all, so this splits after it rather than after its first byte
+fn split_command(argv: &str) -> Option<(char, &str)> {
+    let mut chars = argv.chars();
+    let c = chars.next()?;
+    Some((c, chars.as_str()))
+}
+
+/// the one command that's a word
+fn is_status(argv: &str) -> bool {
+    argv.eq_ignore_ascii_case("status")
+}
+
+/// commands are single chars, then a space, then args
+pub async fn handle_command(argv: &str) -> CommandStatus {
+    match run(argv).await {
+        Ok(status) => status,
+        Err(e) => CommandStatus::error(e),
+    }
+}
+
+async fn run(argv: &str) -> Result<CommandStatus, Error> {
+    if is_status(argv) {
+        let mut status = CommandStatus::new("");
+        status::report(&mut status).await?;
+        status.write_char('\n')?;
+        return Ok(status);
+    }
+    let (c, a) = split_command(argv).ok_or(Error::UnknownCommand)?;
+    let status = match c {
+        '0' => {
+            // echo
+            echo(a, Effect::Alert).await?;
+            CommandStatus::new("[*] echoing message\n")
+        }
+        '1' => {
+            // store clk
+            set_clock(a.trim()).await?;
+            CommandStatus::new("[*] clock set! ^-^\n")
+        }
+        '2' => {
+            // store weather
+            set_weather(a).await?;
+            CommandStatus::new("[*] weather set! ^-^\n")
+        }
+        '3' => {
+            // draw a 32x8 picture, sent as 64 hex digits
+            let bitmap =
+                graphics::bitmap_from_hex(a.trim()).ok_or(Error::Malformed)?;
+            draw_image(&bitmap).await?;
+            CommandStatus::new("[*] picture drawn! ^-^\n")
+        }
+        '4' => {
+            // brightness
+            let level = a.trim().parse().map_err(|_| Error::OutOfRange)?;
+            set_brightness(level)?;
+            CommandStatus::new("[*] brightness set! ^-^\n")
+        }
+        '5' => {
+            // clock timezone and format
+            configure_clock(a)?;
+            CommandStatus::new("[*] clock configured! ^-^\n")
+        }
+        '6' => {
+            // weather unit
+            let mut chars = a.trim().chars();
+            let unit =
+                match (chars.next().and_then(Unit::from_char), chars.next()) {
+                    (Some(unit), None) => unit,
+                    _ => return Err(Error::Malformed),
+                };
+            set_weather_unit(unit).await;
+            CommandStatus::new("[*] weather unit set! ^-^\n")
+        }
+        // playlist
+        '7' => edit_playlist(a).await?,
+        '8' => {
+            // queue a message, see `Message::parse` for the options
+            let now = Instant::now().as_secs();
+            let message = Message::parse(a, now).ok_or(Error::Malformed)?;
+            let id = enqueue(message).await?;
+            let mut status = CommandStatus::new("[*] queued ");
+            writeln!(status, "{} ^-^", id)?;
+            status
+        }
+        // message queue
+        '9' => edit_queue(a).await?,
+        // command port keys
+        'k' => edit_keys(a).await?,
+        // read back what the displays show
+        'r' => read_frame(a).await?,
+        // wifi and ip settings
+        'w' => edit_network(a).await?,
+        _ => return Err(Error::UnknownCommand),
+    };
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use proptest::prelude::*;
+
+    fn reply(argv: &str) -> String<MAX_STATUS_LEN> {
+        block_on(handle_command(argv)).mesg
+    }
+
+    #[test]
+    fn commands_are_one_char_of_any_width() {
+        assert_eq!(split_command("0hello"), Some(('0', "hello")));
+        assert_eq!(split_command("é1"), Some(('é', "1")));
+        assert_eq!(split_command("7"), Some(('7', "")));
+        assert_eq!(split_command(""), None);
+    }
+
+    #[test]
+    fn errors_carry_their_code() {
+        assert_eq!(reply(""), "[*] error 8: unknown command :c\n");
+        assert_eq!(reply("x"), "[*] error 8: unknown command :c\n");
+        assert_eq!(reply("ñ1"), "[*] error 8: unknown command :c\n");
+        assert_eq!(reply("49"), "[*] error 4: out of range :c\n");
+        assert_eq!(reply("4 bright"), "[*] error 4: out of range :c\n");
+        assert_eq!(reply("3 abc"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("6K"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("5 60 mars 24"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("5 60 none"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("5 soon none 24"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("5 900 none 24"), "[*] error 4: out of range :c\n");
+        assert_eq!(reply("5 99999 eu 12"), "[*] error 4: out of range :c\n");
+        assert_eq!(reply("1 12:3☃"), "[*] error 3: no glyph for '☃' :c\n");
+        assert_eq!(reply("2=hot,sun"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("wid Hall/1"), "[*] error 5: malformed :c\n");
+        assert_eq!(reply("wmqtt broker"), "[*] error 5: malformed :c\n");
+    }
+
+    #[test]
+    fn blank_displays_read_back_blank() {
+        let mut blank = String::<MAX_STATUS_LEN>::new();
+        for _ in 0..64 {
+            blank.push('0').unwrap();
+        }
+        blank.push('\n').unwrap();
+        assert_eq!(reply("r"), blank);
+        assert_eq!(reply("rhex"), blank);
+        assert_eq!(reply("rart").lines().count(), 8);
+        assert_eq!(reply("rpng"), "[*] error 5: malformed :c\n");
+    }
+
+    #[test]
+    fn empty_lists_still_answer() {
+        assert_eq!(reply("klist"), "[*] no keys\n");
+    }
+
+    #[test]
+    fn read_only_commands() {
+        for argv in ["STATUS", "status", "7", "7list", "9list", "klist"] {
+            assert!(is_read_only(argv), "{}", argv);
+        }
+        for argv in ["rart", "w", "wshow"] {
+            assert!(is_read_only(argv), "{}", argv);
+        }
+        for argv in ["", "0hi", "7add clock", "9clear", "kadd a rw b"] {
+            assert!(!is_read_only(argv), "{}", argv);
+        }
+        for argv in ["wssid x", "wreboot", "é", "7list é"] {
+            assert!(!is_read_only(argv), "{}", argv);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn split_command_keeps_every_char(argv in "\\PC{0,16}") {
+            match split_command(&argv) {
+                Some((c, rest)) => {
+                    prop_assert_eq!(argv.strip_prefix(c), Some(rest))
+                }
+                None => prop_assert!(argv.is_empty()),
+            }
+        }
+
+        // commands whose side effects no other test looks at
+        #[test]
+        fn any_args_get_a_reply(
+            c in prop::sample::select(vec!['3', '4', '5', '6', 'r', 'x']),
+            args in "\\PC{0,64}",
+        ) {
+            let argv = format!("{}{}", c, args);
+            prop_assert!(!reply(&argv).is_empty());
+        }
+
+        #[test]
+        fn non_commands_are_unknown(argv in "[^0-9krwsS]\\PC{0,16}") {
+            prop_assert_eq!(
+                reply(&argv),
+                "[*] error 8: unknown command :c\n"
+            );
+        }
+    }
+}
@@ -0,0 +1,668 @@
+//! what is kept in flash across reboots, and how it's encoded. every key
+//! holds a bit of text plus a format version, so a newer firmware can
+//! still read what an older one wrote
+
+use core::fmt::{self, Write};
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal,
+};
+use embassy_time::Instant;
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::String;
+use portable_atomic::AtomicU8;
+
+use crate::auth::{self, KEYRING};
+use crate::clock::{self, ClockConfig, Dst, HourFormat};
+use crate::commands;
+use crate::crash::{Crash, LAST_CRASH};
+use crate::playlist::{Playlist, Slot};
+use crate::queue::{Message, MAX_LINE_LEN};
+use crate::status;
+use crate::storage::{self, Store, MAX_VALUE_LEN};
+use crate::weather::Unit;
+use crate::{BRIGHTNESS, DATA, MAX_BRIGHTNESS, PLAYLIST, QUEUE};
+
+// the compile-time wifi credentials from credentials.rs, if there is one
+include!(concat!(env!("OUT_DIR"), "/credentials.rs"));
+
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASSWORD_LEN: usize = 64;
+/// short enough for an mdns label with `dotmatrix-` in front, and for mqtt
+/// topics
+pub const MAX_ID_LEN: usize = 24;
+pub const MQTT_PORT: u16 = 1883;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+    Settings = 0,
+    Playlist = 1,
+    Network = 2,
+    /// the pushed clock and weather
+    Content = 3,
+    /// queued text messages
+    Messages = 4,
+    /// pre-shared keys for the command port
+    AuthKeys = 5,
+    /// the last panic
+    Crash = 6,
+}
+
+const KEYS: [Key; 7] = [
+    Key::Settings,
+    Key::Playlist,
+    Key::Network,
+    Key::Content,
+    Key::Messages,
+    Key::AuthKeys,
+    Key::Crash,
+];
+
+impl Key {
+    /// the format written now. when a format changes, bump this and keep
+    /// a way to read the old one
+    pub fn version(self) -> u8 {
+        match self {
+            // 2 keeps pictures and messages that expire, see `Message::write`
+            Self::Messages => 2,
+            // 2 added the id and the mqtt broker
+            Self::Network => 2,
+            _ => 1,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// keys that changed since the last save
+static DIRTY: AtomicU8 = AtomicU8::new(0);
+/// signalled whenever something should be saved
+pub static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// the stored network config, if any
+pub static NETWORK: Mutex<CriticalSectionRawMutex, Option<NetworkConfig>> =
+    Mutex::new(None);
+
+/// set when the device should restart once everything is saved
+pub static REBOOT: AtomicBool = AtomicBool::new(false);
+
+/// asks for `key` to be saved soon
+pub fn mark_dirty(key: Key) {
+    DIRTY.fetch_or(key.bit(), Ordering::Relaxed);
+    CHANGED.signal(());
+}
+
+/// saves right away, then restarts
+pub fn reboot() {
+    REBOOT.store(true, Ordering::Relaxed);
+    CHANGED.signal(());
+}
+
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub brightness: u8,
+    pub clock: ClockConfig,
+    pub weather_unit: Unit,
+}
+
+impl Settings {
+    /// e.g. `b=8 o=540 d=none f=24 u=C`
+    pub fn encode(&self, out: &mut impl Write) -> fmt::Result {
+        let format = match self.clock.format {
+            HourFormat::H12 => 12,
+            HourFormat::H24 => 24,
+        };
+        write!(
+            out,
+            "b={} o={} d={} f={} u={}",
+            self.brightness,
+            self.clock.offset_minutes,
+            self.clock.dst.name(),
+            format,
+            self.weather_unit.as_char()
+        )
+    }
+
+    /// fields that are missing keep their value from `self`
+    pub fn decode(mut self, version: u8, s: &str) -> Option<Self> {
+        if version != 1 {
+            return None;
+        }
+        for field in s.split_whitespace() {
+            match field.split_once('=')? {
+                ("b", v) => self.brightness = v.parse().ok()?,
+                ("o", v) => self.clock.offset_minutes = v.parse().ok()?,
+                ("d", v) => self.clock.dst = Dst::from_name(v)?,
+                ("f", "12") => self.clock.format = HourFormat::H12,
+                ("f", "24") => self.clock.format = HourFormat::H24,
+                ("f", _) => return None,
+                ("u", v) => {
+                    self.weather_unit = Unit::from_char(v.chars().next()?)?
+                }
+                // from a newer firmware
+                _ => {}
+            }
+        }
+        (self.brightness <= MAX_BRIGHTNESS).then_some(self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StaticIpv4 {
+    pub address: [u8; 4],
+    pub prefix: u8,
+    pub gateway: Option<[u8; 4]>,
+}
+
+fn parse_ipv4(s: &str) -> Option<[u8; 4]> {
+    let mut octets = s.split('.');
+    let mut address = [0; 4];
+    for octet in &mut address {
+        *octet = octets.next()?.parse().ok()?;
+    }
+    octets.next().is_none().then_some(address)
+}
+
+impl StaticIpv4 {
+    /// `<address>/<prefix> [gateway]`, e.g. `192.168.1.5/24 192.168.1.1`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut fields = s.split_whitespace();
+        let (address, prefix) = fields.next()?.split_once('/')?;
+        let gateway = match fields.next() {
+            Some(g) => Some(parse_ipv4(g)?),
+            None => None,
+        };
+        let prefix = prefix.parse().ok().filter(|&p| p <= 32)?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            address: parse_ipv4(address)?,
+            prefix,
+            gateway,
+        })
+    }
+}
+
+impl fmt::Display for StaticIpv4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.address;
+        write!(f, "{}.{}.{}.{}/{}", a, b, c, d, self.prefix)?;
+        if let Some([a, b, c, d]) = self.gateway {
+            write!(f, " {}.{}.{}.{}", a, b, c, d)?;
+        }
+        Ok(())
+    }
+}
+
+/// an mqtt broker to connect to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Broker {
+    pub address: [u8; 4],
+    pub port: u16,
+}
+
+impl Broker {
+    /// `<address>[:<port>]`, on `MQTT_PORT` unless given
+    pub fn parse(s: &str) -> Option<Self> {
+        let (address, port) = match s.split_once(':') {
+            Some((address, port)) => (address, port.parse().ok()?),
+            None => (s, MQTT_PORT),
+        };
+        Some(Self {
+            address: parse_ipv4(address)?,
+            port: (port != 0).then_some(port)?,
+        })
+    }
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.address;
+        write!(f, "{}.{}.{}.{}:{}", a, b, c, d, self.port)
+    }
+}
+
+/// lowercase letters, digits and `-`, so it can go in a host name and in
+/// mqtt topics as is
+pub fn is_valid_id(id: &str) -> bool {
+    let allowed =
+        |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-';
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(allowed)
+}
+
+/// the id of a sign that wasn't given one: the end of its flash chip's
+/// unique id, in hex. different for every pico, and the same every boot
+pub fn chip_id(unique_id: &[u8; 8]) -> String<MAX_ID_LEN> {
+    let mut id = String::new();
+    for b in &unique_id[4..] {
+        let _ = write!(id, "{:02x}", b);
+    }
+    id
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct NetworkConfig {
+    pub ssid: String<MAX_SSID_LEN>,
+    /// empty for open networks
+    pub password: String<MAX_PASSWORD_LEN>,
+    /// None for dhcp
+    pub ipv4: Option<StaticIpv4>,
+    /// what the sign goes by on mqtt. empty for `chip_id`
+    pub id: String<MAX_ID_LEN>,
+    /// None to stay off mqtt
+    pub mqtt: Option<Broker>,
+}
+
+impl NetworkConfig {
+    /// from credentials.rs, if it was there at build time. uses dhcp
+    pub fn compiled() -> Option<Self> {
+        let (ssid, password) = DEFAULT_CREDENTIALS?;
+        Some(Self {
+            ssid: String::try_from(ssid).ok()?,
+            password: String::try_from(password).ok()?,
+            ..Self::default()
+        })
+    }
+
+    /// five lines: the ssid, the password, `dhcp` or a static address
+    /// (see `StaticIpv4::parse`), the id, and `none` or the mqtt broker
+    /// (see `Broker::parse`)
+    pub fn encode(&self, out: &mut impl Write) -> fmt::Result {
+        writeln!(out, "{}", self.ssid)?;
+        writeln!(out, "{}", self.password)?;
+        match &self.ipv4 {
+            Some(ipv4) => writeln!(out, "{}", ipv4)?,
+            None => writeln!(out, "dhcp")?,
+        }
+        writeln!(out, "{}", self.id)?;
+        match &self.mqtt {
+            Some(broker) => write!(out, "{}", broker),
+            None => out.write_str("none"),
+        }
+    }
+
+    /// version 1 stopped after the address
+    pub fn decode(version: u8, s: &str) -> Option<Self> {
+        if version != 1 && version != 2 {
+            return None;
+        }
+        let mut lines = s.split('\n');
+        let mut network = Self {
+            ssid: String::try_from(lines.next()?).ok()?,
+            password: String::try_from(lines.next()?).ok()?,
+            ipv4: match lines.next()? {
+                "dhcp" => None,
+                ipv4 => Some(StaticIpv4::parse(ipv4)?),
+            },
+            ..Self::default()
+        };
+        if version == 2 {
+            network.id = match lines.next()? {
+                "" => String::new(),
+                id => {
+                    String::try_from(id).ok().filter(|id| is_valid_id(id))?
+                }
+            };
+            network.mqtt = match lines.next()? {
+                "none" => None,
+                broker => Some(Broker::parse(broker)?),
+            };
+        }
+        if lines.next().is_some() {
+            return None;
+        }
+        Some(network)
+    }
+}
+
+/// the network to join: the stored config, or else the compiled-in one.
+/// None if there's no ssid to join
+pub async fn network() -> Option<NetworkConfig> {
+    let stored = NETWORK.lock().await.clone();
+    stored
+        .or_else(NetworkConfig::compiled)
+        .filter(|n| !n.ssid.is_empty())
+}
+
+fn trim_nulls(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("").trim_end_matches('\0')
+}
+
+async fn encode(key: Key, out: &mut String<MAX_VALUE_LEN>) -> fmt::Result {
+    match key {
+        Key::Settings => {
+            let weather_unit = DATA.lock().await.weather_unit;
+            let settings = Settings {
+                brightness: crate::brightness(),
+                clock: clock::config(),
+                weather_unit,
+            };
+            settings.encode(out)
+        }
+        Key::Playlist => {
+            let playlist = PLAYLIST.lock().await;
+            for slot in playlist.slots() {
+                slot.write(out)?;
+                out.write_char('\n')?;
+            }
+            if playlist.slots().is_empty() {
+                // an empty value would remove the key, and the defaults
+                // would come back on the next boot
+                out.write_char('\n')?;
+            }
+            Ok(())
+        }
+        Key::Network => match NETWORK.lock().await.as_ref() {
+            Some(network) => network.encode(out),
+            None => Ok(()),
+        },
+        Key::Content => {
+            let data = DATA.lock().await;
+            if let Some(clock) = &data.clock {
+                writeln!(out, "c={}", trim_nulls(clock))?;
+            }
+            match &data.weather {
+                Some(crate::WeatherData::Text(text)) => {
+                    writeln!(out, "w={}", trim_nulls(text))
+                }
+                Some(crate::WeatherData::Report(report)) => {
+                    out.write_str("w==")?;
+                    report.encode(out)?;
+                    out.write_char('\n')
+                }
+                None => Ok(()),
+            }
+        }
+        Key::AuthKeys => {
+            for key in KEYRING.lock().await.keys() {
+                key.write(out)?;
+                out.write_char('\n')?;
+            }
+            Ok(())
+        }
+        Key::Crash => match LAST_CRASH.lock().await.as_ref() {
+            Some(crash) => out.write_str(&crash.0),
+            None => Ok(()),
+        },
+        Key::Messages => {
+            let now = Instant::now().as_secs();
+            // `push` only takes what fits, so this never drops any
+            for message in QUEUE.lock().await.saved() {
+                let mut line: String<MAX_LINE_LEN> = String::new();
+                let fits = message.write(&mut line, now).is_ok()
+                    && out.len() + line.len() < out.capacity();
+                if !fits {
+                    status::record_error("flash-save");
+                    continue;
+                }
+                writeln!(out, "{}", line)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// applies one stored value. anything that doesn't decode is ignored
+async fn apply(key: Key, version: u8, value: &str) {
+    match key {
+        Key::Settings => {
+            let current = Settings {
+                brightness: crate::brightness(),
+                clock: clock::config(),
+                weather_unit: DATA.lock().await.weather_unit,
+            };
+            if let Some(settings) = current.decode(version, value) {
+                BRIGHTNESS.store(settings.brightness, Ordering::Relaxed);
+                clock::set_config(settings.clock);
+                DATA.lock().await.weather_unit = settings.weather_unit;
+            }
+        }
+        Key::Playlist if version == 1 => {
+            let mut playlist = Playlist::new();
+            for slot in value.lines().filter_map(Slot::parse) {
+                let _ = playlist.add(slot);
+            }
+            *PLAYLIST.lock().await = playlist;
+        }
+        Key::Network => {
+            *NETWORK.lock().await = NetworkConfig::decode(version, value);
+        }
+        Key::Content if version == 1 => {
+            for line in value.lines() {
+                if let Some(clock) = line.strip_prefix("c=") {
+                    let _ = commands::set_clock(clock).await;
+                } else if let Some(weather) = line.strip_prefix("w=") {
+                    let _ = commands::set_weather(weather).await;
+                }
+            }
+        }
+        Key::AuthKeys if version == 1 => {
+            let mut keyring = KEYRING.lock().await;
+            keyring.clear();
+            for key in value.lines().filter_map(auth::Key::parse) {
+                let _ = keyring.add(key);
+            }
+        }
+        Key::Crash if version == 1 => {
+            if let Ok(text) = String::try_from(value) {
+                *LAST_CRASH.lock().await = Some(Crash(text));
+            }
+        }
+        Key::Messages => {
+            let now = Instant::now().as_secs();
+            let mut queue = QUEUE.lock().await;
+            for line in value.lines() {
+                if let Some(message) = Message::decode(version, line, now) {
+                    let _ = queue.push(message, now);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// reads back everything that was saved. whatever isn't stored keeps the
+/// value it has now
+pub async fn load<F: NorFlash>(
+    store: &mut Store<F>,
+) -> Result<(), storage::Error> {
+    for key in KEYS {
+        let mut value: String<MAX_VALUE_LEN> = String::new();
+        let version = match store.read(key as u8).await? {
+            Some((version, bytes)) => {
+                let Ok(s) = str::from_utf8(bytes) else {
+                    continue;
+                };
+                let _ = value.push_str(s);
+                version
+            }
+            None => continue,
+        };
+        apply(key, version, &value).await;
+    }
+    // applying went through the same setters as everything else
+    DIRTY.store(0, Ordering::Relaxed);
+    CHANGED.reset();
+    Ok(())
+}
+
+/// writes out every key that changed since the last save
+pub async fn save<F: NorFlash>(
+    store: &mut Store<F>,
+) -> Result<(), storage::Error> {
+    let dirty = DIRTY.swap(0, Ordering::Relaxed);
+    for key in KEYS.into_iter().filter(|k| dirty & k.bit() != 0) {
+        let mut value: String<MAX_VALUE_LEN> = String::new();
+        let result = match encode(key, &mut value).await {
+            Ok(()) => {
+                let bytes = value.as_bytes();
+                store.write(key as u8, key.version(), bytes).await
+            }
+            Err(_) => Err(storage::Error::TooLarge),
+        };
+        if let Err(e) = result {
+            // try again on the next save
+            DIRTY.fetch_or(dirty, Ordering::Relaxed);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_flash::MockFlash;
+    use crate::playlist::Content;
+    use crate::queue::Effect;
+    use embassy_futures::block_on;
+
+    fn settings() -> Settings {
+        Settings {
+            brightness: 5,
+            clock: ClockConfig {
+                offset_minutes: -300,
+                dst: Dst::Us,
+                format: HourFormat::H12,
+            },
+            weather_unit: Unit::Fahrenheit,
+        }
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut encoded = String::<64>::new();
+        settings().encode(&mut encoded).unwrap();
+        assert_eq!(encoded, "b=5 o=-300 d=us f=12 u=F");
+
+        let defaults = Settings {
+            brightness: MAX_BRIGHTNESS,
+            clock: ClockConfig::new(),
+            weather_unit: Unit::Celsius,
+        };
+        let decoded = defaults.decode(1, &encoded).unwrap();
+        assert_eq!(decoded.brightness, 5);
+        assert_eq!(decoded.clock.offset_minutes, -300);
+        assert!(decoded.clock.dst == Dst::Us);
+        assert!(decoded.clock.format == HourFormat::H12);
+        assert_eq!(decoded.weather_unit, Unit::Fahrenheit);
+    }
+
+    #[test]
+    fn settings_from_other_firmwares() {
+        // missing fields keep what they were, unknown ones are skipped
+        let decoded = settings().decode(1, "b=2 x=whatever").unwrap();
+        assert_eq!(decoded.brightness, 2);
+        assert_eq!(decoded.clock.offset_minutes, -300);
+        assert!(settings().decode(2, "b=2").is_none());
+        assert!(settings().decode(1, "b=9").is_none());
+        assert!(settings().decode(1, "f=36").is_none());
+        assert!(settings().decode(1, "b").is_none());
+    }
+
+    #[test]
+    fn network_configs_round_trip() {
+        let network = NetworkConfig {
+            ssid: String::try_from("my wifi").unwrap(),
+            password: String::new(),
+            ipv4: StaticIpv4::parse("192.168.1.5/24 192.168.1.1"),
+            id: String::try_from("hall").unwrap(),
+            mqtt: Broker::parse("192.168.1.2"),
+        };
+        let mut encoded = String::<128>::new();
+        network.encode(&mut encoded).unwrap();
+        assert_eq!(
+            encoded,
+            "my wifi\n\n192.168.1.5/24 192.168.1.1\nhall\n192.168.1.2:1883"
+        );
+        let version = Key::Network.version();
+        assert_eq!(NetworkConfig::decode(version, &encoded), Some(network));
+
+        let dhcp = "home\nhunter2\ndhcp\n\nnone";
+        let dhcp = NetworkConfig::decode(version, dhcp).unwrap();
+        assert_eq!((dhcp.ipv4, dhcp.mqtt), (None, None));
+        assert!(dhcp.id.is_empty());
+        let bad_id = "home\nhunter2\ndhcp\nHall/1\nnone";
+        assert_eq!(NetworkConfig::decode(version, bad_id), None);
+        assert_eq!(NetworkConfig::decode(version, "home\nhunter2"), None);
+        assert_eq!(NetworkConfig::decode(3, "home\nhunter2\ndhcp"), None);
+    }
+
+    #[test]
+    fn network_configs_from_version_1_still_load() {
+        let v1 = NetworkConfig::decode(1, "home\nhunter2\ndhcp").unwrap();
+        assert_eq!(v1.ssid, "home");
+        assert_eq!((v1.id.as_str(), v1.mqtt), ("", None));
+        assert_eq!(NetworkConfig::decode(1, "home\nhunter2\ndhcp\nx"), None);
+    }
+
+    #[test]
+    fn brokers_and_ids() {
+        let broker = Broker::parse("10.0.0.2:8883").unwrap();
+        assert_eq!((broker.address, broker.port), ([10, 0, 0, 2], 8883));
+        assert_eq!(Broker::parse("10.0.0.2").unwrap().port, MQTT_PORT);
+        for s in ["10.0.0.2:", "10.0.0.2:0", "10.0.0:1883", "broker"] {
+            assert_eq!(Broker::parse(s), None, "{}", s);
+        }
+
+        for id in ["1", "hall", "kitchen-2", "0a1b2c3d"] {
+            assert!(is_valid_id(id), "{}", id);
+        }
+        for id in ["", "Hall", "a/b", "a+", "a#", "a.b", &"x".repeat(25)] {
+            assert!(!is_valid_id(id), "{}", id);
+        }
+        let id = chip_id(&[0xe6, 0x61, 0x38, 0x52, 0x0b, 0x1a, 0xc2, 0x2f]);
+        assert_eq!(id, "0b1ac22f");
+        assert!(is_valid_id(&id));
+    }
+
+    #[test]
+    fn static_addresses() {
+        let ipv4 = StaticIpv4::parse("10.0.0.2/8").unwrap();
+        assert_eq!((ipv4.address, ipv4.prefix), ([10, 0, 0, 2], 8));
+        assert_eq!(ipv4.gateway, None);
+        for s in ["10.0.0.2", "10.0.0/8", "10.0.0.256/8", "10.0.0.2/33"] {
+            assert_eq!(StaticIpv4::parse(s), None, "{}", s);
+        }
+        assert_eq!(StaticIpv4::parse("10.0.0.2/8 10.0.0.1 x"), None);
+    }
+
+    #[test]
+    fn messages_stored_by_version_1_still_load() {
+        // what the last firmware saved, as it saved it
+        let flash = MockFlash::new(storage::STORE_SIZE as usize);
+        let mut store = block_on(Store::mount(flash.clone(), 0)).unwrap();
+        let v1 = "p=150 n=2 e=scroll HELLO\np=100 n=1 e=alert text me\n";
+        let key = Key::Messages as u8;
+        block_on(store.write(key, 1, v1.as_bytes())).unwrap();
+
+        let mut store = block_on(Store::mount(flash, 0)).unwrap();
+        let (version, value) = block_on(store.read(key)).unwrap().unwrap();
+        let value = str::from_utf8(value).unwrap();
+        let messages: std::vec::Vec<Message> = value
+            .lines()
+            .filter_map(|line| Message::decode(version, line, 0))
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].effect, Effect::Scroll);
+        assert_eq!(messages[0].remaining, 2);
+        assert!(
+            matches!(&messages[0].content, Content::Text(t) if t == "HELLO")
+        );
+        assert!(
+            matches!(&messages[1].content, Content::Text(t) if t == "text me")
+        );
+
+        // and they're written back in the new format
+        assert_eq!(Key::Messages.version(), 2);
+        let mut line = String::<MAX_LINE_LEN>::new();
+        messages[1].write(&mut line, 0).unwrap();
+        let v2 = Message::decode(Key::Messages.version(), &line, 0).unwrap();
+        assert_eq!(v2.content, messages[1].content);
+    }
+}
@@ -0,0 +1,218 @@
+//! a record of the last panic or stalled task. it's left in a bit of ram
+//! that a reset doesn't clear, the next boot picks it up from there, and
+//! it's kept in flash until another crash replaces it
+
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex,
+};
+use heapless::String;
+
+#[cfg(feature = "firmware")]
+pub use firmware::halt;
+
+pub const MAX_CRASH_LEN: usize = 160;
+const MAGIC: u32 = 0x4352_5348; // "CRSH"
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    len: u32,
+    text: [u8; MAX_CRASH_LEN],
+}
+
+#[cfg_attr(feature = "firmware", link_section = ".uninit.CRASH")]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// the last crash, from this boot's ram or from flash
+pub static LAST_CRASH: Mutex<CriticalSectionRawMutex, Option<Crash>> =
+    Mutex::new(None);
+
+/// `<file>:<line>:<column> <message>`, or `watchdog <task> stalled`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Crash(pub String<MAX_CRASH_LEN>);
+
+impl Crash {
+    pub fn location(&self) -> &str {
+        self.0.split_once(' ').map_or(&self.0, |(l, _)| l)
+    }
+
+    pub fn message(&self) -> &str {
+        self.0.split_once(' ').map_or("", |(_, m)| m)
+    }
+}
+
+/// writes as much as fits, and drops the rest
+struct Truncating<'a>(&'a mut String<MAX_CRASH_LEN>);
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// called from the panic handler
+pub fn record(info: &PanicInfo) {
+    let mut text = String::new();
+    let mut out = Truncating(&mut text);
+    let _ = match info.location() {
+        Some(l) => write!(out, "{}:{}:{}", l.file(), l.line(), l.column()),
+        None => out.write_str("unknown"),
+    };
+    let _ = write!(out, " {}", info.message());
+    // nothing else runs after a panic, so nothing else touches `RECORD`
+    save(&text);
+}
+
+/// called by `liveness` just before it lets the watchdog restart us
+pub fn record_stall(task: &str) {
+    let mut text = String::new();
+    let _ = write!(Truncating(&mut text), "watchdog {} stalled", task);
+    // a panic after this just replaces it
+    save(&text);
+}
+
+fn save(text: &str) {
+    let mut record = Record {
+        magic: MAGIC,
+        len: text.len() as u32,
+        text: [0; MAX_CRASH_LEN],
+    };
+    record.text[..text.len()].copy_from_slice(text.as_bytes());
+    unsafe { addr_of_mut!(RECORD).cast::<Record>().write_volatile(record) };
+}
+
+/// takes the crash the last boot left in ram, if it left one
+pub fn recover() -> Option<Crash> {
+    let record = addr_of!(RECORD).cast::<Record>();
+    // after a power cycle this is whatever the ram came up with
+    let (magic, len) = unsafe {
+        (
+            addr_of!((*record).magic).read_volatile(),
+            addr_of!((*record).len).read_volatile(),
+        )
+    };
+    if magic != MAGIC || len as usize > MAX_CRASH_LEN {
+        return None;
+    }
+    let text = unsafe { addr_of!((*record).text).read_volatile() };
+    unsafe { addr_of_mut!(RECORD).cast::<u32>().write_volatile(0) };
+
+    let text = core::str::from_utf8(&text[..len as usize]).ok()?;
+    String::try_from(text).ok().map(Crash)
+}
+
+#[cfg(feature = "firmware")]
+mod firmware {
+    use embassy_rp::pac;
+    use embassy_time::{Duration, Instant};
+
+    use crate::graphics::{self, Graphic};
+    use crate::DISPLAYS;
+
+    /// how long the panels say so before the restart
+    const SHOW_FOR: Duration = Duration::from_secs(3);
+    const GLYPHS: [&Graphic; 4] = [
+        &graphics::LETTER_F,
+        &graphics::LETTER_A,
+        &graphics::LETTER_I,
+        &graphics::LETTER_L,
+    ];
+
+    /// shows `FAIL` for a few seconds, then has the watchdog restart us. the
+    /// panels are driven right here, since no task runs after a panic
+    pub fn halt() -> ! {
+        // a display the render task was in the middle of stays dark
+        let mut displays: [_; 4] =
+            core::array::from_fn(|i| DISPLAYS[i].0.try_lock().ok());
+        for (display, glyph) in displays.iter_mut().zip(GLYPHS) {
+            if let Some(Some(d)) = display.as_deref_mut() {
+                d.graphic = *glyph;
+            }
+        }
+        let until = Instant::now() + SHOW_FOR;
+        while Instant::now() < until {
+            for d in displays.iter_mut().flatten().flat_map(|d| d.as_mut()) {
+                d.render();
+            }
+        }
+
+        // reset everything but the oscillators, like `Watchdog::start` does
+        pac::PSM
+            .wdsel()
+            .write_value(pac::psm::regs::Wdsel(0x0001_ffff & !0b11));
+        pac::WATCHDOG.ctrl().write(|w| w.set_trigger(true));
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crash(text: &str) -> Crash {
+        Crash(String::try_from(text).unwrap())
+    }
+
+    #[test]
+    fn location_then_message() {
+        let panic = crash("src/main.rs:120:9 index out of bounds: len 4");
+        assert_eq!(panic.location(), "src/main.rs:120:9");
+        assert_eq!(panic.message(), "index out of bounds: len 4");
+
+        let stall = crash("watchdog render stalled");
+        assert_eq!(stall.location(), "watchdog");
+        assert_eq!(stall.message(), "render stalled");
+
+        // a panic with nothing to say, and an empty record
+        assert_eq!(crash("src/lib.rs:1:1 ").message(), "");
+        assert_eq!(crash("unknown").location(), "unknown");
+        assert_eq!(crash("unknown").message(), "");
+        assert_eq!(crash("").location(), "");
+    }
+
+    #[test]
+    fn truncating_stops_between_chars() {
+        let mut text = String::new();
+        let long = "a".repeat(MAX_CRASH_LEN - 1);
+        write!(Truncating(&mut text), "{}é and more", long).unwrap();
+        // the 2 byte char doesn't fit in the last byte, so it's dropped
+        // with everything after it
+        assert_eq!(text.len(), MAX_CRASH_LEN - 1);
+        assert_eq!(text.as_str(), long);
+
+        let mut text = String::new();
+        let mut out = Truncating(&mut text);
+        for _ in 0..MAX_CRASH_LEN {
+            write!(out, "ü").unwrap();
+        }
+        assert_eq!(text.len(), MAX_CRASH_LEN);
+        assert!(text.chars().all(|c| c == 'ü'));
+    }
+
+    #[test]
+    fn a_stall_survives_until_recovered() {
+        record_stall("render");
+        let recovered = recover().unwrap();
+        assert_eq!(recovered, crash("watchdog render stalled"));
+        // and only once
+        assert_eq!(recover(), None);
+
+        let long = "x".repeat(MAX_CRASH_LEN * 2);
+        record_stall(&long);
+        let recovered = recover().unwrap();
+        assert_eq!(recovered.0.len(), MAX_CRASH_LEN);
+        assert_eq!(recovered.location(), "watchdog");
+    }
+}
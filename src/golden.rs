@@ -0,0 +1,188 @@
+//! golden frame tests for the animations. each one runs against mock
+//! displays on a virtual clock (see `virtual_time`), and the frames it goes
+//! through are compared with `tests/golden/<name>.txt`. when a change is on
+//! purpose, write them out again with `UPDATE_GOLDEN=1 cargo test-host` and
+//! check the diff
+
+use core::fmt::Write;
+use core::future::Future;
+use std::string::String;
+use std::vec::Vec;
+
+use embassy_futures::block_on;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::graphics::{self, Bitmap, Panorama, EMPTY, FULL};
+use crate::hal::{DotMatrixLed, ShiftRegister};
+use crate::queue::{Message, URGENT};
+use crate::virtual_time;
+use crate::weather::Weather;
+use crate::{urgent_queued, Displays, QUEUE, QUEUED};
+
+/// an animation still going after this long is stuck
+const GIVE_UP: Duration = Duration::from_secs(60);
+
+fn displays() -> Displays<'static> {
+    let displays = Displays::new();
+    for d in displays.iter() {
+        *d.0.try_lock().unwrap() = Some(DotMatrixLed {
+            sr: ShiftRegister::default(),
+            graphic: EMPTY,
+        });
+    }
+    displays
+}
+
+fn snapshot(displays: &Displays) -> Bitmap {
+    block_on(displays.snapshot())
+}
+
+/// runs `animation` to the end, and writes down every frame it shows and
+/// when, in ms from the start
+fn record(displays: &Displays, animation: impl Future) -> String {
+    let start = Instant::now();
+    let mut frames = String::new();
+    let mut last = None;
+    virtual_time::run(animation, || {
+        let frame = snapshot(displays);
+        if last != Some(frame) {
+            let at = start.elapsed().as_millis();
+            writeln!(frames, "{}ms", at).unwrap();
+            graphics::write_art(&mut frames, &frame).unwrap();
+            last = Some(frame);
+        }
+        assert!(start.elapsed() < GIVE_UP, "the animation never ended");
+    });
+    let at = start.elapsed().as_millis();
+    writeln!(frames, "{}ms done", at).unwrap();
+    frames
+}
+
+/// when each frame in `frames` went up, the end included
+fn times(frames: &str) -> Vec<u64> {
+    frames
+        .lines()
+        .filter_map(|l| l.strip_suffix("ms").or(l.strip_suffix("ms done")))
+        .map(|t| t.parse().unwrap())
+        .collect()
+}
+
+fn check(name: &str, frames: &str) {
+    let path =
+        format!("{}/tests/golden/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, frames).unwrap();
+        return;
+    }
+    let golden = std::fs::read_to_string(&path).unwrap_or_default();
+    if let Some((line, (want, got))) = golden
+        .lines()
+        .zip(frames.lines())
+        .enumerate()
+        .find(|(_, (want, got))| want != got)
+    {
+        panic!(
+            "{} differs from {} at line {}:\n  want {}\n  got  {}\n\
+             rerun with UPDATE_GOLDEN=1 if that's on purpose",
+            name,
+            path,
+            line + 1,
+            want,
+            got
+        );
+    }
+    assert_eq!(
+        golden.lines().count(),
+        frames.lines().count(),
+        "{} has a different number of frames than {}",
+        name,
+        path
+    );
+}
+
+#[test]
+fn panorama() {
+    let displays = displays();
+    let frames = record(&displays, displays.panorama("HI 5"));
+    check("panorama", &frames);
+}
+
+#[test]
+fn pulse() {
+    let displays = displays();
+    let frames = record(&displays, displays[0].pulse());
+    check("pulse", &frames);
+}
+
+#[test]
+fn alert() {
+    let displays = displays();
+    let frames = record(&displays, displays.alert());
+    check("alert", &frames);
+}
+
+/// a weather report: held, then its details scroll by
+#[test]
+fn hold_then_scroll() {
+    let displays = displays();
+    let report = Weather::decode("23C,rain,45").unwrap();
+    let frames = record(&displays, async {
+        displays.hold(&report.frame(), Duration::from_secs(2)).await;
+        displays.panorama(&report.details()).await;
+    });
+    check("hold_then_scroll", &frames);
+}
+
+/// a message cut short by the next one, e.g. an urgent one coming in
+#[test]
+fn cut_short() {
+    let displays = displays();
+    let frames = record(&displays, async {
+        let scroll = displays.panorama("CUT SHORT");
+        select(scroll, Timer::after_millis(500)).await;
+        displays.alert().await;
+    });
+    check("cut_short", &frames);
+}
+
+#[test]
+fn flash_shows_each_graphic_for_100ms() {
+    let displays = displays();
+    let mut graphics = [&EMPTY; 8];
+    graphics[0] = &FULL;
+    graphics[2] = &FULL;
+    let flashes = Panorama { graphics, len: 3 };
+    let frames = record(&displays, displays[0].flash(&flashes));
+    assert_eq!(times(&frames), [0, 100, 200, 300]);
+}
+
+#[test]
+fn scrolling_moves_every_30ms() {
+    let displays = displays();
+    let frames = record(&displays, displays.panorama("TICK TOCK"));
+    let times = times(&frames);
+    assert!(times.len() > 20);
+    assert!(times.iter().all(|t| t % 30 == 0), "{:?}", times);
+}
+
+/// the scroll stops the moment an urgent message is queued, not at the
+/// next tick
+#[test]
+fn an_urgent_message_cuts_a_scroll_short() {
+    let displays = displays();
+    let start = Instant::now();
+    let scroll = select(displays.panorama("NOT FOR LONG"), urgent_queued());
+    let urgent = async {
+        Timer::after_millis(415).await;
+        let now = Instant::now().as_secs();
+        let message = Message::text("FIRE", URGENT).unwrap();
+        QUEUE.lock().await.push(message, now).unwrap();
+        QUEUED.signal(());
+        Timer::after_secs(3600).await;
+    };
+    let which = virtual_time::run(select(scroll, urgent), || {});
+    block_on(QUEUE.lock()).clear();
+    assert!(matches!(which, Either::First(Either::Second(()))));
+    assert_eq!(start.elapsed(), Duration::from_millis(415));
+}
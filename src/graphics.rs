@@ -440,8 +440,95 @@ pub const COLON: Graphic = [
     [0, 0, 0, 0, 0, 0, 0, 0],
 ];
 
+pub const MINUS: Graphic = [
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [1, 1, 1, 1, 1, 1, 0, 0],
+    [1, 1, 1, 1, 1, 1, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+];
+
+pub const SLASH: Graphic = [
+    [0, 0, 0, 0, 1, 1, 0, 0],
+    [0, 0, 0, 0, 1, 1, 0, 0],
+    [0, 0, 0, 1, 1, 0, 0, 0],
+    [0, 0, 0, 1, 1, 0, 0, 0],
+    [0, 0, 1, 1, 0, 0, 0, 0],
+    [0, 0, 1, 1, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+];
+
+pub const PERCENT: Graphic = [
+    [1, 1, 0, 0, 0, 1, 0, 0],
+    [1, 1, 0, 0, 1, 1, 0, 0],
+    [0, 0, 0, 1, 1, 0, 0, 0],
+    [0, 0, 1, 1, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [1, 1, 0, 0, 1, 1, 0, 0],
+    [1, 0, 0, 0, 1, 1, 0, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+];
+
+pub const ICON_SUN: Graphic = [
+    [1, 0, 0, 1, 1, 0, 0, 1],
+    [0, 1, 0, 0, 0, 0, 1, 0],
+    [0, 0, 1, 1, 1, 1, 0, 0],
+    [1, 0, 1, 1, 1, 1, 0, 1],
+    [1, 0, 1, 1, 1, 1, 0, 1],
+    [0, 0, 1, 1, 1, 1, 0, 0],
+    [0, 1, 0, 0, 0, 0, 1, 0],
+    [1, 0, 0, 1, 1, 0, 0, 1],
+];
+
+pub const ICON_CLOUD: Graphic = [
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 1, 1, 0, 0, 0],
+    [0, 0, 1, 1, 1, 1, 0, 0],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+    [1, 1, 1, 1, 1, 1, 1, 1],
+    [1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+];
+
+pub const ICON_RAIN: Graphic = [
+    [0, 0, 1, 1, 1, 0, 0, 0],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+    [1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 1, 0, 0, 1, 0, 0, 1],
+    [1, 0, 0, 1, 0, 0, 1, 0],
+    [0, 0, 0, 0, 0, 0, 0, 0],
+];
+
+pub const ICON_SNOW: Graphic = [
+    [0, 0, 0, 1, 0, 0, 0, 0],
+    [1, 0, 0, 1, 0, 0, 1, 0],
+    [0, 1, 0, 1, 0, 1, 0, 0],
+    [0, 0, 1, 1, 1, 0, 0, 0],
+    [1, 1, 1, 1, 1, 1, 1, 0],
+    [0, 0, 1, 1, 1, 0, 0, 0],
+    [0, 1, 0, 1, 0, 1, 0, 0],
+    [1, 0, 0, 1, 0, 0, 1, 0],
+];
+
+/// draws anything without a glyph as a question mark
 pub fn from_char(c: char) -> &'static Graphic {
-    match c.to_ascii_uppercase() {
+    glyph(c).unwrap_or(&QUESTION_MARK)
+}
+
+/// whether every char in `text` has a glyph
+pub fn check_text(text: &str) -> Result<(), crate::Error> {
+    text.chars().try_for_each(|c| glyph(c).map(|_| ()))
+}
+
+pub fn glyph(c: char) -> Result<&'static Graphic, crate::Error> {
+    let g = match c.to_ascii_uppercase() {
         'A' => &LETTER_A,
         'B' => &LETTER_B,
         'C' => &LETTER_C,
@@ -479,10 +566,123 @@ pub fn from_char(c: char) -> &'static Graphic {
         '8' => &DIGIT_8,
         '9' => &DIGIT_9,
         ':' => &COLON,
+        '-' => &MINUS,
+        '/' => &SLASH,
+        '%' => &PERCENT,
         ' ' => &EMPTY,
+        '?' => &QUESTION_MARK,
         '\x00' => &EMPTY,
-        _ => &QUESTION_MARK,
+        _ => return Err(crate::Error::UnknownGlyph(c)),
+    };
+    Ok(g)
+}
+
+/// all four panels side by side, in columns
+pub const STRIP_WIDTH: usize = 32;
+
+/// one column of pixels, top row in the lsb
+pub type Column = u8;
+
+/// the columns `text` scrolls by as, a glyph at a time. nothing is built
+/// up front, so text can be any length
+pub fn columns(text: &str) -> impl Iterator<Item = Column> + '_ {
+    text.chars().flat_map(|c| {
+        let g = from_char(c);
+        (0..g[0].len()).map(move |col| {
+            g.iter()
+                .enumerate()
+                .fold(0, |bits, (row, pixels)| bits | (pixels[col] & 1) << row)
+        })
+    })
+}
+
+/// moves the panels one column to the left, each one taking over the
+/// first column of the next, and puts `column` in at the right edge
+pub fn shift_in(strip: &mut [Graphic; 4], column: Column) {
+    for row in 0..8 {
+        for p in 0..strip.len() {
+            let incoming = match strip.get(p + 1) {
+                Some(next) => next[row][0],
+                None => (column >> row) & 1,
+            };
+            strip[p][row].copy_within(1.., 0);
+            strip[p][row][7] = incoming;
+        }
+    }
+}
+
+/// a 32x8 picture spanning all four panels, left to right.
+/// each row is 4 bytes; the msb of each byte is the leftmost pixel
+pub type Bitmap = [u8; 32];
+
+pub fn split_bitmap(bitmap: &Bitmap) -> [Graphic; 4] {
+    let mut panels = [EMPTY; 4];
+    for (p, panel) in panels.iter_mut().enumerate() {
+        for (r, row) in panel.iter_mut().enumerate() {
+            let byte = bitmap[r * 4 + p];
+            for (c, pixel) in row.iter_mut().enumerate() {
+                *pixel = (byte >> (7 - c)) & 1;
+            }
+        }
+    }
+    panels
+}
+
+pub fn join_graphics(panels: &[Graphic; 4]) -> Bitmap {
+    let mut bitmap = [0u8; 32];
+    for (p, panel) in panels.iter().enumerate() {
+        for (r, row) in panel.iter().enumerate() {
+            for (c, &pixel) in row.iter().enumerate() {
+                if pixel != 0 {
+                    bitmap[r * 4 + p] |= 1 << (7 - c);
+                }
+            }
+        }
     }
+    bitmap
+}
+
+/// parses 64 hex digits into a bitmap
+pub fn bitmap_from_hex(hex: &str) -> Option<Bitmap> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut bitmap = [0u8; 32];
+    for (byte, pair) in bitmap.iter_mut().zip(hex.chunks(2)) {
+        let hi = (pair[0] as char).to_digit(16)?;
+        let lo = (pair[1] as char).to_digit(16)?;
+        *byte = (hi << 4 | lo) as u8;
+    }
+    Some(bitmap)
+}
+
+pub fn write_hex(
+    out: &mut impl core::fmt::Write,
+    bytes: &[u8],
+) -> core::fmt::Result {
+    for byte in bytes {
+        write!(out, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+/// one line per row, `#` for a lit pixel and `.` for an unlit one
+pub fn write_art(
+    out: &mut impl core::fmt::Write,
+    bitmap: &Bitmap,
+) -> core::fmt::Result {
+    for row in bitmap.chunks(4) {
+        for byte in row {
+            for bit in (0..8).rev() {
+                let lit = (byte >> bit) & 1 == 1;
+                out.write_char(if lit { '#' } else { '.' })?;
+            }
+        }
+        out.write_char('\n')?;
+    }
+    Ok(())
 }
 
 pub const MAX_LEN: usize = 8; // arbitrary
@@ -520,3 +720,97 @@ pub struct Panorama {
 // }
 
 // pub(crate) use text;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn chars_without_glyphs_are_question_marks() {
+        assert_eq!(from_char('a'), &LETTER_A);
+        assert_eq!(from_char('Z'), &LETTER_Z);
+        assert_eq!(from_char('7'), &DIGIT_7);
+        assert_eq!(from_char('~'), &QUESTION_MARK);
+        assert_eq!(from_char('é'), &QUESTION_MARK);
+        assert_eq!(glyph('é'), Err(crate::Error::UnknownGlyph('é')));
+        assert_eq!(check_text("12:30 ok"), Ok(()));
+        assert_eq!(check_text("ok?!"), Err(crate::Error::UnknownGlyph('!')));
+    }
+
+    #[test]
+    fn columns_are_glyphs_on_their_side() {
+        let columns: Vec<Column> = columns("H").collect();
+        assert_eq!(columns.len(), 8);
+        for (col, bits) in columns.iter().enumerate() {
+            for (row, pixels) in LETTER_H.iter().enumerate() {
+                assert_eq!(bits >> row & 1, pixels[col]);
+            }
+        }
+    }
+
+    #[test]
+    fn scrolled_text_crosses_the_panels_right_to_left() {
+        let mut strip = [EMPTY; 4];
+        let mut columns = columns("ABCD");
+
+        for column in columns.by_ref().take(16) {
+            shift_in(&mut strip, column);
+        }
+        assert_eq!(strip, [EMPTY, EMPTY, LETTER_A, LETTER_B]);
+
+        for column in columns {
+            shift_in(&mut strip, column);
+        }
+        assert_eq!(strip, [LETTER_A, LETTER_B, LETTER_C, LETTER_D]);
+
+        for _ in 0..STRIP_WIDTH {
+            shift_in(&mut strip, 0);
+        }
+        assert_eq!(strip, [EMPTY; 4]);
+    }
+
+    #[test]
+    fn hex_and_art() {
+        let mut bitmap = [0u8; 32];
+        bitmap[0] = 0x80;
+        bitmap[31] = 0x01;
+
+        let mut hex = heapless::String::<64>::new();
+        write_hex(&mut hex, &bitmap).unwrap();
+        assert_eq!(bitmap_from_hex(&hex), Some(bitmap));
+        assert_eq!(bitmap_from_hex(&hex[1..]), None);
+        assert_eq!(bitmap_from_hex(&hex.replace('0', "g")), None);
+
+        let mut art = heapless::String::<512>::new();
+        write_art(&mut art, &bitmap).unwrap();
+        let rows: Vec<&str> = art.lines().collect();
+        assert_eq!(rows.len(), 8);
+        assert!(rows[0].starts_with("#."));
+        assert!(rows[7].ends_with(".#"));
+    }
+
+    proptest! {
+        #[test]
+        fn eight_columns_per_char(text in "\\PC{0,64}") {
+            prop_assert_eq!(columns(&text).count(), 8 * text.chars().count());
+        }
+
+        #[test]
+        fn split_and_join_round_trip(bitmap in any::<Bitmap>()) {
+            prop_assert_eq!(join_graphics(&split_bitmap(&bitmap)), bitmap);
+        }
+
+        #[test]
+        fn hex_round_trips(bitmap in any::<Bitmap>()) {
+            let mut hex = heapless::String::<64>::new();
+            write_hex(&mut hex, &bitmap).unwrap();
+            prop_assert_eq!(bitmap_from_hex(&hex), Some(bitmap));
+        }
+
+        #[test]
+        fn hex_never_panics(hex in "\\PC{0,80}") {
+            let _ = bitmap_from_hex(&hex);
+        }
+    }
+}
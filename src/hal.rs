@@ -1,11 +1,14 @@
 use crate::graphics::Graphic;
+#[cfg(feature = "firmware")]
 use embassy_rp::gpio::{AnyPin, Level, Output};
 
+#[cfg(feature = "firmware")]
 pub enum Line<'a> {
     Anode(Output<'a, AnyPin>),
     Cathode(Output<'a, AnyPin>),
 }
 
+#[cfg(feature = "firmware")]
 impl<'a> Line<'a> {
     pub fn new_anode(pin: AnyPin) -> Self {
         Self::Anode(Output::new(pin, Level::Low))
@@ -31,6 +34,7 @@ impl<'a> Line<'a> {
 }
 
 // TODO: rewrite. this should be a generic ShiftRegister
+#[cfg(feature = "firmware")]
 pub struct ShiftRegister<'a> {
     pub ser: Line<'a>,
     // pub oe: Line<'a>,
@@ -39,6 +43,7 @@ pub struct ShiftRegister<'a> {
     pub srclr: Line<'a>,
 }
 
+#[cfg(feature = "firmware")]
 impl<'a> ShiftRegister<'a> {
     // 1s represent disabled columns (cathodes)
     // TODO: this shouldn't be in the generic driver...
@@ -74,10 +79,29 @@ impl<'a> ShiftRegister<'a> {
     }
 }
 
+/// stands in for the shift register when the library is built for the
+/// host, and keeps the words written to it instead of clocking them out
+#[cfg(not(feature = "firmware"))]
+#[derive(Default)]
+pub struct ShiftRegister<'a> {
+    /// the oldest words are dropped once it's full
+    pub words: heapless::Vec<u16, 64>,
+    pins: core::marker::PhantomData<&'a ()>,
+}
+
+#[cfg(not(feature = "firmware"))]
+impl<'a> ShiftRegister<'a> {
+    pub fn write_short(&mut self, data: u16) {
+        if self.words.is_full() {
+            self.words.remove(0);
+        }
+        let _ = self.words.push(data);
+    }
+}
+
 pub struct DotMatrixLed<'a> {
     pub sr: ShiftRegister<'a>,
     pub graphic: Graphic,
-    pub overridden: bool,
 }
 
 impl<'a> DotMatrixLed<'a> {
@@ -103,3 +127,72 @@ impl<'a> DotMatrixLed<'a> {
         self.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::EMPTY;
+    use proptest::prelude::*;
+
+    // the pins each row and column are wired to, counted from 1
+    const ROW_PINS: [u16; 8] = [9, 14, 8, 12, 1, 7, 2, 5];
+    const COL_PINS: [u16; 8] = [13, 3, 4, 10, 6, 11, 15, 16];
+
+    fn render(graphic: Graphic) -> heapless::Vec<u16, 64> {
+        let mut d = DotMatrixLed {
+            sr: ShiftRegister::default(),
+            graphic,
+        };
+        d.render();
+        d.sr.words
+    }
+
+    fn is_lit(word: u16, row: usize, col: usize) -> bool {
+        word & 1 << (ROW_PINS[row] - 1) != 0
+            && word & 1 << (COL_PINS[col] - 1) == 0
+    }
+
+    #[test]
+    fn a_blank_display_only_writes_the_empty_signal() {
+        let words = render(EMPTY);
+        assert_eq!(words.len(), 9);
+        assert!(words.iter().all(|&w| w == DotMatrixLed::EMPTY_SIGNAL));
+    }
+
+    #[test]
+    fn top_left_pixel() {
+        let mut graphic = EMPTY;
+        graphic[0][0] = 1;
+        let words = render(graphic);
+        // row 0 is on pin 9, and column 0 is pulled low on pin 13
+        assert_eq!(words[0], 0b1100_0111_0010_1100);
+        assert!(words[1..].iter().all(|&w| w == DotMatrixLed::EMPTY_SIGNAL));
+    }
+
+    #[test]
+    fn the_mock_keeps_the_latest_words() {
+        let mut sr = ShiftRegister::default();
+        for word in 0..100 {
+            sr.write_short(word);
+        }
+        assert_eq!(sr.words.len(), 64);
+        assert_eq!(sr.words.first(), Some(&36));
+        assert_eq!(sr.words.last(), Some(&99));
+    }
+
+    proptest! {
+        #[test]
+        fn one_word_per_row_then_a_clear(
+            graphic in prop::array::uniform8(prop::array::uniform8(0u8..2))
+        ) {
+            let words = render(graphic);
+            prop_assert_eq!(words.len(), 9);
+            for (row, pixels) in graphic.iter().enumerate() {
+                for (col, &pixel) in pixels.iter().enumerate() {
+                    prop_assert_eq!(is_lit(words[row], row, col), pixel == 1);
+                }
+            }
+            prop_assert_eq!(words[8], DotMatrixLed::EMPTY_SIGNAL);
+        }
+    }
+}
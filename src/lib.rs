@@ -1,30 +1,80 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-use core::cmp::min;
 use core::default::Default;
 use core::ops::Deref;
 use core::str;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex,
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal,
 };
-use embassy_time::{Duration, Ticker};
-
+use embassy_time::{Duration, Instant, Ticker};
+
+pub mod auth;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod crash;
+#[cfg(test)]
+mod golden;
 pub mod graphics;
 pub mod hal;
+pub mod liveness;
+#[cfg(test)]
+mod mock_flash;
 pub mod network;
+#[cfg(feature = "firmware")]
+pub mod ota;
+pub mod playlist;
+pub mod queue;
+#[cfg(feature = "firmware")]
+pub mod serialdrv;
+pub mod status;
+pub mod storage;
+#[cfg(test)]
+mod virtual_time;
+pub mod weather;
+#[cfg(feature = "firmware")]
 pub use network::tcpserver;
 
-// pub mod serialdrv;
-
-use graphics::Graphic;
+use graphics::{Bitmap, Graphic};
 
 use hal::DotMatrixLed;
+use playlist::Playlist;
+use queue::MessageQueue;
+use weather::{Unit, Weather};
 
 pub static DISPLAYS: Displays<'static> = Displays::new();
 pub static DATA: Mutex<CriticalSectionRawMutex, Data> =
     Mutex::new(Data::new());
 
+pub static PLAYLIST: Mutex<CriticalSectionRawMutex, Playlist> =
+    Mutex::new(Playlist::new());
+
+pub static QUEUE: Mutex<CriticalSectionRawMutex, MessageQueue> =
+    Mutex::new(MessageQueue::new());
+/// signalled whenever a message is queued
+pub static QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// resolves once an urgent message is waiting
+pub async fn urgent_queued() {
+    loop {
+        if QUEUE.lock().await.has_urgent(Instant::now().as_secs()) {
+            return;
+        }
+        QUEUED.wait().await;
+    }
+}
+
+pub const MAX_BRIGHTNESS: u8 = 8;
+/// the displays are only lit on this many out of every `MAX_BRIGHTNESS`
+/// render passes
+pub static BRIGHTNESS: AtomicU8 = AtomicU8::new(MAX_BRIGHTNESS);
+
+pub fn brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
 pub struct DotMatrixLedMutex<'a>(
     pub Mutex<CriticalSectionRawMutex, Option<DotMatrixLed<'a>>>,
 );
@@ -37,44 +87,11 @@ impl<'a> Default for DotMatrixLedMutex<'a> {
 
 impl<'a> DotMatrixLedMutex<'a> {
     const FLASH_DURATION: Duration = Duration::from_millis(100);
-    const OVERRIDE_CHECK_INTERVAL: Duration = Duration::from_micros(100);
 
     pub const fn new() -> Self {
         Self(Mutex::new(None))
     }
 
-    pub async fn overridden(&self) -> bool {
-        match self.0.lock().await.as_ref() {
-            Some(d) => d.overridden,
-            None => false,
-        }
-    }
-
-    pub async fn set_override(&self, state: bool) {
-        let mut ticker = Ticker::every(Self::OVERRIDE_CHECK_INTERVAL);
-        while self.overridden().await && state {
-            ticker.next().await;
-        }
-        if let Some(d) = self.0.lock().await.as_mut() {
-            d.overridden = state;
-        }
-    }
-
-    /// TODO: deprecate. the term "lock/ed" is confusing in a Mutex context
-    pub async fn locked(&self) -> bool {
-        self.overridden().await
-    }
-
-    /// TODO: deprecate
-    pub async fn lock(&self) {
-        self.set_override(true).await;
-    }
-
-    /// TODO: deprecate
-    pub async fn unlock(&self) {
-        self.set_override(false).await;
-    }
-
     pub async fn render(&self) {
         if let Some(d) = self.0.lock().await.as_mut() {
             d.render();
@@ -87,18 +104,18 @@ impl<'a> DotMatrixLedMutex<'a> {
         }
     }
 
-    pub async fn flash(
-        &self,
-        panorama: &graphics::Panorama,
-        ignore_lock: bool,
-    ) {
+    /// what the display is currently showing
+    pub async fn graphic(&self) -> Graphic {
+        match self.0.lock().await.as_ref() {
+            Some(d) => d.graphic,
+            None => graphics::EMPTY,
+        }
+    }
+
+    pub async fn flash(&self, panorama: &graphics::Panorama) {
         let mut ticker = Ticker::every(Self::FLASH_DURATION);
 
         for i in 0..panorama.len {
-            while (!ignore_lock) && self.overridden().await {
-                ticker.next().await;
-            }
-
             let graphic = panorama.graphics[i];
             self.draw(graphic).await;
             ticker.next().await;
@@ -111,14 +128,12 @@ impl<'a> DotMatrixLedMutex<'a> {
         let mut iters = 0;
 
         loop {
-            while self.locked().await {
-                ticker.next().await;
-            }
             // moving diagonal stripe
             let mut canvas = graphics::EMPTY;
             for (row_index, row) in canvas.iter_mut().enumerate() {
-                let n = counter - row_index;
-                if n < row.len() {
+                // rows the stripe hasn't reached yet stay dark
+                let n = counter.checked_sub(row_index);
+                if let Some(n) = n.filter(|&n| n < row.len()) {
                     row[n] = 1;
                 }
             }
@@ -135,47 +150,6 @@ impl<'a> DotMatrixLedMutex<'a> {
             }
         }
     }
-
-    pub async fn panorama2(&self, message: &str, prio: bool) {
-        // let panorama_cols = message.len() * 8;
-        let mut ticker = Ticker::every(Duration::from_millis(30));
-
-        for pair in message.chars().zip(message.chars().skip(1)) {
-            let mut cursor = 0;
-            let (a, b) = {
-                let (a, b) = pair;
-                (graphics::from_char(a), graphics::from_char(b))
-            };
-
-            // 8, bc i only care about the first char and pieces of the 2nd
-            while cursor < 8 {
-                while (!prio) && self.overridden().await {
-                    ticker.next().await;
-                }
-
-                let mut canvas = graphics::EMPTY;
-                for r in 0..canvas.len() {
-                    for canvas_c in 0..canvas[r].len() {
-                        let panorama_c = canvas_c + cursor;
-                        let frame_c = panorama_c % 8;
-                        let frame = {
-                            // a or b?
-                            if panorama_c < 8 {
-                                a
-                            } else {
-                                b
-                            }
-                        };
-                        canvas[r][canvas_c] = frame[r][frame_c];
-                    }
-                }
-
-                cursor += 1;
-                self.draw(&canvas).await;
-                ticker.next().await;
-            }
-        }
-    }
 }
 
 #[derive(Default)]
@@ -189,6 +163,9 @@ impl<'a> Deref for Displays<'a> {
 }
 
 impl<'a> Displays<'a> {
+    /// how long each column of scrolling text stays put
+    const SCROLL_STEP: Duration = Duration::from_millis(30);
+
     pub const fn new() -> Self {
         Self([
             DotMatrixLedMutex::new(),
@@ -198,22 +175,46 @@ impl<'a> Displays<'a> {
         ])
     }
 
-    pub async fn panorama(&self, message: &str, prio: bool) {
-        let p = pad(message.as_bytes());
-        let message = get_null_term_string(&p).unwrap_or("");
-        embassy_futures::join::join4(
-            self[0].panorama2(message, prio),
-            self[1].panorama2(&message[1..], prio),
-            self[2].panorama2(&message[2..], prio),
-            self[3].panorama2(&message[3..], prio),
-        )
-        .await;
+    /// scrolls `message` across all four displays, right to left, until
+    /// it's gone. glyphs are looked up as they come in, so a message can be
+    /// any length. only the animate task scrolls, and it keeps checking in
+    pub async fn panorama(&self, message: &str) {
+        let mut strip = [graphics::EMPTY; 4];
+        let mut ticker = Ticker::every(Self::SCROLL_STEP);
+        let trailer = core::iter::repeat_n(0, graphics::STRIP_WIDTH);
+        for column in graphics::columns(message).chain(trailer) {
+            graphics::shift_in(&mut strip, column);
+            for (d, g) in self.0.iter().zip(&strip) {
+                d.draw(g).await;
+            }
+            liveness::check_in(liveness::Task::Animate);
+            ticker.next().await;
+        }
+    }
+
+    pub async fn draw_bitmap(&self, bitmap: &Bitmap) {
+        let panels = graphics::split_bitmap(bitmap);
+        for (d, g) in self.0.iter().zip(panels.iter()) {
+            d.draw(g).await;
+        }
+    }
+
+    /// what all four displays are currently showing, as one picture
+    pub async fn snapshot(&self) -> Bitmap {
+        let mut panels = [graphics::EMPTY; 4];
+        for (d, g) in self.0.iter().zip(panels.iter_mut()) {
+            *g = d.graphic().await;
+        }
+        graphics::join_graphics(&panels)
     }
 
-    pub async fn set_override(&self, v: bool) {
-        for d in &self.0 {
-            d.set_override(v).await;
+    /// shows one graphic per display for `duration`. only the animate task
+    /// holds, and it keeps checking in meanwhile
+    pub async fn hold(&self, frame: &[&Graphic; 4], duration: Duration) {
+        for (d, &g) in self.0.iter().zip(frame) {
+            d.draw(g).await;
         }
+        liveness::sleep(liveness::Task::Animate, duration).await;
     }
 
     pub async fn alert(&self) {
@@ -229,27 +230,143 @@ impl<'a> Displays<'a> {
             len: 8,
         };
         embassy_futures::join::join4(
-            self[0].flash(&alert, true),
-            self[1].flash(&alert, true),
-            self[2].flash(&alert, true),
-            self[3].flash(&alert, true),
+            self[0].flash(&alert),
+            self[1].flash(&alert),
+            self[2].flash(&alert),
+            self[3].flash(&alert),
         )
         .await;
     }
 }
 
-#[derive(Debug)]
+/// everything that can go wrong in here. the command port, mqtt and http
+/// report these as `error <code>: <what>`, see `code`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
 pub enum Error {
+    /// not valid utf-8
     Utf8,
-    // DataLength,
+    /// too long for where it was going, and would have been cut short
+    Truncated,
+    /// there's no glyph to draw this with
+    UnknownGlyph(char),
+    OutOfRange,
+    /// args that don't parse
+    Malformed,
+    /// a list, or a reply, has no room left
+    Full,
+    /// the message queue has no room left
+    QueueFull,
+    UnknownCommand,
+    /// the key in use isn't allowed to do that
+    Denied,
+    /// something on the wire that doesn't follow the protocol
+    Protocol,
+    /// the flash or the wifi chip failed
+    Driver,
+    /// the connection failed or went away
+    Network,
+}
+
+impl Error {
+    /// what the error is called on the wire. these stay put, new errors
+    /// get new codes
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Utf8 => 1,
+            Self::Truncated => 2,
+            Self::UnknownGlyph(_) => 3,
+            Self::OutOfRange => 4,
+            Self::Malformed => 5,
+            Self::Full => 6,
+            Self::QueueFull => 7,
+            Self::UnknownCommand => 8,
+            Self::Denied => 9,
+            Self::Protocol => 10,
+            Self::Driver => 11,
+            Self::Network => 12,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Utf8 => f.write_str("not utf-8"),
+            Self::Truncated => f.write_str("too long"),
+            Self::UnknownGlyph(c) => write!(f, "no glyph for {:?}", c),
+            Self::OutOfRange => f.write_str("out of range"),
+            Self::Malformed => f.write_str("malformed"),
+            Self::Full => f.write_str("no room left"),
+            Self::QueueFull => f.write_str("queue full"),
+            Self::UnknownCommand => f.write_str("unknown command"),
+            Self::Denied => f.write_str("not allowed"),
+            Self::Protocol => f.write_str("protocol error"),
+            Self::Driver => f.write_str("driver error"),
+            Self::Network => f.write_str("network error"),
+        }
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(_: str::Utf8Error) -> Self {
+        Self::Utf8
+    }
 }
 
-type ClockString = [u8; 16];
-type WeatherString = [u8; 16];
+/// replies are written with `write!`, and only fail when they're full
+impl From<core::fmt::Error> for Error {
+    fn from(_: core::fmt::Error) -> Self {
+        Self::Full
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self {
+        match e {
+            storage::Error::Flash => Self::Driver,
+            storage::Error::BadKey => Self::Protocol,
+            storage::Error::TooLarge => Self::Truncated,
+            storage::Error::Full => Self::Full,
+        }
+    }
+}
+
+impl From<network::mqtt::Error> for Error {
+    fn from(e: network::mqtt::Error) -> Self {
+        use network::mqtt::Error as E;
+        match e {
+            E::Buffer => Self::Full,
+            E::Malformed | E::Refused(_) => Self::Protocol,
+            E::Network | E::Closed => Self::Network,
+        }
+    }
+}
+
+impl From<embassy_net::tcp::Error> for Error {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Self::Network
+    }
+}
+
+pub const MAX_CLOCK_LEN: usize = 32;
+pub const MAX_WEATHER_LEN: usize = 128;
+
+type ClockString = [u8; MAX_CLOCK_LEN];
+type WeatherString = [u8; MAX_WEATHER_LEN];
+
+#[derive(Clone, Copy)]
+pub enum WeatherData {
+    /// free-form text, scrolled as-is
+    Text(WeatherString),
+    Report(Weather),
+}
 
 pub struct Data {
     pub clock: Option<ClockString>,
-    pub weather: Option<WeatherString>,
+    pub weather: Option<WeatherData>,
+    /// reports are converted to this before being shown
+    pub weather_unit: Unit,
 }
 
 impl Data {
@@ -258,63 +375,94 @@ impl Data {
         Self {
             clock: None,
             weather: None,
+            weather_unit: Unit::Celsius,
         }
     }
 }
 
+/// copies `buf` into a null padded array. text that doesn't fit is an
+/// error rather than being cut short
 pub fn copy_str_bytes<const LEN: usize>(
     buf: &[u8],
 ) -> Result<[u8; LEN], Error> {
-    if str::from_utf8(buf).is_err() {
-        return Err(Error::Utf8);
+    str::from_utf8(buf)?;
+    if buf.len() > LEN {
+        return Err(Error::Truncated);
     }
 
     let mut out = [0u8; LEN];
-    let copy_len = min(buf.len(), out.len());
-    out[..copy_len].copy_from_slice(&buf[..copy_len]);
+    out[..buf.len()].copy_from_slice(buf);
 
     Ok(out)
 }
 
-/// pads string with spaces, so it can be scrolled on displays
-/// four spaces are prepended, and one space is appended
-/// the string will be truncated if it is more than 59 (64 - 5) chars
-pub fn pad(string: &[u8]) -> [u8; 64] {
-    let mut out = [0x0; 64];
-    let mut cursor = 0;
+/// the text up to the first null, or all of it when there's no null
+pub fn get_null_term_string(data: &[u8]) -> Result<&str, Error> {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    Ok(str::from_utf8(&data[..end])?)
+}
 
-    for _i in 0..4 {
-        out[cursor] = 0x20; // スペース文字
-        cursor += 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn null_term_string_stops_at_the_first_null() {
+        assert_eq!(get_null_term_string(b"12:34\0\0\0"), Ok("12:34"));
+        assert_eq!(get_null_term_string(b"\0WAT\0"), Ok(""));
+        assert_eq!(get_null_term_string(b"12:34"), Ok("12:34"));
+        assert_eq!(get_null_term_string(b""), Ok(""));
+        assert_eq!(get_null_term_string(b"\xff\0"), Err(Error::Utf8));
     }
 
-    use core::cmp::min;
-    let msg_part_len = min(string.len(), out.len() - 5);
+    #[test]
+    fn copy_str_bytes_pads_with_nulls() {
+        assert_eq!(copy_str_bytes::<8>(b"abc"), Ok(*b"abc\0\0\0\0\0"));
+        assert_eq!(copy_str_bytes::<3>(b"abc"), Ok(*b"abc"));
+        assert_eq!(copy_str_bytes::<3>(b"abcd"), Err(Error::Truncated));
+        // half a char is never copied
+        assert_eq!(copy_str_bytes::<8>(b"\xc3"), Err(Error::Utf8));
+    }
 
-    for &character in string.iter().take(msg_part_len) {
-        if character == 0 {
-            break; // doing this bc calling from clock() and weather(), string contains nulls and if i don't break here then the final 0x20 under will be pushed back and eventually cut by null_term_string. these funcs need to be merged somehow...
+    #[test]
+    fn error_codes_are_all_different() {
+        let errors = [
+            Error::Utf8,
+            Error::Truncated,
+            Error::UnknownGlyph('~'),
+            Error::OutOfRange,
+            Error::Malformed,
+            Error::Full,
+            Error::QueueFull,
+            Error::UnknownCommand,
+            Error::Denied,
+            Error::Protocol,
+            Error::Driver,
+            Error::Network,
+        ];
+        for (i, e) in errors.iter().enumerate() {
+            assert_eq!(e.code() as usize, i + 1);
         }
-        out[cursor] = character;
-        cursor += 1;
     }
 
-    // after last char from string
-    out[cursor] = 0x20;
-    // cursor += 1;
-
-    out
-}
-
-pub fn get_null_term_string(data: &[u8]) -> Result<&str, Error> {
-    let mut null_index = 0;
+    proptest! {
+        #[test]
+        fn copied_text_reads_back(text in "\\PC{0,40}") {
+            match copy_str_bytes::<MAX_CLOCK_LEN>(text.as_bytes()) {
+                Ok(buf) => {
+                    prop_assert_eq!(get_null_term_string(&buf), Ok(&*text))
+                }
+                Err(e) => {
+                    prop_assert_eq!(e, Error::Truncated);
+                    prop_assert!(text.len() > MAX_CLOCK_LEN);
+                }
+            }
+        }
 
-    for (index, &byte) in data.iter().enumerate() {
-        if byte == 0 {
-            null_index = index;
-            break;
+        #[test]
+        fn null_term_string_never_panics(data in any::<Vec<u8>>()) {
+            let _ = get_null_term_string(&data);
         }
     }
-
-    str::from_utf8(&data[..null_index]).map_err(|_| Error::Utf8)
 }
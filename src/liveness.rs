@@ -0,0 +1,184 @@
+//! keeps the watchdog fed only while every critical task is making
+//! progress. tasks check in as they go; one that goes quiet for longer
+//! than its timeout stops the feeding, and the watchdog restarts the sign.
+//! the task that stalled is left in the crash log
+
+use core::cell::RefCell;
+
+#[cfg(feature = "firmware")]
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+#[cfg(feature = "firmware")]
+use crate::crash;
+
+#[cfg(feature = "firmware")]
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Task {
+    Render,
+    Animate,
+    Blinky,
+    Commands,
+    /// a fresh update that hasn't confirmed itself yet, see `ota`
+    Trial,
+}
+
+const TASKS: [Task; 5] = [
+    Task::Render,
+    Task::Animate,
+    Task::Blinky,
+    Task::Commands,
+    Task::Trial,
+];
+
+impl Task {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Render => "render",
+            Self::Animate => "animate",
+            Self::Blinky => "blinky",
+            Self::Commands => "commands",
+            Self::Trial => "trial",
+        }
+    }
+
+    /// how long the task may go without checking in
+    pub fn timeout_ms(self) -> u64 {
+        match self {
+            Self::Render => 1000,
+            // holds and scrolls check in as they go, so this only covers
+            // the pulses and alerts in between
+            Self::Animate => 30_000,
+            Self::Blinky => 5000,
+            Self::Commands => 30_000,
+            Self::Trial => 10_000,
+        }
+    }
+}
+
+/// when each task last checked in. a task is only watched from its first
+/// check in, and not while it's paused. time only comes in through the
+/// `now_ms` arguments
+pub struct Monitor {
+    last_ms: [Option<u64>; TASKS.len()],
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Monitor {
+    pub const fn new() -> Self {
+        Self {
+            last_ms: [None; TASKS.len()],
+        }
+    }
+
+    pub fn check_in(&mut self, task: Task, now_ms: u64) {
+        self.last_ms[task as usize] = Some(now_ms);
+    }
+
+    /// stops watching `task` until it checks in again, for when it waits
+    /// on something that can take any amount of time
+    pub fn pause(&mut self, task: Task) {
+        self.last_ms[task as usize] = None;
+    }
+
+    /// the first task that has been quiet for too long, if any
+    pub fn stalled(&self, now_ms: u64) -> Option<Task> {
+        TASKS.into_iter().find(|&task| {
+            self.last_ms[task as usize].is_some_and(|last| {
+                now_ms.saturating_sub(last) > task.timeout_ms()
+            })
+        })
+    }
+}
+
+static MONITOR: Mutex<CriticalSectionRawMutex, RefCell<Monitor>> =
+    Mutex::new(RefCell::new(Monitor::new()));
+
+pub fn check_in(task: Task) {
+    let now = Instant::now().as_millis();
+    MONITOR.lock(|m| m.borrow_mut().check_in(task, now));
+}
+
+pub fn pause(task: Task) {
+    MONITOR.lock(|m| m.borrow_mut().pause(task));
+}
+
+/// waits out `duration` while still checking in, for holds that can be
+/// longer than the task's timeout
+pub async fn sleep(task: Task, duration: Duration) {
+    let until = Instant::now() + duration;
+    loop {
+        check_in(task);
+        let now = Instant::now();
+        if now >= until {
+            return;
+        }
+        Timer::after((until - now).min(CHECK_INTERVAL)).await;
+    }
+}
+
+#[cfg(feature = "firmware")]
+#[embassy_executor::task]
+pub async fn watch(mut watchdog: Watchdog) {
+    watchdog.start(WATCHDOG_TIMEOUT);
+    loop {
+        let now = Instant::now().as_millis();
+        if let Some(task) = MONITOR.lock(|m| m.borrow().stalled(now)) {
+            crash::record_stall(task.name());
+            // stop feeding, and let the watchdog restart us
+            core::future::pending::<()>().await;
+        }
+        watchdog.feed();
+        Timer::after(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_tasks_that_checked_in_are_watched() {
+        let monitor = Monitor::new();
+        assert_eq!(monitor.stalled(u64::MAX), None);
+    }
+
+    #[test]
+    fn a_quiet_task_stalls() {
+        let mut monitor = Monitor::new();
+        monitor.check_in(Task::Render, 0);
+        monitor.check_in(Task::Blinky, 0);
+        assert_eq!(monitor.stalled(1000), None);
+        assert_eq!(monitor.stalled(1001), Some(Task::Render));
+
+        monitor.check_in(Task::Render, 4500);
+        assert_eq!(monitor.stalled(5001), Some(Task::Blinky));
+    }
+
+    #[test]
+    fn paused_tasks_are_let_be() {
+        let mut monitor = Monitor::new();
+        monitor.check_in(Task::Commands, 0);
+        monitor.pause(Task::Commands);
+        assert_eq!(monitor.stalled(60_000), None);
+        monitor.check_in(Task::Commands, 60_000);
+        assert_eq!(monitor.stalled(90_001), Some(Task::Commands));
+    }
+
+    #[test]
+    fn a_clock_going_backwards_isnt_a_stall() {
+        let mut monitor = Monitor::new();
+        monitor.check_in(Task::Trial, 5000);
+        assert_eq!(monitor.stalled(0), None);
+    }
+}
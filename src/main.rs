@@ -2,74 +2,257 @@
 #![no_main]
 
 use core::panic::PanicInfo;
-use core::str;
+use core::sync::atomic::Ordering;
 
+use dotmatrix::clock::{self, ClockConfig, Dst, HourFormat};
+use dotmatrix::config::{self, Key, NetworkConfig};
+use dotmatrix::crash::{self, LAST_CRASH};
 use dotmatrix::hal::{DotMatrixLed, Line, ShiftRegister};
+use dotmatrix::liveness::{self, Task};
+use dotmatrix::network::mqtt::{self, MqttConfig};
+use dotmatrix::network::supervisor::{self, SupervisorConfig};
+use dotmatrix::network::{ddp, httpserver, mdns, sntp};
+use dotmatrix::ota::{self, OnboardFlash, SharedFlash};
+use dotmatrix::playlist::{Animation, Content, Playlist, Slot};
+use dotmatrix::queue::{Effect, Message, NORMAL};
+use dotmatrix::serialdrv::{self, USB_DRIVER};
+use dotmatrix::status::{self, Showing};
+use dotmatrix::storage::{self, Store};
 use dotmatrix::{graphics, tcpserver};
 
 use defmt_rtt as _;
+use embassy_embedded_hal::flash::partition::Partition;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, Either};
+use embassy_rp::flash::Flash;
 use embassy_rp::gpio::{AnyPin, Level};
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_rp::usb::Driver;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Ticker, Timer};
 
 use cyw43_pio::PioSpi;
 use embassy_rp::gpio::Output;
 use embassy_rp::pio::Pio;
 
+use dotmatrix::WeatherData;
 use dotmatrix::DATA;
 use dotmatrix::DISPLAYS;
+use dotmatrix::{brightness, MAX_BRIGHTNESS};
+use dotmatrix::{get_null_term_string, Error};
+use dotmatrix::{urgent_queued, PLAYLIST, QUEUE, QUEUED};
 
-use embassy_net::{Ipv4Address, Ipv4Cidr, StaticConfigV4};
+use embassy_net::{
+    IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, StaticConfigV4,
+};
 use heapless::Vec;
 use pico_wifi::{configure_network, WifiConfiguration};
+use static_cell::StaticCell;
 
-include!("../credentials.rs");
+/// ntp.nict.jp
+const NTP_SERVER: IpEndpoint =
+    IpEndpoint::new(IpAddress::v4(133, 243, 238, 164), 123);
+const CLOCK_CONFIG: ClockConfig = ClockConfig {
+    offset_minutes: 9 * 60, // jst
+    dst: Dst::None,
+    format: HourFormat::H24,
+};
+
+/// the config store takes the end of flash. memory.x keeps the firmware
+/// out of it
+const STORE_OFFSET: u32 = ota::FLASH_SIZE as u32 - storage::STORE_SIZE;
+/// changes are saved once things have been quiet for this long
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+/// shown when there's no wifi to join
+const SETUP_MESSAGE: &str = "NO WIFI - SET UP OVER USB";
+/// scroll `NO WIFI` when the connection drops
+const SHOW_OUTAGES: bool = true;
+
+type StoreFlash = Partition<'static, NoopRawMutex, OnboardFlash>;
 
 #[panic_handler]
-fn panic(_: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    crash::record(info);
+    crash::halt()
+}
+
+async fn clock() -> Result<(), Error> {
+    // a pushed clock string overrides the synced clock
+    let pushed = DATA.lock().await.clock;
+    if let Some(clock) = pushed {
+        let string = get_null_term_string(&clock)?;
+        DISPLAYS.panorama(string).await;
+    } else if let Some(face) = clock::face() {
+        DISPLAYS.panorama(&face).await;
+    } else {
+        return Ok(());
+    }
+    for d in &*DISPLAYS {
+        d.pulse().await;
+    }
+    Ok(())
 }
 
-async fn clock() {
-    if let Some(clock) = DATA.lock().await.clock {
-        let string = str::from_utf8(&clock[..]).unwrap();
-        DISPLAYS.panorama(string, false).await;
-        for d in &*DISPLAYS {
-            d.pulse().await;
+async fn weather(duration: Duration) -> Result<(), Error> {
+    let (weather, unit) = {
+        let data = DATA.lock().await;
+        (data.weather, data.weather_unit)
+    };
+    match weather {
+        Some(WeatherData::Text(weather)) => {
+            let string = get_null_term_string(&weather)?;
+            DISPLAYS.panorama(string).await;
+        }
+        Some(WeatherData::Report(report)) => {
+            let report = report.to_unit(unit);
+            DISPLAYS.hold(&report.frame(), duration).await;
+            let details = report.details();
+            if !details.is_empty() {
+                DISPLAYS.panorama(&details).await;
+            }
         }
+        None => return Ok(()),
+    }
+    for d in &*DISPLAYS {
+        d.pulse().await;
     }
+    Ok(())
 }
 
-async fn weather() {
-    if let Some(weather) = DATA.lock().await.weather {
-        let string = str::from_utf8(&weather[..]).unwrap();
-        DISPLAYS.panorama(string, false).await;
-        for d in &*DISPLAYS {
-            d.pulse().await;
+async fn play_content(
+    content: &Content,
+    duration: Duration,
+) -> Result<(), Error> {
+    match content {
+        Content::Text(text) => DISPLAYS.panorama(text).await,
+        Content::Clock => clock().await?,
+        Content::Weather => weather(duration).await?,
+        Content::Image(bitmap) => {
+            let panels = graphics::split_bitmap(bitmap);
+            DISPLAYS.hold(&panels.each_ref(), duration).await;
         }
+        Content::Animation(Animation::Pulse) => {
+            for d in &*DISPLAYS {
+                d.pulse().await;
+            }
+        }
+        Content::Animation(Animation::Alert) => DISPLAYS.alert().await,
     }
+    Ok(())
+}
+
+async fn play(slot: &Slot) {
+    let duration = Duration::from_secs(slot.duration_secs.into());
+    // everything shown was checked on the way in, so this shouldn't happen
+    if play_content(&slot.content, duration).await.is_err() {
+        status::record_error("show");
+    }
+}
+
+async fn show(message: &Message) {
+    if message.effect == Effect::Alert {
+        DISPLAYS.alert().await;
+    }
+    play(&Slot::new(message.content.clone())).await;
 }
 
 #[embassy_executor::task]
 async fn animate() {
     Timer::after_secs(3).await;
     loop {
-        DISPLAYS.panorama("AKIHABARA", false).await;
-        for d in &*DISPLAYS {
-            d.pulse().await;
+        liveness::check_in(Task::Animate);
+        // a live stream has the displays to itself until it stops
+        if ddp::is_live() {
+            liveness::pause(Task::Animate);
+            ddp::wait_live(false).await;
+            continue;
+        }
+
+        // queued messages go before the playlist. anything but an urgent
+        // message gets cut short when an urgent one comes in, and anything
+        // at all when a stream starts
+        let message = QUEUE.lock().await.next(Instant::now().as_secs());
+        if let Some(message) = message {
+            status::set_showing(Showing::Message(message.id));
+            let shown = if message.is_urgent() {
+                let live = ddp::wait_live(true);
+                matches!(select(show(&message), live).await, Either::First(_))
+            } else {
+                let cut_in = select(urgent_queued(), ddp::wait_live(true));
+                matches!(
+                    select(show(&message), cut_in).await,
+                    Either::First(_)
+                )
+            };
+            // one that got cut short waits for its turn again
+            if shown {
+                QUEUE.lock().await.shown(message.id);
+                config::mark_dirty(Key::Messages);
+            }
+            continue;
+        }
+
+        let minute =
+            clock::local_now().map(|t| t.hour as u16 * 60 + t.minute as u16);
+        let slot = PLAYLIST.lock().await.next(minute);
+        match slot {
+            Some(slot) => {
+                status::set_showing(Showing::Playlist(slot.content.kind()));
+                select3(play(&slot), urgent_queued(), ddp::wait_live(true))
+                    .await;
+            }
+            // nothing to show right now
+            None => {
+                status::set_showing(Showing::Idle);
+                select3(
+                    Timer::after_secs(1),
+                    QUEUED.wait(),
+                    ddp::wait_live(true),
+                )
+                .await;
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn persist(mut store: Store<StoreFlash>) {
+    loop {
+        config::CHANGED.wait().await;
+        let reboot = config::REBOOT.load(Ordering::Relaxed);
+        if !reboot {
+            Timer::after(SAVE_DELAY).await;
+        }
+        // on failure the changes stay dirty, and go out with the next save
+        if config::save(&mut store).await.is_err() {
+            status::record_error("flash-save");
+        }
+        if reboot {
+            // give the reply to the reboot command a moment to go out
+            Timer::after_millis(200).await;
+            cortex_m::peripheral::SCB::sys_reset();
         }
-        clock().await;
-        weather().await;
     }
 }
 
 #[embassy_executor::task]
 async fn render_displays() {
     let mut ticker = Ticker::every(Duration::from_micros(500));
+    let mut pass = 0;
     loop {
-        for d in &*DISPLAYS {
-            d.render().await;
+        // skipping a pass leaves the displays cleared, so they look dimmer
+        if pass == 0 {
+            liveness::check_in(Task::Render);
         }
+        if pass < brightness() {
+            let start = Instant::now();
+            for d in &*DISPLAYS {
+                d.render().await;
+            }
+            status::record_render(start.elapsed().as_micros() as u32);
+        }
+        pass = (pass + 1) % MAX_BRIGHTNESS;
         ticker.next().await;
     }
 }
@@ -78,6 +261,7 @@ async fn render_displays() {
 async fn blinky(mut led: Output<'static, AnyPin>) -> ! {
     let delay = Duration::from_secs(1);
     loop {
+        liveness::check_in(Task::Blinky);
         led.set_high();
         Timer::after(delay).await;
         led.set_low();
@@ -88,6 +272,28 @@ async fn blinky(mut led: Output<'static, AnyPin>) -> ! {
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
+    clock::set_config(CLOCK_CONFIG);
+    *PLAYLIST.lock().await = Playlist::with_defaults();
+
+    // whatever was saved replaces the defaults above
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash: &'static SharedFlash =
+        FLASH.init(Mutex::new(Flash::new(p.FLASH, p.DMA_CH1)));
+    let partition = Partition::new(flash, STORE_OFFSET, storage::STORE_SIZE);
+    match Store::mount(partition, 0).await {
+        Ok(mut store) => {
+            if config::load(&mut store).await.is_err() {
+                status::record_error("flash-load");
+            }
+            let _ = spawner.spawn(persist(store));
+        }
+        Err(_) => status::record_error("flash-mount"),
+    }
+    // a crash from before the reset replaces the one in flash
+    if let Some(crash) = crash::recover() {
+        *LAST_CRASH.lock().await = Some(crash);
+        config::mark_dirty(Key::Crash);
+    }
 
     {
         let display0 = DotMatrixLed {
@@ -99,7 +305,6 @@ async fn main(spawner: Spawner) {
                 srclr: Line::new_cathode(AnyPin::from(p.PIN_5)),
             },
             graphic: graphics::LETTER_A,
-            overridden: false,
         };
 
         let display1 = DotMatrixLed {
@@ -111,7 +316,6 @@ async fn main(spawner: Spawner) {
                 srclr: Line::new_cathode(AnyPin::from(p.PIN_9)),
             },
             graphic: graphics::LETTER_B,
-            overridden: false,
         };
 
         let display2 = DotMatrixLed {
@@ -123,7 +327,6 @@ async fn main(spawner: Spawner) {
                 srclr: Line::new_cathode(AnyPin::from(p.PIN_13)),
             },
             graphic: graphics::LETTER_C,
-            overridden: false,
         };
 
         let display3 = DotMatrixLed {
@@ -135,7 +338,6 @@ async fn main(spawner: Spawner) {
                 srclr: Line::new_cathode(AnyPin::from(p.PIN_18)),
             },
             graphic: graphics::LETTER_D,
-            overridden: false,
         };
 
         *(DISPLAYS[0].0.lock().await) = Some(display0);
@@ -148,6 +350,11 @@ async fn main(spawner: Spawner) {
     let _ = spawner.spawn(animate());
     let led = Output::new(AnyPin::from(p.PIN_14), Level::Low);
     let _ = spawner.spawn(blinky(led));
+    // feeds the watchdog while the tasks above keep checking in
+    let _ = spawner.spawn(liveness::watch(Watchdog::new(p.WATCHDOG)));
+
+    *USB_DRIVER.lock().await = Some(Driver::new(p.USB, serialdrv::Irqs));
+    let _ = spawner.spawn(serialdrv::setup_serial());
 
     {
         // network code
@@ -165,13 +372,49 @@ async fn main(spawner: Spawner) {
             p.DMA_CH0,
         );
 
+        // a stored network config wins over the compiled-in one
+        let mut network = config::network().await;
+        if let Some(network) = network.as_mut().filter(|n| n.id.is_empty()) {
+            // all zeros if it can't be read, which still makes an id
+            let mut unique_id = [0; 8];
+            let _ = flash.lock().await.blocking_unique_id(&mut unique_id);
+            network.id = config::chip_id(&unique_id);
+        }
+        static NETWORK_CONFIG: StaticCell<Option<NetworkConfig>> =
+            StaticCell::new();
+        let network: &'static _ = NETWORK_CONFIG.init(network);
+        // a fresh update has to get back online to be kept
+        let _ = spawner.spawn(ota::confirm(flash, network.is_some()));
+        let Some(network) = network else {
+            // nothing to join. usb serial still works for setting it up
+            if let Ok(mut message) = Message::text(SETUP_MESSAGE, NORMAL) {
+                message.remaining = 3;
+                message.expires_at = Some(60 * 60);
+                // every boot without wifi queues it again, so there's no
+                // need to save it
+                message.saved = false;
+                let _ = QUEUE.lock().await.push(message, 0);
+            }
+            return;
+        };
         let wifi_config = WifiConfiguration {
-            wifi_ssid: WIFI_NETWORK,
-            wifi_password: Some(WIFI_PASSWORD),
-            ipv4: Some(StaticConfigV4 {
-                address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 1, 5), 24),
-                gateway: Some(Ipv4Address::new(192, 168, 0, 1)),
-                dns_servers: Vec::new(),
+            wifi_ssid: &network.ssid,
+            wifi_password: match network.password.as_str() {
+                "" => None,
+                password => Some(password),
+            },
+            ipv4: network.ipv4.map(|ipv4| {
+                let [a, b, c, d] = ipv4.address;
+                StaticConfigV4 {
+                    address: Ipv4Cidr::new(
+                        Ipv4Address::new(a, b, c, d),
+                        ipv4.prefix,
+                    ),
+                    gateway: ipv4
+                        .gateway
+                        .map(|[a, b, c, d]| Ipv4Address::new(a, b, c, d)),
+                    dns_servers: Vec::new(),
+                }
             }),
             ipv6: None,
         };
@@ -179,6 +422,29 @@ async fn main(spawner: Spawner) {
         let (ctrl, stack) =
             configure_network(&spawner, pwr, spi, wifi_config).await;
 
-        tcpserver::listen(stack, ctrl).await;
+        let _ = spawner.spawn(supervisor::supervise(
+            stack,
+            ctrl,
+            SupervisorConfig {
+                network,
+                show_outages: SHOW_OUTAGES,
+            },
+        ));
+        let _ = spawner.spawn(httpserver::serve(stack, flash));
+        let _ = spawner.spawn(ddp::listen(stack));
+        let _ = spawner.spawn(mdns::respond(stack, &network.id));
+        let _ = spawner.spawn(sntp::sync(stack, NTP_SERVER));
+        if let Some(broker) = network.mqtt {
+            let [a, b, c, d] = broker.address;
+            let address = IpAddress::v4(a, b, c, d);
+            let _ = spawner.spawn(mqtt::run(
+                stack,
+                MqttConfig {
+                    broker: IpEndpoint::new(address, broker.port),
+                    device_id: &network.id,
+                },
+            ));
+        }
+        tcpserver::listen(stack).await;
     }
 }
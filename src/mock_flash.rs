@@ -0,0 +1,168 @@
+//! nor flash in memory, for host tests. like the real thing, erased bytes
+//! are 0xff and writes can only clear bits. it can also lose power partway
+//! through a write, to see what the store makes of what's left
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::vec;
+use std::vec::Vec;
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    OutOfBounds,
+    NotAligned,
+    /// the write stopped halfway
+    PowerLoss,
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+struct State {
+    bytes: Vec<u8>,
+    /// how many more writes go through before the power goes
+    writes_left: Option<usize>,
+    writes: usize,
+    erases: Vec<usize>,
+}
+
+/// clones share the same flash, so a store can be mounted again on it
+#[derive(Clone)]
+pub struct MockFlash(Rc<RefCell<State>>);
+
+impl MockFlash {
+    pub const ERASE_SIZE: usize = 4096;
+
+    /// `size` bytes, all erased
+    pub fn new(size: usize) -> Self {
+        Self(Rc::new(RefCell::new(State {
+            bytes: vec![0xff; size],
+            writes_left: None,
+            writes: 0,
+            erases: vec![0; size / Self::ERASE_SIZE],
+        })))
+    }
+
+    /// a flash of its own, with the same contents
+    pub fn copy(&self) -> Self {
+        let copy = Self::new(self.0.borrow().bytes.len());
+        copy.0.borrow_mut().bytes = self.0.borrow().bytes.clone();
+        copy
+    }
+
+    /// the next `n` writes go through, and the one after only half does
+    pub fn lose_power_after(&self, n: usize) {
+        self.0.borrow_mut().writes_left = Some(n);
+    }
+
+    /// flips the bits in `mask` at `addr`, like a worn out cell would
+    pub fn corrupt(&self, addr: u32, mask: u8) {
+        self.0.borrow_mut().bytes[addr as usize] ^= mask;
+    }
+
+    /// how many writes have gone through so far
+    pub fn writes(&self) -> usize {
+        self.0.borrow().writes
+    }
+
+    /// how many times each erase block has been erased
+    pub fn erases(&self) -> Vec<usize> {
+        self.0.borrow().erases.clone()
+    }
+
+    fn check(
+        &self,
+        offset: u32,
+        len: usize,
+        align: usize,
+    ) -> Result<(), Error> {
+        let offset = offset as usize;
+        if offset + len > self.0.borrow().bytes.len() {
+            return Err(Error::OutOfBounds);
+        }
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(Error::NotAligned);
+        }
+        Ok(())
+    }
+}
+
+impl ErrorType for MockFlash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(
+        &mut self,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> Result<(), Error> {
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        let start = offset as usize;
+        let end = start + bytes.len();
+        bytes.copy_from_slice(&self.0.borrow().bytes[start..end]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.borrow().bytes.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = Self::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let len = to.checked_sub(from).ok_or(Error::OutOfBounds)? as usize;
+        self.check(from, len, Self::ERASE_SIZE)?;
+        let mut state = self.0.borrow_mut();
+        let (from, to) = (from as usize, to as usize);
+        state.bytes[from..to].fill(0xff);
+        for block in from / Self::ERASE_SIZE..to / Self::ERASE_SIZE {
+            state.erases[block] += 1;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        let mut state = self.0.borrow_mut();
+        let (len, result) = match state.writes_left {
+            Some(0) => {
+                state.writes_left = None;
+                let half = bytes.len() / 2;
+                (half - half % Self::WRITE_SIZE, Err(Error::PowerLoss))
+            }
+            left => {
+                state.writes_left = left.map(|n| n - 1);
+                state.writes += 1;
+                (bytes.len(), Ok(()))
+            }
+        };
+        let start = offset as usize;
+        for (cell, &b) in state.bytes[start..].iter_mut().zip(&bytes[..len]) {
+            assert_eq!(
+                *cell & b,
+                b,
+                "writing over something that wasn't erased at {:#x}",
+                start
+            );
+            *cell &= b;
+        }
+        result
+    }
+}
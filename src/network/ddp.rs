@@ -0,0 +1,531 @@
+//! live frames over udp, in the distributed display protocol (ddp) that
+//! xlights, wled and friends speak. the panels are one 32x8 strip of
+//! pixels, row by row from the top left. a pixel is lit when it's bright
+//! enough. while frames keep coming the playlist and the message queue
+//! wait; once they stop for `LIVE_TIMEOUT_MS`, the playlist picks up again
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "firmware")]
+use cyw43::NetDriver;
+#[cfg(feature = "firmware")]
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+#[cfg(feature = "firmware")]
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+#[cfg(feature = "firmware")]
+use embassy_time::{with_timeout, Duration, Instant};
+
+use crate::graphics::Bitmap;
+#[cfg(feature = "firmware")]
+use crate::DISPLAYS;
+
+pub const PORT: u16 = 4048;
+/// how long after the last frame the sign goes back to the playlist
+pub const LIVE_TIMEOUT_MS: u64 = 2500;
+
+const WIDTH: usize = 32;
+const HEIGHT: usize = 8;
+const PIXELS: usize = WIDTH * HEIGHT;
+/// the most any format needs, at 3 bytes per pixel
+const MAX_DATA_LEN: usize = PIXELS * 3;
+
+const HEADER_LEN: usize = 10;
+const TIMECODE_LEN: usize = 4;
+const VERSION: u8 = 1;
+
+const FLAG_TIMECODE: u8 = 0x10;
+const FLAG_STORAGE: u8 = 0x08;
+const FLAG_REPLY: u8 = 0x04;
+const FLAG_QUERY: u8 = 0x02;
+const FLAG_PUSH: u8 = 0x01;
+
+/// the default output device, and every device
+const DESTINATIONS: [u8; 2] = [1, 255];
+
+/// a pixel at least this bright is lit
+const THRESHOLD: u8 = 128;
+
+static LIVE: AtomicBool = AtomicBool::new(false);
+static LIVE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// 8 bit red, green, blue
+    Rgb8,
+    /// one 8 bit level per pixel
+    Gray8,
+}
+
+impl PixelFormat {
+    /// from the data type byte. senders often leave it at 0 or 1 for rgb
+    pub fn from_type(data_type: u8) -> Option<Self> {
+        match data_type {
+            0x00 | 0x01 | 0x0b => Some(Self::Rgb8),
+            0x23 => Some(Self::Gray8),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgb8 => 3,
+            Self::Gray8 => 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    /// 1 to 15, or 0 when the sender doesn't number its packets
+    pub sequence: u8,
+    /// the frame is complete, show it
+    pub push: bool,
+    pub format: PixelFormat,
+    /// where `data` goes, in bytes from the start of the frame
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// a data packet for this sign. queries, replies and anything meant for
+    /// another device are ignored
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let header = buf.get(..HEADER_LEN)?;
+        let flags = header[0];
+        if flags >> 6 != VERSION
+            || flags & (FLAG_QUERY | FLAG_REPLY | FLAG_STORAGE) != 0
+            || !DESTINATIONS.contains(&header[3])
+        {
+            return None;
+        }
+        let format = PixelFormat::from_type(header[2])?;
+        let offset = u32::from_be_bytes(header[4..8].try_into().ok()?);
+        let len = u16::from_be_bytes(header[8..10].try_into().ok()?);
+
+        let start = match flags & FLAG_TIMECODE {
+            0 => HEADER_LEN,
+            _ => HEADER_LEN + TIMECODE_LEN,
+        };
+        let data = buf.get(start..start + len as usize)?;
+
+        Some(Self {
+            sequence: header[1] & 0x0f,
+            push: flags & FLAG_PUSH != 0,
+            format,
+            offset: offset as usize,
+            data,
+        })
+    }
+}
+
+/// whether `sequence` comes at or before `last`, going by the 4 bit
+/// sequence numbers wrapping from 15 back to 1
+fn is_stale(last: u8, sequence: u8) -> bool {
+    let behind = (last + 15 - sequence) % 15;
+    behind < 7
+}
+
+fn luma(pixel: &[u8]) -> u8 {
+    match *pixel {
+        [r, g, b] => {
+            let luma = r as u16 * 77 + g as u16 * 150 + b as u16 * 29;
+            (luma >> 8) as u8
+        }
+        [level] => level,
+        _ => 0,
+    }
+}
+
+/// puts frames together from packets. time only comes in through the
+/// `now_ms` arguments
+pub struct Receiver {
+    data: [u8; MAX_DATA_LEN],
+    format: PixelFormat,
+    last_sequence: Option<u8>,
+    last_frame_ms: Option<u64>,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; MAX_DATA_LEN],
+            format: PixelFormat::Rgb8,
+            last_sequence: None,
+            last_frame_ms: None,
+        }
+    }
+
+    /// takes in a packet, and returns the frame when it's complete.
+    /// duplicated and out of order packets are dropped
+    pub fn receive(&mut self, packet: &Packet, now_ms: u64) -> Option<Bitmap> {
+        // a sender starting over may start from any sequence number
+        if !self.is_live(now_ms) {
+            self.last_sequence = None;
+        }
+        if packet.sequence != 0 {
+            if let Some(last) = self.last_sequence {
+                if is_stale(last, packet.sequence) {
+                    return None;
+                }
+            }
+            self.last_sequence = Some(packet.sequence);
+        }
+
+        if packet.format != self.format {
+            self.format = packet.format;
+            self.data = [0; MAX_DATA_LEN];
+        }
+        let len = PIXELS * self.format.bytes_per_pixel();
+        if packet.offset < len {
+            let data = &mut self.data[packet.offset..len];
+            let n = data.len().min(packet.data.len());
+            data[..n].copy_from_slice(&packet.data[..n]);
+        }
+
+        if !packet.push {
+            return None;
+        }
+        self.last_frame_ms = Some(now_ms);
+        Some(self.frame())
+    }
+
+    /// the frame as it is so far
+    pub fn frame(&self) -> Bitmap {
+        let size = self.format.bytes_per_pixel();
+        let mut bitmap = [0u8; 32];
+        for (i, pixel) in self.data.chunks(size).take(PIXELS).enumerate() {
+            if luma(pixel) >= THRESHOLD {
+                let (row, col) = (i / WIDTH, i % WIDTH);
+                bitmap[row * (WIDTH / 8) + col / 8] |= 0x80 >> (col % 8);
+            }
+        }
+        bitmap
+    }
+
+    /// whether a frame came in recently enough to keep the stream going
+    pub fn is_live(&self, now_ms: u64) -> bool {
+        self.last_frame_ms
+            .is_some_and(|t| now_ms.saturating_sub(t) < LIVE_TIMEOUT_MS)
+    }
+}
+
+/// whether frames are being streamed right now
+pub fn is_live() -> bool {
+    LIVE.load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "firmware")]
+fn set_live(live: bool) {
+    if LIVE.swap(live, Ordering::Relaxed) != live {
+        LIVE_CHANGED.signal(());
+    }
+}
+
+/// resolves once streaming starts, or once it stops
+pub async fn wait_live(live: bool) {
+    while is_live() != live {
+        LIVE_CHANGED.wait().await;
+    }
+}
+
+#[cfg(feature = "firmware")]
+#[embassy_executor::task]
+pub async fn listen(stack: &'static Stack<NetDriver<'static>>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 2048];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 16];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(PORT).is_err() {
+        return;
+    }
+
+    let timeout = Duration::from_millis(LIVE_TIMEOUT_MS);
+    let mut buf = [0; 1500];
+    let mut receiver = Receiver::new();
+    loop {
+        let received = with_timeout(timeout, socket.recv_from(&mut buf)).await;
+        let now = Instant::now().as_millis();
+        if let Ok(Ok((n, _))) = received {
+            let frame = Packet::parse(&buf[..n])
+                .and_then(|packet| receiver.receive(&packet, now));
+            if let Some(frame) = frame {
+                set_live(true);
+                DISPLAYS.draw_bitmap(&frame).await;
+            }
+        }
+        if !receiver.is_live(now) {
+            set_live(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// a ddp packet, for the cases the recordings below don't cover
+    fn packet(
+        flags: u8,
+        seq: u8,
+        ty: u8,
+        offset: u32,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut buf = vec![flags, seq, ty, 1];
+        buf.extend(offset.to_be_bytes());
+        buf.extend((data.len() as u16).to_be_bytes());
+        buf.extend(data);
+        buf
+    }
+
+    fn replay(receiver: &mut Receiver, packets: &[Vec<u8>]) -> Option<Bitmap> {
+        packets
+            .iter()
+            .filter_map(|buf| Packet::parse(buf))
+            .fold(None, |frame, p| receiver.receive(&p, 0).or(frame))
+    }
+
+    #[test]
+    fn a_frame_split_over_two_packets() {
+        // the top left pixel white, the bottom right one dim red
+        let mut first = [0u8; 384];
+        first[..3].copy_from_slice(&[255, 255, 255]);
+        let mut second = [0u8; 384];
+        second[381..].copy_from_slice(&[255, 0, 0]);
+
+        let mut receiver = Receiver::new();
+        let frame = replay(
+            &mut receiver,
+            &[
+                packet(0x40, 1, 0x0b, 0, &first),
+                packet(0x41, 2, 0x0b, 384, &second),
+            ],
+        );
+        let mut expected = [0u8; 32];
+        expected[0] = 0x80;
+        assert_eq!(frame, Some(expected));
+    }
+
+    #[test]
+    fn grayscale_with_a_timecode() {
+        let mut buf = packet(0x51, 0, 0x23, 0, &[]);
+        buf.extend([0, 0, 0, 0]); // timecode
+        buf.extend([200; 8]);
+        buf[9] = 8;
+
+        let packet = Packet::parse(&buf).unwrap();
+        assert_eq!(packet.format, PixelFormat::Gray8);
+        assert_eq!(packet.data, &[200; 8]);
+
+        let frame = Receiver::new().receive(&packet, 0).unwrap();
+        assert_eq!(frame[0], 0xff);
+        assert!(frame[1..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn not_for_us() {
+        let data = [255; 3];
+        // queries, replies, storage, another device, version 2, a short
+        // packet and an unknown data type
+        assert_eq!(Packet::parse(&packet(0x43, 0, 1, 0, &data)), None);
+        assert_eq!(Packet::parse(&packet(0x45, 0, 1, 0, &data)), None);
+        assert_eq!(Packet::parse(&packet(0x49, 0, 1, 0, &data)), None);
+        let mut other = packet(0x41, 0, 1, 0, &data);
+        other[3] = 2;
+        assert_eq!(Packet::parse(&other), None);
+        assert_eq!(Packet::parse(&packet(0x81, 0, 1, 0, &data)), None);
+        let short = packet(0x41, 0, 1, 0, &data);
+        assert_eq!(Packet::parse(&short[..short.len() - 1]), None);
+        assert_eq!(Packet::parse(&packet(0x41, 0, 0x1b, 0, &data)), None);
+    }
+
+    #[test]
+    fn late_and_repeated_packets_are_dropped() {
+        let lit = [packet(0x41, 5, 1, 0, &[255; 3])];
+        let dark = [packet(0x41, 4, 1, 0, &[0; 3])];
+        let mut receiver = Receiver::new();
+        assert!(replay(&mut receiver, &lit).is_some());
+        assert_eq!(replay(&mut receiver, &dark), None);
+        assert_eq!(replay(&mut receiver, &lit), None);
+        assert_eq!(receiver.frame()[0], 0x80);
+
+        // 15 wraps around to 1
+        let mut receiver = Receiver::new();
+        let wrapped = [
+            packet(0x41, 15, 1, 0, &[255; 3]),
+            packet(0x41, 1, 1, 0, &[0; 3]),
+        ];
+        assert_eq!(replay(&mut receiver, &wrapped).unwrap()[0], 0);
+    }
+
+    #[test]
+    fn a_stream_stops_after_the_timeout() {
+        let buf = packet(0x41, 3, 1, 0, &[255; 3]);
+        let packet = Packet::parse(&buf).unwrap();
+        let mut receiver = Receiver::new();
+        assert!(!receiver.is_live(0));
+        receiver.receive(&packet, 1000);
+        assert!(receiver.is_live(1000 + LIVE_TIMEOUT_MS - 1));
+        assert!(!receiver.is_live(1000 + LIVE_TIMEOUT_MS));
+        // a sender starting over, with an older sequence number
+        let buf = self::packet(0x41, 2, 1, 0, &[255; 3]);
+        let restart = Packet::parse(&buf).unwrap();
+        assert!(receiver.receive(&restart, 10_000).is_some());
+    }
+
+    /// packets as xlights (a 32x8 rgb model, after its discovery query)
+    /// and wled (600 leds, two packets a frame) send them, rebuilt from
+    /// how each one frames ddp. each is the ms it arrives at, a 2 byte
+    /// length and the udp payload, all big endian
+    const XLIGHTS: &[u8] = include_bytes!("../../tests/ddp/xlights.bin");
+    const WLED: &[u8] = include_bytes!("../../tests/ddp/wled.bin");
+
+    fn recording(mut bytes: &[u8]) -> Vec<(u64, &[u8])> {
+        let mut packets = Vec::new();
+        while !bytes.is_empty() {
+            let ms = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+            let len = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+            packets.push((ms as u64, &bytes[6..6 + len]));
+            bytes = &bytes[6 + len..];
+        }
+        packets
+    }
+
+    /// every frame the recording puts on the displays, and when
+    fn play(recording: &[u8]) -> (Receiver, Vec<(u64, Bitmap)>) {
+        let mut receiver = Receiver::new();
+        let mut frames = Vec::new();
+        for (ms, buf) in self::recording(recording) {
+            let frame = Packet::parse(buf)
+                .and_then(|packet| receiver.receive(&packet, ms));
+            if let Some(frame) = frame {
+                frames.push((ms, frame));
+            }
+        }
+        (receiver, frames)
+    }
+
+    fn lit(frame: &Bitmap) -> u32 {
+        frame.iter().map(|b| b.count_ones()).sum()
+    }
+
+    #[test]
+    fn xlights_walks_a_bar_across() {
+        let packets = recording(XLIGHTS);
+        // the discovery query isn't a frame
+        assert_eq!(Packet::parse(packets[0].1), None);
+        let packet = Packet::parse(packets[1].1).unwrap();
+        assert_eq!(packet.format, PixelFormat::Rgb8);
+        assert_eq!((packet.sequence, packet.push), (1, true));
+        assert_eq!(packet.data.len(), PIXELS * 3);
+
+        let (receiver, frames) = play(XLIGHTS);
+        assert_eq!(frames.len(), 32);
+        for (column, (ms, frame)) in frames.iter().enumerate() {
+            assert_eq!(*ms, 50 + column as u64 * 50);
+            // the same column on every row, and the background stays dark
+            let mut expected = [0u8; 32];
+            for row in 0..HEIGHT {
+                expected[row * 4 + column / 8] = 0x80 >> (column % 8);
+            }
+            assert_eq!(*frame, expected, "frame {}", column);
+        }
+
+        let last = frames.last().unwrap().0;
+        assert!(receiver.is_live(last + LIVE_TIMEOUT_MS - 1));
+        assert!(!receiver.is_live(last + LIVE_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn wled_fills_up_past_the_end_of_the_sign() {
+        let packets = recording(WLED);
+        assert_eq!(packets.len(), 24);
+        let first = Packet::parse(packets[0].1).unwrap();
+        let second = Packet::parse(packets[1].1).unwrap();
+        assert_eq!(
+            (first.push, first.offset, first.data.len()),
+            (false, 0, 1440)
+        );
+        // the push comes with pixels the sign doesn't have
+        assert_eq!((second.push, second.offset), (true, 1440));
+
+        let (_, frames) = play(WLED);
+        // sequence numbers wrap from 15 to 1 halfway through, and no
+        // frame is lost to it
+        assert_eq!(frames.len(), 12);
+        for (i, (ms, frame)) in frames.iter().enumerate() {
+            assert_eq!(*ms, i as u64 * 40);
+            // orange is lit, dim blue isn't
+            let orange = ((i as u32 + 1) * 25).min(PIXELS as u32);
+            assert_eq!(lit(frame), orange, "frame {}", i);
+            let first_dark = orange as usize;
+            if first_dark < PIXELS {
+                let byte = frame[first_dark / 8];
+                assert_eq!(byte & 0x80 >> (first_dark % 8), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn recordings_repeated_change_nothing() {
+        // a sender retrying every packet, as some do over flaky wifi
+        for recording in [XLIGHTS, WLED] {
+            let (_, once) = play(recording);
+            let mut receiver = Receiver::new();
+            let mut twice = Vec::new();
+            for (ms, buf) in self::recording(recording) {
+                for _ in 0..2 {
+                    let frame = Packet::parse(buf)
+                        .and_then(|packet| receiver.receive(&packet, ms));
+                    if let Some(frame) = frame {
+                        twice.push((ms, frame));
+                    }
+                }
+            }
+            assert_eq!(once, twice);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn any_bytes_parse_or_not(buf in any::<Vec<u8>>()) {
+            let _ = Packet::parse(&buf);
+        }
+
+        #[test]
+        fn any_packet_can_be_received(
+            gray in any::<bool>(),
+            offset in 0usize..1024,
+            data in prop::collection::vec(any::<u8>(), 0..1024),
+        ) {
+            let format = match gray {
+                true => PixelFormat::Gray8,
+                false => PixelFormat::Rgb8,
+            };
+            let packet = Packet {
+                sequence: 0,
+                push: true,
+                format,
+                offset,
+                data: &data,
+            };
+            prop_assert!(Receiver::new().receive(&packet, 0).is_some());
+        }
+    }
+}
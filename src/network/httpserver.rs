@@ -0,0 +1,428 @@
+use core::fmt::Write as _;
+use core::str;
+
+use cyw43::NetDriver;
+use embassy_boot_rp::AlignedBuffer;
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_net::Stack;
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write;
+use heapless::String;
+
+use crate::auth;
+use crate::commands::{self, Effect};
+use crate::config;
+use crate::graphics;
+use crate::ota::{self, SharedFlash};
+use crate::queue::{Message, URGENT};
+use crate::status;
+use crate::{get_null_term_string, WeatherData, DATA, DISPLAYS};
+
+/// the control panel, gzipped by build.rs
+const INDEX_HTML_GZ: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz"));
+
+const PORT: u16 = 80;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// the parts of a request line and headers we care about
+pub struct Request {
+    pub method: Method,
+    pub path: String<64>,
+    pub query: String<64>,
+    pub content_length: usize,
+}
+
+impl Request {
+    /// `head` is everything up to (not including) the blank line
+    pub fn parse(head: &[u8]) -> Option<Self> {
+        let head = str::from_utf8(head).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next()?.split(' ');
+        let method = match request_line.next()? {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            _ => return None,
+        };
+        let target = request_line.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut content_length = 0;
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().ok()?;
+                }
+            }
+        }
+
+        Some(Self {
+            method,
+            path: String::try_from(path).ok()?,
+            query: String::try_from(query).ok()?,
+            content_length,
+        })
+    }
+
+    /// looks up `key` in the query string. values are not url-decoded
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query.split('&').find_map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (k == key).then_some(v)
+        })
+    }
+}
+
+/// finds the blank line that ends the headers
+fn find_header_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+#[embassy_executor::task]
+pub async fn serve(
+    stack: &'static Stack<NetDriver<'static>>,
+    flash: &'static SharedFlash,
+) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+    // the headers and the longest message
+    let mut buf = [0; 2048];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        if socket.accept(PORT).await.is_err() {
+            continue;
+        }
+
+        let _ = handle_connection(&mut socket, &mut buf, flash).await;
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+async fn handle_connection(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    flash: &'static SharedFlash,
+) -> Result<(), tcp::Error> {
+    // read until we have all the headers
+    let mut len = 0;
+    let header_end = loop {
+        if len == buf.len() {
+            return respond(socket, "413 Payload Too Large", "", b"").await;
+        }
+        let n = socket.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Ok(()); // eof
+        }
+        len += n;
+        if let Some(i) = find_header_end(&buf[..len]) {
+            break i;
+        }
+    };
+
+    let request = match Request::parse(&buf[..header_end]) {
+        Some(r) => r,
+        None => return respond(socket, "400 Bad Request", "", b"").await,
+    };
+
+    // there's no way to give a key here, so once there are keys the
+    // panel can only look, and that goes for firmware too
+    if request.method == Method::Post && !auth::open().await {
+        return respond_error(socket, crate::Error::Denied).await;
+    }
+
+    let body_start = header_end + 4;
+    // firmware is far bigger than `buf`, so it's written out as it comes
+    if (request.method, request.path.as_str())
+        == (Method::Post, "/api/firmware")
+    {
+        let received = &buf[body_start..len];
+        return update_firmware(socket, &request, received, flash).await;
+    }
+
+    // then the rest of the body, if any
+    let body_end = body_start + request.content_length;
+    if body_end > buf.len() {
+        return respond(socket, "413 Payload Too Large", "", b"").await;
+    }
+    while len < body_end {
+        let n = socket.read(&mut buf[len..body_end]).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        len += n;
+    }
+    let body = match str::from_utf8(&buf[body_start..body_end]) {
+        Ok(s) => s.trim(),
+        Err(_) => return respond(socket, "400 Bad Request", "", b"").await,
+    };
+
+    route(socket, &request, body).await
+}
+
+async fn route(
+    socket: &mut TcpSocket<'_>,
+    request: &Request,
+    body: &str,
+) -> Result<(), tcp::Error> {
+    match (request.method, request.path.as_str()) {
+        (Method::Get, "/") | (Method::Get, "/index.html") => {
+            respond_gzip(socket, "text/html; charset=utf-8", INDEX_HTML_GZ)
+                .await
+        }
+        (Method::Get, "/api/state") => {
+            let state = state_json().await;
+            respond(socket, "200 OK", "application/json", state.as_bytes())
+                .await
+        }
+        (Method::Get, "/api/status") => {
+            let mut report: String<{ status::MAX_REPORT_LEN }> = String::new();
+            match status::report(&mut report).await {
+                Ok(()) => {
+                    let report = report.as_bytes();
+                    respond(socket, "200 OK", "text/plain", report).await
+                }
+                // better than half a report
+                Err(_) => {
+                    respond(socket, "500 Internal Server Error", "", b"").await
+                }
+            }
+        }
+        (Method::Post, "/api/message") => {
+            let effect = match request.query_param("effect") {
+                None => Effect::Alert,
+                Some(name) => match Effect::from_name(name) {
+                    Some(e) => e,
+                    None => {
+                        return respond(socket, "400 Bad Request", "", b"")
+                            .await
+                    }
+                },
+            };
+            // optional, defaults to showing the message right away
+            let priority = request.query_param("priority").map(str::parse);
+            let ttl = request.query_param("ttl").map(str::parse::<u64>);
+            let mut message = match (Message::text(body, URGENT), priority) {
+                (Ok(m), None) => m,
+                (Ok(m), Some(Ok(p))) => Message { priority: p, ..m },
+                (Err(e), _) => return respond_error(socket, e).await,
+                (Ok(_), Some(Err(_))) => {
+                    return respond_error(socket, crate::Error::Malformed)
+                        .await
+                }
+            };
+            message.effect = effect;
+            match ttl {
+                None => {}
+                Some(Ok(t)) => {
+                    message.expires_at = Some(Instant::now().as_secs() + t)
+                }
+                Some(Err(_)) => {
+                    return respond(socket, "400 Bad Request", "", b"").await
+                }
+            }
+            match commands::enqueue(message).await {
+                Ok(_) => respond(socket, "202 Accepted", "", b"").await,
+                Err(e) => respond_error(socket, e).await,
+            }
+        }
+        (Method::Post, "/api/image") => {
+            match graphics::bitmap_from_hex(body) {
+                Some(bitmap) => match commands::draw_image(&bitmap).await {
+                    Ok(_) => respond(socket, "202 Accepted", "", b"").await,
+                    Err(e) => respond_error(socket, e).await,
+                },
+                None => respond(socket, "400 Bad Request", "", b"").await,
+            }
+        }
+        (Method::Post, "/api/clock") => {
+            match commands::set_clock(body).await {
+                Ok(()) => respond(socket, "204 No Content", "", b"").await,
+                Err(e) => respond_error(socket, e).await,
+            }
+        }
+        (Method::Post, "/api/weather") => {
+            match commands::set_weather(body).await {
+                Ok(()) => respond(socket, "204 No Content", "", b"").await,
+                Err(e) => respond_error(socket, e).await,
+            }
+        }
+        _ => respond(socket, "404 Not Found", "", b"").await,
+    }
+}
+
+/// the body is the image, then its 64 byte signature. on success the sign
+/// restarts into the update
+async fn update_firmware(
+    socket: &mut TcpSocket<'_>,
+    request: &Request,
+    received: &[u8],
+    flash: &'static SharedFlash,
+) -> Result<(), tcp::Error> {
+    let Some(image_len) =
+        request.content_length.checked_sub(ota::SIGNATURE_LEN)
+    else {
+        return respond(socket, "400 Bad Request", "", b"").await;
+    };
+    if received.len() > request.content_length {
+        return respond(socket, "400 Bad Request", "", b"").await;
+    }
+
+    let mut state = AlignedBuffer([0; 1]);
+    let result = async {
+        let mut update = ota::Update::begin(flash, &mut state, image_len)
+            .await
+            .map_err(Some)?;
+        update.write(received).await.map_err(Some)?;
+        let mut left = request.content_length - received.len();
+        let mut buf = [0; 1024];
+        while left > 0 {
+            let n = left.min(buf.len());
+            let n = match socket.read(&mut buf[..n]).await {
+                Ok(0) | Err(_) => return Err(None),
+                Ok(n) => n,
+            };
+            update.write(&buf[..n]).await.map_err(Some)?;
+            left -= n;
+        }
+        update.finish().await.map_err(Some)
+    }
+    .await;
+
+    let code = match result {
+        Ok(()) => "202 Accepted",
+        // the connection is gone
+        Err(None) => return Ok(()),
+        Err(Some(ota::Error::Disabled)) => "501 Not Implemented",
+        Err(Some(ota::Error::OnTrial)) => "409 Conflict",
+        Err(Some(ota::Error::TooBig)) => "413 Payload Too Large",
+        Err(Some(ota::Error::BadSignature)) => "403 Forbidden",
+        Err(Some(ota::Error::Old)) => "409 Conflict",
+        Err(Some(ota::Error::Length)) => "400 Bad Request",
+        Err(Some(ota::Error::Flash)) => "500 Internal Server Error",
+    };
+    if let Err(Some(_)) = result {
+        status::record_error("ota-update");
+    }
+    let sent = respond(socket, code, "", b"").await;
+    if result.is_ok() {
+        config::reboot();
+    }
+    sent
+}
+
+/// `{"frame":"<64 hex digits>","clock":"...","weather":"..."}`
+async fn state_json() -> String<512> {
+    let frame = DISPLAYS.snapshot().await;
+    let (clock, weather) = {
+        let data = DATA.lock().await;
+        (data.clock, data.weather)
+    };
+
+    let mut out = String::new();
+    let _ = out.push_str("{\"frame\":\"");
+    let _ = graphics::write_hex(&mut out, &frame);
+    let _ = out.push_str("\",\"clock\":\"");
+    if let Some(clock) = clock {
+        let _ = write_json_str(&mut out, get_null_term_string(&clock));
+    }
+    let _ = out.push_str("\",\"weather\":\"");
+    match weather {
+        Some(WeatherData::Text(weather)) => {
+            let _ = write_json_str(&mut out, get_null_term_string(&weather));
+        }
+        Some(WeatherData::Report(report)) => {
+            // the compact encoding never needs escaping
+            let _ = out.push('=');
+            let _ = report.encode(&mut out);
+        }
+        None => {}
+    }
+    let _ = out.push_str("\"}");
+    out
+}
+
+fn write_json_str(
+    out: &mut impl core::fmt::Write,
+    s: Result<&str, crate::Error>,
+) -> core::fmt::Result {
+    for c in s.unwrap_or("").chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), tcp::Error> {
+    write_response(socket, status, content_type, false, body).await
+}
+
+/// `error <code>: <what went wrong>`, under the closest http status
+async fn respond_error(
+    socket: &mut TcpSocket<'_>,
+    e: crate::Error,
+) -> Result<(), tcp::Error> {
+    use crate::Error as E;
+    let status = match e {
+        E::QueueFull | E::Full => "503 Service Unavailable",
+        E::Denied => "403 Forbidden",
+        E::UnknownCommand => "404 Not Found",
+        E::Driver | E::Network => "500 Internal Server Error",
+        _ => "400 Bad Request",
+    };
+    let mut body: String<64> = String::new();
+    let _ = write!(body, "error {}: {}", e.code(), e);
+    respond(socket, status, "text/plain", body.as_bytes()).await
+}
+
+async fn respond_gzip(
+    socket: &mut TcpSocket<'_>,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), tcp::Error> {
+    write_response(socket, "200 OK", content_type, true, body).await
+}
+
+async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    gzip: bool,
+    body: &[u8],
+) -> Result<(), tcp::Error> {
+    let mut head: String<192> = String::new();
+    let _ = write!(head, "HTTP/1.1 {}\r\n", status);
+    if !content_type.is_empty() {
+        let _ = write!(head, "Content-Type: {}\r\n", content_type);
+    }
+    if gzip {
+        let _ = head.push_str("Content-Encoding: gzip\r\n");
+    }
+    let _ = write!(
+        head,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.flush().await
+}
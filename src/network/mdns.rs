@@ -0,0 +1,838 @@
+//! a minimal mdns responder. answers for `<host>.local` and advertises the
+//! command port as `<host>._dotmatrix._tcp.local` over dns-sd
+
+use heapless::String;
+
+use crate::config::MAX_ID_LEN;
+
+#[cfg(feature = "firmware")]
+pub use firmware::respond;
+
+const MAX_NAME_LEN: usize = 255;
+/// `dotmatrix-` and the longest id there can be
+const MAX_HOST_LEN: usize = "dotmatrix-".len() + MAX_ID_LEN;
+
+const SERVICE: &str = "_dotmatrix._tcp.local";
+const SERVICES: &str = "_services._dns-sd._udp.local";
+/// the tcp command port
+const SERVICE_PORT: u16 = 1234;
+
+// record types
+const A: u16 = 1;
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const SRV: u16 = 33;
+const ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// on a record: other records with this name are stale. on a question:
+/// the asker would like a unicast reply
+const TOP_BIT: u16 = 0x8000;
+
+/// how long names that point at this host may be cached
+const HOST_TTL: u32 = 120;
+/// how long everything else may be cached
+const SERVICE_TTL: u32 = 4500;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// the output buffer is too small
+    Buffer,
+    Malformed,
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(Error::Buffer);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u16(&mut self, v: u16) -> Result<(), Error> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> Result<(), Error> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    /// dotted, uncompressed
+    fn name(&mut self, name: &str) -> Result<(), Error> {
+        for label in name.split('.') {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// type, class, ttl, then room for the rdata length. returns where
+    /// the length goes, for `end_record`
+    fn record(
+        &mut self,
+        name: &str,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+    ) -> Result<usize, Error> {
+        self.name(name)?;
+        self.u16(rtype)?;
+        self.u16(class)?;
+        self.u32(ttl)?;
+        let at = self.len;
+        self.u16(0)?;
+        Ok(at)
+    }
+
+    fn end_record(&mut self, at: usize) {
+        let len = (self.len - at - 2) as u16;
+        self.buf[at..at + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn u16_at(packet: &[u8], at: usize) -> Result<u16, Error> {
+    match packet.get(at..at + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(Error::Malformed),
+    }
+}
+
+/// reads a possibly compressed name at `at` into `out`, dotted and
+/// lowercased. returns where the name ends in the packet
+fn read_name(
+    packet: &[u8],
+    mut at: usize,
+    out: &mut String<MAX_NAME_LEN>,
+) -> Result<usize, Error> {
+    out.clear();
+    let mut end = None;
+    // every pointer has to go backwards, so this can't loop forever
+    let mut limit = at;
+    loop {
+        let len = *packet.get(at).ok_or(Error::Malformed)? as usize;
+        match len {
+            0 => return Ok(end.unwrap_or(at + 1)),
+            0xc0.. => {
+                let pointer = u16_at(packet, at)? as usize & 0x3fff;
+                if pointer >= limit {
+                    return Err(Error::Malformed);
+                }
+                end.get_or_insert(at + 2);
+                limit = pointer;
+                at = pointer;
+            }
+            1..=63 => {
+                let label = packet
+                    .get(at + 1..at + 1 + len)
+                    .ok_or(Error::Malformed)?;
+                if !out.is_empty() {
+                    out.push('.').map_err(|_| Error::Malformed)?;
+                }
+                for &b in label {
+                    let c = b.to_ascii_lowercase() as char;
+                    out.push(c).map_err(|_| Error::Malformed)?;
+                }
+                at += 1 + len;
+            }
+            _ => return Err(Error::Malformed),
+        }
+    }
+}
+
+/// which records to send
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+struct Answers {
+    a: bool,
+    /// `_dotmatrix._tcp` -> our instance
+    ptr: bool,
+    srv: bool,
+    txt: bool,
+    /// `_services._dns-sd._udp` -> `_dotmatrix._tcp`
+    services: bool,
+}
+
+impl Answers {
+    fn any(&self) -> bool {
+        self.a || self.ptr || self.srv || self.txt || self.services
+    }
+
+    fn or(self, other: Self) -> Self {
+        Self {
+            a: self.a || other.a,
+            ptr: self.ptr || other.ptr,
+            srv: self.srv || other.srv,
+            txt: self.txt || other.txt,
+            services: self.services || other.services,
+        }
+    }
+}
+
+/// what a reply copies from the query
+#[derive(Default)]
+struct Echo<'q> {
+    id: u16,
+    /// the number of questions in `raw`
+    count: u16,
+    raw: &'q [u8],
+}
+
+/// what's known about this device
+pub struct Responder<'a> {
+    /// e.g. `dotmatrix-hall`, without `.local`
+    pub host: &'a str,
+    pub device_id: &'a str,
+    pub ipv4: [u8; 4],
+}
+
+/// a reply to a query
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub len: usize,
+    /// the asker wants a unicast reply, or isn't a full mdns resolver
+    pub unicast: bool,
+}
+
+impl<'a> Responder<'a> {
+    fn host_name(&self) -> String<MAX_NAME_LEN> {
+        let mut name = String::new();
+        let _ = name.push_str(self.host);
+        let _ = name.push_str(".local");
+        name
+    }
+
+    fn instance_name(&self) -> String<MAX_NAME_LEN> {
+        let mut name = String::new();
+        let _ = name.push_str(self.host);
+        let _ = name.push('.');
+        let _ = name.push_str(SERVICE);
+        name
+    }
+
+    /// what a question for `name` and `qtype` should be answered with
+    fn answers_for(&self, name: &str, qtype: u16) -> Answers {
+        let is = |t| qtype == t || qtype == ANY;
+        let mut answers = Answers::default();
+        if name.eq_ignore_ascii_case(&self.host_name()) {
+            answers.a = is(A);
+        } else if name == SERVICE {
+            answers.ptr = is(PTR);
+        } else if name.eq_ignore_ascii_case(&self.instance_name()) {
+            answers.srv = is(SRV);
+            answers.txt = is(TXT);
+        } else if name == SERVICES {
+            answers.services = is(PTR);
+        }
+        answers
+    }
+
+    /// the reply to `query`, written to `out`. None if there's nothing
+    /// for us in it. `legacy` is for queries from plain dns resolvers,
+    /// which get a unicast reply that looks like a regular dns one
+    pub fn respond(
+        &self,
+        query: &[u8],
+        out: &mut [u8],
+        legacy: bool,
+    ) -> Result<Option<Reply>, Error> {
+        let id = u16_at(query, 0)?;
+        let flags = u16_at(query, 2)?;
+        let questions = u16_at(query, 4)?;
+        if flags & 0x8000 != 0 {
+            return Ok(None); // a response, not a query
+        }
+
+        let mut answers = Answers::default();
+        let mut unicast = legacy;
+        let mut name = String::new();
+        let mut at = 12;
+        for _ in 0..questions {
+            at = read_name(query, at, &mut name)?;
+            let qtype = u16_at(query, at)?;
+            let qclass = u16_at(query, at + 2)?;
+            at += 4;
+            if qclass & !TOP_BIT != CLASS_IN {
+                continue;
+            }
+            let these = self.answers_for(&name, qtype);
+            if these.any() && qclass & TOP_BIT != 0 {
+                unicast = true;
+            }
+            answers = answers.or(these);
+        }
+        if !answers.any() {
+            return Ok(None);
+        }
+
+        // legacy resolvers want their id and questions back
+        let len = if legacy {
+            let echoed = Echo {
+                id,
+                count: questions,
+                raw: &query[12..at],
+            };
+            self.write(out, echoed, answers, false)?
+        } else {
+            self.write(out, Echo::default(), answers, true)?
+        };
+        Ok(Some(Reply { len, unicast }))
+    }
+
+    /// an unsolicited response with everything, sent when we come up
+    pub fn announce(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let all = Answers {
+            a: true,
+            ptr: true,
+            srv: true,
+            txt: true,
+            services: false,
+        };
+        self.write(out, Echo::default(), all, true)
+    }
+
+    fn write(
+        &self,
+        out: &mut [u8],
+        echo: Echo,
+        answers: Answers,
+        flush: bool,
+    ) -> Result<usize, Error> {
+        // a pointer to our instance comes with everything needed to use it
+        let additional = if answers.ptr {
+            Answers {
+                a: !answers.a,
+                srv: !answers.srv,
+                txt: !answers.txt,
+                ..Answers::default()
+            }
+        } else {
+            Answers::default()
+        };
+        let count = |a: &Answers| {
+            [a.a, a.ptr, a.srv, a.txt, a.services]
+                .iter()
+                .filter(|&&x| x)
+                .count() as u16
+        };
+
+        let mut w = Writer { buf: out, len: 0 };
+        w.u16(echo.id)?;
+        w.u16(0x8400)?; // response, authoritative
+        w.u16(echo.count)?;
+        w.u16(count(&answers))?;
+        w.u16(0)?;
+        w.u16(count(&additional))?;
+        // at the same offset as in the query, so any compression
+        // pointers in it still work
+        w.bytes(echo.raw)?;
+        self.write_records(&mut w, answers, flush)?;
+        self.write_records(&mut w, additional, flush)?;
+        Ok(w.len)
+    }
+
+    fn write_records(
+        &self,
+        w: &mut Writer,
+        answers: Answers,
+        flush: bool,
+    ) -> Result<(), Error> {
+        // unique records tell caches to drop older copies
+        let unique = if flush { CLASS_IN | TOP_BIT } else { CLASS_IN };
+        let host = self.host_name();
+        let instance = self.instance_name();
+
+        if answers.a {
+            let at = w.record(&host, A, unique, HOST_TTL)?;
+            w.bytes(&self.ipv4)?;
+            w.end_record(at);
+        }
+        if answers.ptr {
+            let at = w.record(SERVICE, PTR, CLASS_IN, SERVICE_TTL)?;
+            w.name(&instance)?;
+            w.end_record(at);
+        }
+        if answers.srv {
+            let at = w.record(&instance, SRV, unique, HOST_TTL)?;
+            w.u16(0)?; // priority
+            w.u16(0)?; // weight
+            w.u16(SERVICE_PORT)?;
+            w.name(&host)?;
+            w.end_record(at);
+        }
+        if answers.txt {
+            let at = w.record(&instance, TXT, unique, SERVICE_TTL)?;
+            let id_len = "id=".len() + self.device_id.len();
+            w.bytes(&[id_len as u8])?;
+            w.bytes(b"id=")?;
+            w.bytes(self.device_id.as_bytes())?;
+            w.bytes(&[b"http=80".len() as u8])?;
+            w.bytes(b"http=80")?;
+            w.end_record(at);
+        }
+        if answers.services {
+            let at = w.record(SERVICES, PTR, CLASS_IN, SERVICE_TTL)?;
+            w.name(SERVICE)?;
+            w.end_record(at);
+        }
+        Ok(())
+    }
+}
+
+/// a sign that answered `browse`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Found {
+    /// e.g. `dotmatrix-hall`, without `.local`
+    pub host: String<MAX_NAME_LEN>,
+    pub device_id: String<MAX_NAME_LEN>,
+    pub port: u16,
+    pub ipv4: Option<[u8; 4]>,
+}
+
+/// a query for every sign on the network. sent from any port but 5353,
+/// each one answers it straight back
+pub fn browse(out: &mut [u8]) -> Result<usize, Error> {
+    let mut w = Writer { buf: out, len: 0 };
+    w.u16(0)?; // id
+    w.u16(0)?; // flags, a plain query
+    w.u16(1)?; // one question
+    w.u16(0)?;
+    w.u16(0)?;
+    w.u16(0)?;
+    w.name(SERVICE)?;
+    w.u16(PTR)?;
+    w.u16(CLASS_IN)?;
+    Ok(w.len)
+}
+
+/// the sign a reply to `browse` is about, or None if it isn't from one
+pub fn read_reply(packet: &[u8]) -> Result<Option<Found>, Error> {
+    let flags = u16_at(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        return Ok(None); // a query, not a response
+    }
+    let questions = u16_at(packet, 4)?;
+    let records = [6, 8, 10]
+        .iter()
+        .map(|&at| u16_at(packet, at).map(u32::from))
+        .sum::<Result<u32, Error>>()?;
+
+    let mut name = String::new();
+    let mut at = 12;
+    for _ in 0..questions {
+        at = read_name(packet, at, &mut name)? + 4;
+    }
+
+    let mut found = Found::default();
+    let mut target = String::new();
+    let mut addresses: heapless::Vec<_, 4> = heapless::Vec::new();
+    for _ in 0..records {
+        at = read_name(packet, at, &mut name)?;
+        let rtype = u16_at(packet, at)?;
+        let len = u16_at(packet, at + 8)? as usize;
+        let data = at + 10;
+        at = data + len;
+        let rdata = packet.get(data..at).ok_or(Error::Malformed)?;
+        match rtype {
+            SRV if name.ends_with(SERVICE) => {
+                found.port = u16_at(packet, data + 4)?;
+                read_name(packet, data + 6, &mut target)?;
+            }
+            TXT if name.ends_with(SERVICE) => {
+                let mut rest = rdata;
+                while let Some((&n, tail)) = rest.split_first() {
+                    let entry = tail.get(..n as usize).unwrap_or(tail);
+                    if let Some(id) = entry.strip_prefix(b"id=") {
+                        let id = core::str::from_utf8(id)
+                            .map_err(|_| Error::Malformed)?;
+                        found.device_id = String::try_from(id)
+                            .map_err(|_| Error::Malformed)?;
+                    }
+                    rest = tail.get(n as usize..).unwrap_or(&[]);
+                }
+            }
+            A => {
+                let ipv4 = rdata.try_into().map_err(|_| Error::Malformed)?;
+                let _ = addresses.push((name.clone(), ipv4));
+            }
+            _ => {}
+        }
+    }
+
+    let Some(host) = target.strip_suffix(".local") else {
+        return Ok(None);
+    };
+    found.host = String::try_from(host).map_err(|_| Error::Malformed)?;
+    found.ipv4 = addresses
+        .into_iter()
+        .find(|(name, _)| *name == target)
+        .map(|(_, ipv4)| ipv4);
+    Ok(Some(found))
+}
+
+/// `dotmatrix-<id>`
+pub fn host_name(device_id: &str) -> String<MAX_HOST_LEN> {
+    let mut host = String::new();
+    let _ = host.push_str("dotmatrix-");
+    let _ = host.push_str(device_id);
+    host
+}
+
+#[cfg(feature = "firmware")]
+mod firmware {
+    use cyw43::NetDriver;
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+    use embassy_time::{Duration, Timer};
+
+    use super::{host_name, Responder};
+
+    const PORT: u16 = 5353;
+    const GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+    const MAX_PACKET_LEN: usize = 512;
+    const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+    const ANNOUNCEMENTS: usize = 2;
+
+    #[embassy_executor::task]
+    pub async fn respond(
+        stack: &'static Stack<NetDriver<'static>>,
+        device_id: &'static str,
+    ) {
+        stack.wait_config_up().await;
+        let _ = stack.join_multicast_group(GROUP).await;
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0; MAX_PACKET_LEN * 2];
+        let mut tx_meta = [PacketMetadata::EMPTY; 4];
+        let mut tx_buffer = [0; MAX_PACKET_LEN * 2];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let _ = socket.bind(PORT);
+
+        let host = host_name(device_id);
+        let group = IpEndpoint::new(IpAddress::Ipv4(GROUP), PORT);
+        // the address can change when the dhcp lease does
+        let ipv4 = || match stack.config_v4() {
+            Some(config) => config.address.address().0,
+            None => [0; 4],
+        };
+
+        let mut out = [0; MAX_PACKET_LEN];
+        for _ in 0..ANNOUNCEMENTS {
+            let responder = Responder {
+                host: &host,
+                device_id,
+                ipv4: ipv4(),
+            };
+            if let Ok(len) = responder.announce(&mut out) {
+                let _ = socket.send_to(&out[..len], group).await;
+            }
+            Timer::after(ANNOUNCE_INTERVAL).await;
+        }
+
+        let mut buf = [0; MAX_PACKET_LEN];
+        loop {
+            let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            let responder = Responder {
+                host: &host,
+                device_id,
+                ipv4: ipv4(),
+            };
+            // queries from anything but port 5353 are plain dns resolvers
+            let legacy = from.port != PORT;
+            let reply = match responder.respond(&buf[..n], &mut out, legacy) {
+                Ok(Some(reply)) => reply,
+                _ => continue,
+            };
+            let to = if reply.unicast { from } else { group };
+            let _ = socket.send_to(&out[..reply.len], to).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGN: Responder = Responder {
+        host: "dotmatrix-7",
+        device_id: "7",
+        ipv4: [192, 168, 1, 5],
+    };
+
+    fn found() -> Found {
+        Found {
+            host: String::try_from("dotmatrix-7").unwrap(),
+            device_id: String::try_from("7").unwrap(),
+            port: SERVICE_PORT,
+            ipv4: Some([192, 168, 1, 5]),
+        }
+    }
+
+    #[test]
+    fn signs_answer_a_browse() {
+        let mut query = [0; 512];
+        let len = browse(&mut query).unwrap();
+        let mut out = [0; 512];
+        let reply = SIGN.respond(&query[..len], &mut out, true).unwrap();
+        let reply = reply.unwrap();
+        assert!(reply.unicast);
+        assert_eq!(read_reply(&out[..reply.len]), Ok(Some(found())));
+    }
+
+    #[test]
+    fn announcements_read_the_same() {
+        let mut out = [0; 512];
+        let len = SIGN.announce(&mut out).unwrap();
+        assert_eq!(read_reply(&out[..len]), Ok(Some(found())));
+    }
+
+    #[test]
+    fn queries_and_scraps_are_not_signs() {
+        let mut query = [0; 512];
+        let len = browse(&mut query).unwrap();
+        assert_eq!(read_reply(&query[..len]), Ok(None));
+        assert_eq!(read_reply(&[0x84]), Err(Error::Malformed));
+
+        let mut out = [0; 512];
+        let len = SIGN.announce(&mut out).unwrap();
+        for end in 0..len {
+            // cut short anywhere, it's never taken for a whole answer
+            assert_ne!(read_reply(&out[..end]), Ok(Some(found())));
+        }
+    }
+
+    /// a browse from avahi-browse, with a known answer for a sign it's
+    /// already seen. avahi asks from port 5353 and wants the reply
+    /// multicast
+    #[rustfmt::skip]
+    const AVAHI_BROWSE: &[u8] = &[
+        0x00, 0x00, 0x00, 0x00, // id, flags
+        0x00, 0x01, 0x00, 0x01, // 1 question, 1 answer
+        0x00, 0x00, 0x00, 0x00,
+        // _dotmatrix._tcp.local PTR IN
+        0x0a, b'_', b'd', b'o', b't', b'm', b'a', b't', b'r', b'i', b'x',
+        0x04, b'_', b't', b'c', b'p', 0x05, b'l', b'o', b'c', b'a', b'l',
+        0x00, 0x00, 0x0c, 0x00, 0x01,
+        // known answer: -> dotmatrix-3._dotmatrix._tcp.local, ttl 4500
+        0xc0, 0x0c, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94,
+        0x00, 0x0e, 0x0b, b'd', b'o', b't', b'm', b'a', b't', b'r', b'i',
+        b'x', b'-', b'3', 0xc0, 0x0c,
+    ];
+
+    /// what dns-sd -B and a resolve right after it put in one packet.
+    /// names after the first are compressed, and the first two ask for
+    /// a unicast reply
+    #[rustfmt::skip]
+    const DNS_SD_RESOLVE: &[u8] = &[
+        0x00, 0x00, 0x00, 0x00, // id, flags
+        0x00, 0x03, 0x00, 0x00, // 3 questions
+        0x00, 0x00, 0x00, 0x00,
+        // _dotmatrix._tcp.local PTR, QU
+        0x0a, b'_', b'd', b'o', b't', b'm', b'a', b't', b'r', b'i', b'x',
+        0x04, b'_', b't', b'c', b'p', 0x05, b'l', b'o', b'c', b'a', b'l',
+        0x00, 0x00, 0x0c, 0x80, 0x01,
+        // DotMatrix-7 + pointer to _dotmatrix._tcp.local, SRV, QU
+        0x0b, b'D', b'o', b't', b'M', b'a', b't', b'r', b'i', b'x', b'-',
+        b'7', 0xc0, 0x0c, 0x00, 0x21, 0x80, 0x01,
+        // dotmatrix-7 + pointer to local, A
+        0x0b, b'd', b'o', b't', b'm', b'a', b't', b'r', b'i', b'x', b'-',
+        b'7', 0xc0, 0x1c, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    /// `dig -p 5353 @224.0.0.251 dotmatrix-7.local`, a plain resolver
+    /// with an edns record on the end
+    #[rustfmt::skip]
+    const DIG: &[u8] = &[
+        0x5c, 0x3a, 0x01, 0x20, // id, rd + ad
+        0x00, 0x01, 0x00, 0x00, // 1 question, 1 additional
+        0x00, 0x00, 0x00, 0x01,
+        // dotmatrix-7.local A IN
+        0x0b, b'd', b'o', b't', b'm', b'a', b't', b'r', b'i', b'x', b'-',
+        b'7', 0x05, b'l', b'o', b'c', b'a', b'l', 0x00, 0x00, 0x01, 0x00,
+        0x01,
+        // OPT, udp size 1232
+        0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// `avahi-resolve -n printer.local`, nothing to do with us
+    #[rustfmt::skip]
+    const SOMEONE_ELSE: &[u8] = &[
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x07, b'p', b'r', b'i', b'n', b't', b'e', b'r', 0x05, b'l',
+        b'o', b'c', b'a', b'l', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    /// every record in a reply as name, type and class, and how many of
+    /// them are answers rather than additional
+    #[allow(clippy::type_complexity)]
+    fn records(
+        packet: &[u8],
+    ) -> (std::vec::Vec<(String<MAX_NAME_LEN>, u16, u16)>, usize) {
+        let questions = u16_at(packet, 4).unwrap();
+        let answers = u16_at(packet, 6).unwrap() as usize;
+        let additional = u16_at(packet, 10).unwrap() as usize;
+        let mut name = String::new();
+        let mut at = 12;
+        for _ in 0..questions {
+            at = read_name(packet, at, &mut name).unwrap() + 4;
+        }
+        let mut all = std::vec::Vec::new();
+        for _ in 0..answers + additional {
+            at = read_name(packet, at, &mut name).unwrap();
+            let rtype = u16_at(packet, at).unwrap();
+            let class = u16_at(packet, at + 2).unwrap();
+            at += 10 + u16_at(packet, at + 8).unwrap() as usize;
+            all.push((name.clone(), rtype, class));
+        }
+        assert_eq!(at, packet.len());
+        (all, answers)
+    }
+
+    fn record(name: &str, rtype: u16, class: u16) -> (String<255>, u16, u16) {
+        (String::try_from(name).unwrap(), rtype, class)
+    }
+
+    #[test]
+    fn avahi_gets_a_multicast_pointer_and_the_rest() {
+        let mut out = [0; 512];
+        let reply = SIGN.respond(AVAHI_BROWSE, &mut out, false).unwrap();
+        let reply = reply.unwrap();
+        assert!(!reply.unicast);
+        let out = &out[..reply.len];
+        assert_eq!(&out[..4], &[0, 0, 0x84, 0x00]);
+
+        let instance = "dotmatrix-7._dotmatrix._tcp.local";
+        let flush = CLASS_IN | TOP_BIT;
+        let (all, answers) = records(out);
+        assert_eq!(answers, 1);
+        assert_eq!(
+            all,
+            [
+                record(SERVICE, PTR, CLASS_IN),
+                record("dotmatrix-7.local", A, flush),
+                record(instance, SRV, flush),
+                record(instance, TXT, flush),
+            ]
+        );
+        assert_eq!(read_reply(out), Ok(Some(found())));
+    }
+
+    #[test]
+    fn compressed_questions_are_all_answered_unicast() {
+        let mut out = [0; 512];
+        let reply = SIGN.respond(DNS_SD_RESOLVE, &mut out, false).unwrap();
+        let reply = reply.unwrap();
+        assert!(reply.unicast);
+        let out = &out[..reply.len];
+
+        let instance = "dotmatrix-7._dotmatrix._tcp.local";
+        let flush = CLASS_IN | TOP_BIT;
+        let (all, answers) = records(out);
+        assert_eq!(answers, 3);
+        // the txt record isn't asked for, but comes with the pointer
+        assert_eq!(
+            all,
+            [
+                record("dotmatrix-7.local", A, flush),
+                record(SERVICE, PTR, CLASS_IN),
+                record(instance, SRV, flush),
+                record(instance, TXT, flush),
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_resolvers_get_their_question_back() {
+        let mut out = [0; 512];
+        let reply = SIGN.respond(DIG, &mut out, true).unwrap().unwrap();
+        assert!(reply.unicast);
+        let out = &out[..reply.len];
+        // same id, one question, one answer, nothing else
+        assert_eq!(&out[..12], &[0x5c, 0x3a, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&out[12..35], &DIG[12..35]);
+
+        let (all, _) = records(out);
+        // no cache flush bit, a plain resolver would take it for a class
+        assert_eq!(all, [record("dotmatrix-7.local", A, CLASS_IN)]);
+        assert_eq!(&out[out.len() - 4..], &[192, 168, 1, 5]);
+    }
+
+    #[test]
+    fn other_hosts_and_answers_are_left_alone() {
+        let mut out = [0; 512];
+        assert_eq!(SIGN.respond(SOMEONE_ELSE, &mut out, false), Ok(None));
+
+        // another sign's announcement
+        let other = Responder {
+            host: "dotmatrix-3",
+            device_id: "3",
+            ipv4: [192, 168, 1, 9],
+        };
+        let mut announcement = [0; 512];
+        let len = other.announce(&mut announcement).unwrap();
+        let announcement = &announcement[..len];
+        assert_eq!(SIGN.respond(announcement, &mut out, false), Ok(None));
+    }
+
+    #[test]
+    fn the_longest_id_is_announced_whole() {
+        let id = "a".repeat(MAX_ID_LEN);
+        let host = host_name(&id);
+        assert_eq!(host.len(), MAX_HOST_LEN);
+        assert!(host.ends_with(id.as_str()));
+
+        let sign = Responder {
+            host: &host,
+            device_id: &id,
+            ipv4: [192, 168, 1, 5],
+        };
+        let mut out = [0; 512];
+        let len = sign.announce(&mut out).unwrap();
+        let found = read_reply(&out[..len]).unwrap().unwrap();
+        assert_eq!(found.host.as_str(), host.as_str());
+        assert_eq!(found.device_id.as_str(), id);
+    }
+
+    #[test]
+    fn cut_short_queries_are_malformed() {
+        let mut out = [0; 512];
+        for query in [AVAHI_BROWSE, DNS_SD_RESOLVE, DIG] {
+            // anything cut inside the questions
+            let end = match query {
+                DIG => 35,
+                AVAHI_BROWSE => 39,
+                _ => query.len(),
+            };
+            for cut in 0..end {
+                let reply = SIGN.respond(&query[..cut], &mut out, false);
+                assert_eq!(reply.err(), Some(Error::Malformed), "{cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn pointers_that_loop_are_malformed() {
+        let mut query = DNS_SD_RESOLVE.to_vec();
+        // the second question's pointer now points at itself
+        query[51..53].copy_from_slice(&[0xc0, 51]);
+        let mut out = [0; 512];
+        assert_eq!(
+            SIGN.respond(&query, &mut out, false).err(),
+            Some(Error::Malformed)
+        );
+    }
+}
@@ -1 +1,10 @@
+pub mod ddp;
+#[cfg(feature = "firmware")]
+pub mod httpserver;
+pub mod mdns;
+pub mod mqtt;
+pub mod session;
+pub mod sntp;
+pub mod supervisor;
+#[cfg(feature = "firmware")]
 pub mod tcpserver;
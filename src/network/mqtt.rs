@@ -0,0 +1,629 @@
+//! a small mqtt 3.1.1 client. only qos 0 is used in both directions
+
+use core::fmt::Write as _;
+use core::str;
+
+#[cfg(feature = "firmware")]
+use cyw43::NetDriver;
+#[cfg(feature = "firmware")]
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp, IpEndpoint};
+#[cfg(feature = "firmware")]
+use embassy_net::{tcp::TcpSocket, Stack};
+#[cfg(feature = "firmware")]
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(feature = "firmware")]
+use embedded_io_async::Write;
+use heapless::String;
+
+#[cfg(feature = "firmware")]
+use crate::commands::{self, Effect};
+#[cfg(feature = "firmware")]
+use crate::{auth, status};
+
+#[cfg(feature = "firmware")]
+const KEEP_ALIVE_SECS: u16 = 60;
+/// ping a bit before the broker would give up on us
+#[cfg(feature = "firmware")]
+const PING_INTERVAL: Duration =
+    Duration::from_secs(KEEP_ALIVE_SECS as u64 / 2);
+#[cfg(feature = "firmware")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// topics under `dotmatrix/<id>/` that we subscribe to
+#[cfg(feature = "firmware")]
+const SUBSCRIPTIONS: [&str; 5] =
+    ["message", "clock", "weather", "brightness", "query"];
+/// the longest reply published on the status topic, which is the status
+/// report
+#[cfg(feature = "firmware")]
+const REPLY_LEN: usize = status::MAX_REPORT_LEN;
+
+// control packet types, already shifted into the high nibble
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82; // subscribe has reserved flags 0b0010
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/// room for the fixed header, which is at most 5 bytes
+const HEADER_ROOM: usize = 5;
+
+#[derive(Debug)]
+pub enum Error {
+    /// the output buffer is too small for the packet
+    Buffer,
+    /// the broker sent something we can't make sense of
+    Malformed,
+    /// the broker refused the connection with this return code
+    Refused(u8),
+    Network,
+    Closed,
+}
+
+impl From<tcp::Error> for Error {
+    fn from(_: tcp::Error) -> Self {
+        Self::Network
+    }
+}
+
+impl From<tcp::ConnectError> for Error {
+    fn from(_: tcp::ConnectError) -> Self {
+        Self::Network
+    }
+}
+
+pub struct MqttConfig {
+    pub broker: IpEndpoint,
+    /// used as the client id and in topic names
+    pub device_id: &'static str,
+}
+
+/// the last will, published by the broker if we drop off
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+pub enum Packet<'a> {
+    ConnAck {
+        return_code: u8,
+    },
+    SubAck,
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+    },
+    PingResp,
+    /// anything we don't need to act on
+    Other,
+}
+
+/// writes a packet body after `HEADER_ROOM`, leaving space for the fixed
+/// header which is only known once the body length is
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: HEADER_ROOM,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(Error::Buffer);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, v: u8) -> Result<(), Error> {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> Result<(), Error> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    /// length-prefixed, as used for strings and will payloads
+    fn prefixed(&mut self, data: &[u8]) -> Result<(), Error> {
+        let len = u16::try_from(data.len()).map_err(|_| Error::Buffer)?;
+        self.u16(len)?;
+        self.bytes(data)
+    }
+
+    /// prepends the fixed header and returns the packet length
+    fn finish(self, header: u8) -> Result<usize, Error> {
+        let body_len = self.len - HEADER_ROOM;
+        let mut fixed = [header, 0, 0, 0, 0];
+        let fixed_len =
+            1 + encode_remaining_length(body_len, &mut fixed[1..])?;
+        self.buf.copy_within(HEADER_ROOM..self.len, fixed_len);
+        self.buf[..fixed_len].copy_from_slice(&fixed[..fixed_len]);
+        Ok(fixed_len + body_len)
+    }
+}
+
+fn encode_remaining_length(
+    mut len: usize,
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    let mut i = 0;
+    loop {
+        if i == out.len() {
+            return Err(Error::Buffer);
+        }
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out[i] = byte;
+        i += 1;
+        if len == 0 {
+            return Ok(i);
+        }
+    }
+}
+
+/// returns the remaining length and how many bytes encoded it,
+/// or None if more bytes are needed
+fn decode_remaining_length(
+    buf: &[u8],
+) -> Result<Option<(usize, usize)>, Error> {
+    let mut len = 0;
+    for (i, &byte) in buf.iter().enumerate().take(4) {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((len, i + 1)));
+        }
+    }
+    if buf.len() >= 4 {
+        Err(Error::Malformed)
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn encode_connect(
+    buf: &mut [u8],
+    client_id: &str,
+    keep_alive_secs: u16,
+    will: Option<&Will>,
+) -> Result<usize, Error> {
+    let mut w = Writer::new(buf);
+    w.prefixed(b"MQTT")?;
+    w.u8(4)?; // protocol level 3.1.1
+
+    let mut flags = 0x02; // clean session
+    if let Some(will) = will {
+        flags |= 0x04;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    w.u8(flags)?;
+    w.u16(keep_alive_secs)?;
+
+    w.prefixed(client_id.as_bytes())?;
+    if let Some(will) = will {
+        w.prefixed(will.topic.as_bytes())?;
+        w.prefixed(will.payload)?;
+    }
+    w.finish(CONNECT)
+}
+
+pub fn encode_subscribe(
+    buf: &mut [u8],
+    packet_id: u16,
+    topics: &[&str],
+) -> Result<usize, Error> {
+    let mut w = Writer::new(buf);
+    w.u16(packet_id)?;
+    for topic in topics {
+        w.prefixed(topic.as_bytes())?;
+        w.u8(0)?; // qos 0
+    }
+    w.finish(SUBSCRIBE)
+}
+
+pub fn encode_publish(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> Result<usize, Error> {
+    let mut w = Writer::new(buf);
+    w.prefixed(topic.as_bytes())?;
+    w.bytes(payload)?;
+    w.finish(PUBLISH | retain as u8)
+}
+
+pub fn encode_pingreq(buf: &mut [u8]) -> Result<usize, Error> {
+    Writer::new(buf).finish(PINGREQ)
+}
+
+pub fn encode_disconnect(buf: &mut [u8]) -> Result<usize, Error> {
+    Writer::new(buf).finish(DISCONNECT)
+}
+
+/// decodes the first packet in `buf`. returns the packet and its total
+/// length, or None if `buf` doesn't hold a whole packet yet
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    let Some(&header) = buf.first() else {
+        return Ok(None);
+    };
+    let Some((body_len, len_len)) = decode_remaining_length(&buf[1..])? else {
+        return Ok(None);
+    };
+    let start = 1 + len_len;
+    let end = start + body_len;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    let body = &buf[start..end];
+
+    let packet = match header & 0xf0 {
+        CONNACK => {
+            if body.len() != 2 {
+                return Err(Error::Malformed);
+            }
+            Packet::ConnAck {
+                return_code: body[1],
+            }
+        }
+        PUBLISH => {
+            if body.len() < 2 {
+                return Err(Error::Malformed);
+            }
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let mut payload_start = 2 + topic_len;
+            if (header >> 1) & 0x3 != 0 {
+                payload_start += 2; // packet id, only present for qos > 0
+            }
+            if body.len() < payload_start {
+                return Err(Error::Malformed);
+            }
+            let topic = str::from_utf8(&body[2..2 + topic_len])
+                .map_err(|_| Error::Malformed)?;
+            Packet::Publish {
+                topic,
+                payload: &body[payload_start..],
+            }
+        }
+        SUBACK => Packet::SubAck,
+        PINGRESP => Packet::PingResp,
+        _ => Packet::Other,
+    };
+
+    Ok(Some((packet, end)))
+}
+
+/// `dotmatrix/<id>/<leaf>`
+pub fn topic(device_id: &str, leaf: &str) -> String<64> {
+    let mut out = String::new();
+    let _ = write!(out, "dotmatrix/{}/{}", device_id, leaf);
+    out
+}
+
+#[cfg(feature = "firmware")]
+#[embassy_executor::task]
+pub async fn run(
+    stack: &'static Stack<NetDriver<'static>>,
+    config: MqttConfig,
+) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    // a publish with the longest message, and its topic
+    let mut buf = [0; 1024];
+    // the longest reply, with room for its topic and headers
+    let mut out = [0; REPLY_LEN + 128];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(PING_INTERVAL * 3));
+
+        if session(&mut socket, &config, &mut buf, &mut out)
+            .await
+            .is_err()
+        {
+            status::record_error("mqtt");
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(feature = "firmware")]
+async fn send(socket: &mut TcpSocket<'_>, packet: &[u8]) -> Result<(), Error> {
+    socket.write_all(packet).await?;
+    Ok(())
+}
+
+#[cfg(feature = "firmware")]
+async fn session(
+    socket: &mut TcpSocket<'_>,
+    config: &MqttConfig,
+    buf: &mut [u8],
+    out: &mut [u8],
+) -> Result<(), Error> {
+    socket.connect(config.broker).await?;
+
+    let availability = topic(config.device_id, "availability");
+    let will = Will {
+        topic: &availability,
+        payload: b"offline",
+        retain: true,
+    };
+    let n =
+        encode_connect(out, config.device_id, KEEP_ALIVE_SECS, Some(&will))?;
+    send(socket, &out[..n]).await?;
+
+    let mut len = 0;
+    let mut connected = false;
+    let mut last_sent = Instant::now();
+
+    loop {
+        let read = socket.read(&mut buf[len..]);
+        let ping = Timer::at(last_sent + PING_INTERVAL);
+        let result = select(read, ping).await;
+        match result {
+            Either::First(Ok(0)) => return Err(Error::Closed),
+            Either::First(Ok(n)) => len += n,
+            Either::First(Err(e)) => return Err(e.into()),
+            Either::Second(()) => {
+                let n = encode_pingreq(out)?;
+                send(socket, &out[..n]).await?;
+                last_sent = Instant::now();
+                continue;
+            }
+        }
+
+        let mut start = 0;
+        while let Some((packet, used)) = decode(&buf[start..len])? {
+            match packet {
+                Packet::ConnAck { return_code: 0 } if !connected => {
+                    connected = true;
+                    let mut filters: [String<64>; SUBSCRIPTIONS.len()] =
+                        Default::default();
+                    for (f, leaf) in filters.iter_mut().zip(SUBSCRIPTIONS) {
+                        *f = topic(config.device_id, leaf);
+                    }
+                    let filters = filters.each_ref().map(|f| f.as_str());
+                    let n = encode_subscribe(out, 1, &filters)?;
+                    send(socket, &out[..n]).await?;
+
+                    let n =
+                        encode_publish(out, &availability, b"online", true)?;
+                    send(socket, &out[..n]).await?;
+                    last_sent = Instant::now();
+                }
+                Packet::ConnAck { return_code } => {
+                    return Err(Error::Refused(return_code))
+                }
+                Packet::Publish { topic: t, payload } => {
+                    let mut reply = String::new();
+                    dispatch(config.device_id, t, payload, &mut reply).await;
+                    let status = topic(config.device_id, "status");
+                    let n =
+                        encode_publish(out, &status, reply.as_bytes(), false)?;
+                    send(socket, &out[..n]).await?;
+                    last_sent = Instant::now();
+                }
+                Packet::SubAck | Packet::PingResp | Packet::Other => {}
+            }
+            start += used;
+        }
+
+        buf.copy_within(start..len, 0);
+        len -= start;
+        if len == buf.len() {
+            // a single packet bigger than our buffer
+            return Err(Error::Buffer);
+        }
+    }
+}
+
+/// `dotmatrix/<id>/<leaf>` to `<leaf>`
+#[cfg(feature = "firmware")]
+fn leaf<'t>(device_id: &str, topic: &'t str) -> Option<&'t str> {
+    topic
+        .strip_prefix("dotmatrix/")
+        .and_then(|t| t.strip_prefix(device_id))
+        .and_then(|t| t.strip_prefix('/'))
+}
+
+/// runs the command for a message on one of our topics, and writes the
+/// text to publish on the status topic to `reply`
+#[cfg(feature = "firmware")]
+async fn dispatch(
+    device_id: &str,
+    topic: &str,
+    payload: &[u8],
+    reply: &mut String<REPLY_LEN>,
+) {
+    let query = payload.trim_ascii();
+    if leaf(device_id, topic) == Some("query")
+        && query.eq_ignore_ascii_case(b"status")
+    {
+        if status::report(reply).await.is_err() {
+            // better than half a report
+            let e = crate::Error::Truncated;
+            reply.clear();
+            let _ = write!(reply, "error {}: {}", e.code(), e);
+        }
+        return;
+    }
+    match command(device_id, topic, payload).await {
+        Ok(done) => {
+            let _ = reply.push_str(done);
+        }
+        Err(e) => {
+            let _ = write!(reply, "error {}: {}", e.code(), e);
+        }
+    }
+}
+
+#[cfg(feature = "firmware")]
+async fn command(
+    device_id: &str,
+    topic: &str,
+    payload: &[u8],
+) -> Result<&'static str, crate::Error> {
+    let leaf = leaf(device_id, topic).ok_or(crate::Error::UnknownCommand)?;
+    // anyone on the broker can publish, so with keys set up only the
+    // command port may change things
+    if !auth::open().await {
+        return Err(crate::Error::Denied);
+    }
+    let payload = str::from_utf8(payload)?.trim();
+
+    let reply = match leaf {
+        "message" => {
+            commands::echo(payload, Effect::Alert).await?;
+            "message queued"
+        }
+        "clock" => {
+            commands::set_clock(payload).await?;
+            "clock set"
+        }
+        "weather" => {
+            commands::set_weather(payload).await?;
+            "weather set"
+        }
+        "brightness" => {
+            let level =
+                payload.parse().map_err(|_| crate::Error::OutOfRange)?;
+            commands::set_brightness(level)?;
+            "brightness set"
+        }
+        // only `status` can be queried so far
+        _ => return Err(crate::Error::UnknownCommand),
+    };
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn publish(buf: &[u8]) -> (&str, &[u8], usize) {
+        match decode(buf) {
+            Ok(Some((Packet::Publish { topic, payload }, len))) => {
+                (topic, payload, len)
+            }
+            _ => panic!("not a publish"),
+        }
+    }
+
+    #[test]
+    fn broker_replies() {
+        // as mosquitto sends them
+        let connack = [0x20, 0x02, 0x00, 0x05];
+        assert!(matches!(
+            decode(&connack),
+            Ok(Some((Packet::ConnAck { return_code: 5 }, 4)))
+        ));
+        assert!(matches!(
+            decode(&[0x90, 0x03, 0x00, 0x01, 0x00]),
+            Ok(Some((Packet::SubAck, 5)))
+        ));
+        assert!(matches!(
+            decode(&[0xd0, 0x00, 0x30]),
+            Ok(Some((Packet::PingResp, 2)))
+        ));
+        assert!(matches!(decode(&connack[..3]), Ok(None)));
+        assert!(matches!(decode(&[]), Ok(None)));
+        assert!(matches!(decode(&[0x20, 0x01, 0x00]), Err(Error::Malformed)));
+        assert!(matches!(
+            decode(&[0x30, 0xff, 0xff, 0xff, 0xff]),
+            Err(Error::Malformed)
+        ));
+    }
+
+    #[test]
+    fn a_qos_1_publish_skips_the_packet_id() {
+        let buf = [0x32, 0x08, 0x00, 0x02, b'a', b'/', 0x00, 0x07, b'h', b'i'];
+        assert_eq!(publish(&buf), ("a/", &b"hi"[..], 10));
+    }
+
+    #[test]
+    fn a_long_publish_takes_two_length_bytes() {
+        let mut buf = [0; 512];
+        let payload = [b'x'; 300];
+        let len =
+            encode_publish(&mut buf, "dotmatrix/1/message", &payload, true)
+                .unwrap();
+        assert_eq!(buf[0], PUBLISH | 1);
+        assert_eq!(buf[1] & 0x80, 0x80);
+        assert_eq!(
+            publish(&buf[..len]),
+            ("dotmatrix/1/message", &payload[..], len)
+        );
+        assert!(matches!(
+            encode_publish(&mut buf[..64], "t", &payload, false),
+            Err(Error::Buffer)
+        ));
+    }
+
+    #[test]
+    fn connect_with_a_will() {
+        let will = Will {
+            topic: "dotmatrix/1/availability",
+            payload: b"offline",
+            retain: true,
+        };
+        let mut buf = [0; 128];
+        let len = encode_connect(&mut buf, "1", 60, Some(&will)).unwrap();
+        let expected = [
+            &[0x10, 48, 0, 4][..],
+            b"MQTT",
+            &[4, 0x26, 0, 60, 0, 1, b'1', 0, 24],
+            b"dotmatrix/1/availability",
+            &[0, 7],
+            b"offline",
+        ]
+        .concat();
+        assert_eq!(&buf[..len], &expected[..]);
+    }
+
+    #[test]
+    fn topics() {
+        assert_eq!(topic("1", "status"), "dotmatrix/1/status");
+    }
+
+    proptest! {
+        #[test]
+        fn publishes_round_trip(
+            topic in "[a-z/]{1,64}",
+            payload in prop::collection::vec(any::<u8>(), 0..900),
+        ) {
+            let mut buf = [0; 1024];
+            let len = encode_publish(&mut buf, &topic, &payload, false)
+                .unwrap();
+            let decoded = publish(&buf[..len]);
+            prop_assert_eq!(decoded, (&*topic, &payload[..], len));
+            // and nothing until it's all there
+            prop_assert!(matches!(decode(&buf[..len - 1]), Ok(None)));
+        }
+
+        #[test]
+        fn any_bytes_decode_or_not(buf in any::<Vec<u8>>()) {
+            let _ = decode(&buf);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! one connection to the command port, from the banner to hanging up:
+//! signing in, read-only keys, and a reply for every command. it only sees
+//! what was read and says what to write back, so the sign's socket and the
+//! simulator's both drive the same one
+
+use core::fmt::Write as _;
+
+use crate::auth::{self, Limiter, Permission, KEYRING, NONCE_LEN};
+use crate::commands::{self, handle_command, CommandStatus};
+use crate::Error;
+
+/// what to do with a read
+pub enum Step {
+    /// nothing to answer, read on
+    Quiet,
+    /// write this, then read on
+    Reply(CommandStatus),
+    /// write this, then hang up
+    Last(CommandStatus),
+}
+
+pub struct Session<'a, P> {
+    peer: P,
+    nonce: heapless::String<{ NONCE_LEN * 2 }>,
+    /// None until a key is given, when the sign has any
+    permission: Option<Permission>,
+    limiter: &'a mut Limiter<P>,
+}
+
+impl<'a, P: Copy + PartialEq> Session<'a, P> {
+    /// `nonce` has to be fresh for every connection, so answers can't be
+    /// replayed. `open` is whether the sign has no keys (`auth::open`), in
+    /// which case anyone gets in
+    pub fn new(
+        peer: P,
+        nonce: &[u8; NONCE_LEN],
+        open: bool,
+        limiter: &'a mut Limiter<P>,
+    ) -> Self {
+        Self {
+            peer,
+            nonce: auth::nonce_hex(nonce),
+            permission: open.then_some(Permission::Full),
+            limiter,
+        }
+    }
+
+    /// the first thing written, with the nonce to sign if there are keys
+    pub fn banner(&self) -> CommandStatus {
+        let mut banner = CommandStatus::new("[*] welcome~");
+        if self.permission.is_none() {
+            let _ = write!(banner, " nonce={}", self.nonce);
+        }
+        let _ = banner.write_char('\n');
+        banner
+    }
+
+    /// whatever one read brought in is one command. `now_ms` is only for
+    /// the limiter, so any clock that goes forward will do
+    pub async fn read(&mut self, bytes: &[u8], now_ms: u64) -> Step {
+        // not text, or a char split across two reads
+        let Ok(string) = crate::get_null_term_string(bytes) else {
+            return Step::Reply(CommandStatus::error(Error::Utf8));
+        };
+        let string = string.trim();
+        if string.is_empty() {
+            return Step::Quiet;
+        }
+
+        match self.permission {
+            None => {
+                if let Some(ms) = self.limiter.wait_ms(self.peer, now_ms) {
+                    let mut status = CommandStatus::new("");
+                    let _ = writeln!(
+                        status,
+                        "[*] too many tries, wait {}s :c",
+                        ms.div_ceil(1000)
+                    );
+                    return Step::Last(status);
+                }
+                self.permission =
+                    KEYRING.lock().await.verify(string, &self.nonce);
+                if self.permission.is_none() {
+                    // a new connection gets a new nonce
+                    self.limiter.failed(self.peer, now_ms);
+                    return Step::Last(CommandStatus::new(
+                        "[*] auth failed :c\n",
+                    ));
+                }
+                self.limiter.succeeded(self.peer);
+                Step::Reply(CommandStatus::new("[*] authenticated ^-^\n"))
+            }
+            Some(Permission::ReadOnly) if !commands::is_read_only(string) => {
+                Step::Reply(CommandStatus::error(Error::Denied))
+            }
+            Some(_) => Step::Reply(handle_command(string).await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+
+    const NONCE: [u8; NONCE_LEN] = [0xab; NONCE_LEN];
+
+    /// what was written back, and whether that was the last of it
+    fn read(
+        session: &mut Session<u8>,
+        bytes: &[u8],
+    ) -> Option<(String, bool)> {
+        match block_on(session.read(bytes, 0)) {
+            Step::Quiet => None,
+            Step::Reply(reply) => Some((reply.mesg.to_string(), false)),
+            Step::Last(reply) => Some((reply.mesg.to_string(), true)),
+        }
+    }
+
+    fn reply(text: &str) -> Option<(String, bool)> {
+        Some((text.to_string(), false))
+    }
+
+    // the tests' keyring is always empty, so nobody signs in here
+    #[test]
+    fn without_keys_anyone_gets_in() {
+        let mut limiter = Limiter::new();
+        let mut session = Session::new(1, &NONCE, true, &mut limiter);
+        assert_eq!(session.banner().mesg, "[*] welcome~\n");
+        assert_eq!(read(&mut session, b"klist\n"), reply("[*] no keys\n"));
+        assert_eq!(read(&mut session, b"  \r\n"), None);
+        assert_eq!(read(&mut session, b""), None);
+        assert_eq!(
+            read(&mut session, b"0\xff\n"),
+            reply("[*] error 1: not utf-8 :c\n")
+        );
+    }
+
+    #[test]
+    fn a_wrong_key_hangs_up_and_backs_off() {
+        let mut limiter = Limiter::new();
+        let mut session = Session::new(1, &NONCE, false, &mut limiter);
+        let banner = session.banner();
+        let nonce = "ab".repeat(NONCE_LEN);
+        assert_eq!(
+            banner.mesg.as_str(),
+            format!("[*] welcome~ nonce={}\n", nonce)
+        );
+        let failed = Some(("[*] auth failed :c\n".to_string(), true));
+        assert_eq!(read(&mut session, b"auth laptop 00"), failed);
+
+        // straight back in, from the same address and from another
+        let mut session = Session::new(1, &NONCE, false, &mut limiter);
+        let wait = Some(("[*] too many tries, wait 1s :c\n".into(), true));
+        assert_eq!(read(&mut session, b"auth laptop 00"), wait);
+        let mut session = Session::new(2, &NONCE, false, &mut limiter);
+        assert_eq!(read(&mut session, b"auth laptop 00"), failed);
+    }
+
+    #[test]
+    fn read_only_keys_only_read() {
+        let mut limiter = Limiter::new();
+        let mut session = Session::new(1, &NONCE, false, &mut limiter);
+        session.permission = Some(Permission::ReadOnly);
+        assert_eq!(read(&mut session, b"klist"), reply("[*] no keys\n"));
+        assert_eq!(
+            read(&mut session, b"kadd a rw b"),
+            reply("[*] error 9: not allowed :c\n")
+        );
+    }
+}
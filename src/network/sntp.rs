@@ -0,0 +1,165 @@
+#[cfg(feature = "firmware")]
+pub use firmware::sync;
+
+const PACKET_LEN: usize = 48;
+
+/// seconds between 1900-01-01 (ntp epoch) and 1970-01-01 (unix epoch)
+const NTP_TO_UNIX_SECS: u64 = 2_208_988_800;
+
+/// a client request. `nonce` goes in the transmit timestamp, which the
+/// server echoes back as the originate timestamp
+pub fn request(nonce: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    packet[0] = (4 << 3) | 3; // li = 0, version 4, mode 3 (client)
+    packet[40..48].copy_from_slice(&nonce.to_be_bytes());
+    packet
+}
+
+/// unix time in ms from a server reply to the request with `nonce`
+pub fn parse_reply(packet: &[u8], nonce: u64) -> Option<u64> {
+    if packet.len() < PACKET_LEN {
+        return None;
+    }
+    let mode = packet[0] & 0x7;
+    let stratum = packet[1];
+    if mode != 4 || stratum == 0 {
+        return None; // not a server reply, or a kiss-of-death
+    }
+    if packet[24..32] != nonce.to_be_bytes() {
+        return None; // not a reply to our request
+    }
+
+    let secs = u32::from_be_bytes(packet[40..44].try_into().ok()?) as u64;
+    let frac = u32::from_be_bytes(packet[44..48].try_into().ok()?) as u64;
+    let unix_secs = secs.checked_sub(NTP_TO_UNIX_SECS)?;
+    Some(unix_secs * 1000 + ((frac * 1000) >> 32))
+}
+
+#[cfg(feature = "firmware")]
+mod firmware {
+    use cyw43::NetDriver;
+    use embassy_net::udp::{PacketMetadata, UdpSocket};
+    use embassy_net::{IpEndpoint, Stack};
+    use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+    use super::{parse_reply, request, PACKET_LEN};
+    use crate::clock;
+
+    const LOCAL_PORT: u16 = 12300;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    #[embassy_executor::task]
+    pub async fn sync(
+        stack: &'static Stack<NetDriver<'static>>,
+        server: IpEndpoint,
+    ) {
+        let mut rx_meta = [PacketMetadata::EMPTY; 2];
+        let mut rx_buffer = [0; 128];
+        let mut tx_meta = [PacketMetadata::EMPTY; 2];
+        let mut tx_buffer = [0; 128];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let _ = socket.bind(LOCAL_PORT);
+
+        let mut buf = [0; PACKET_LEN];
+        loop {
+            let sent_at = Instant::now();
+            let nonce = sent_at.as_ticks();
+            if socket.send_to(&request(nonce), server).await.is_err() {
+                Timer::after(RETRY_INTERVAL).await;
+                continue;
+            }
+
+            let reply = with_timeout(TIMEOUT, socket.recv_from(&mut buf));
+            let synced = match reply.await {
+                Ok(Ok((n, _))) => parse_reply(&buf[..n], nonce),
+                _ => None,
+            };
+
+            match synced {
+                Some(unix_ms) => {
+                    // assume the reply took half the round trip to get here
+                    let rtt = sent_at.elapsed().as_millis();
+                    clock::set_time(unix_ms + rtt / 2);
+                    Timer::after(SYNC_INTERVAL).await;
+                }
+                None => Timer::after(RETRY_INTERVAL).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: u64 = 0x0123_4567_89ab_cdef;
+
+    /// a stratum 2 server's reply to `NONCE`, sent at 2024-01-01 00:00:00.5
+    fn reply() -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0] = (4 << 3) | 4; // version 4, mode 4 (server)
+        packet[1] = 2;
+        packet[24..32].copy_from_slice(&NONCE.to_be_bytes());
+        let secs = (1_704_067_200 + NTP_TO_UNIX_SECS) as u32;
+        packet[40..44].copy_from_slice(&secs.to_be_bytes());
+        packet[44..48].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn requests_carry_the_nonce() {
+        let request = request(NONCE);
+        assert_eq!(request[0], 0x23);
+        assert_eq!(request[40..48], NONCE.to_be_bytes());
+        assert!(request[1..40].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn replies() {
+        assert_eq!(parse_reply(&reply(), NONCE), Some(1_704_067_200_500));
+        // with an extension field or a mac on the end
+        let mut longer = [0; PACKET_LEN + 20];
+        longer[..PACKET_LEN].copy_from_slice(&reply());
+        assert_eq!(parse_reply(&longer, NONCE), Some(1_704_067_200_500));
+    }
+
+    #[test]
+    fn kiss_of_death() {
+        let mut kod = reply();
+        kod[0] = (3 << 6) | (4 << 3) | 4; // li = 3, unsynchronized
+        kod[1] = 0;
+        kod[12..16].copy_from_slice(b"RATE");
+        kod[40..48].fill(0);
+        assert_eq!(parse_reply(&kod, NONCE), None);
+    }
+
+    #[test]
+    fn not_replies_to_us() {
+        assert_eq!(parse_reply(&reply(), NONCE + 1), None);
+        // our own request, looped back
+        assert_eq!(parse_reply(&request(NONCE), NONCE), None);
+        let mut broadcast = reply();
+        broadcast[0] = (4 << 3) | 5;
+        assert_eq!(parse_reply(&broadcast, NONCE), None);
+        // before 1970 (or after the 2036 era rollover)
+        let mut early = reply();
+        early[40..44].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(parse_reply(&early, NONCE), None);
+    }
+
+    #[test]
+    fn bad_lengths() {
+        let reply = reply();
+        for len in [0, 1, 24, 40, PACKET_LEN - 1] {
+            assert_eq!(parse_reply(&reply[..len], NONCE), None, "{}", len);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! keeps the sign on wifi. `configure_network` only joins once, so this
+//! watches the link and the dhcp lease, and joins again when either goes
+//! away for too long. it also owns the cyw43 led: on while online, blinking
+//! while it's trying to get back
+
+use core::sync::atomic::AtomicBool;
+
+use embassy_time::Duration;
+
+use crate::config::NetworkConfig;
+
+#[cfg(feature = "firmware")]
+pub use firmware::supervise;
+
+const BASE_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+/// whether the sign has a link and an address right now
+pub static ONLINE: AtomicBool = AtomicBool::new(false);
+
+pub struct SupervisorConfig {
+    pub network: &'static NetworkConfig,
+    /// scroll `NO WIFI` on the panels when the connection is lost
+    pub show_outages: bool,
+}
+
+/// the wait after `failures` failed joins in a row
+pub fn backoff(failures: u32) -> Duration {
+    let secs = BASE_BACKOFF_SECS << failures.min(16);
+    Duration::from_secs(secs.min(MAX_BACKOFF_SECS))
+}
+
+#[cfg(feature = "firmware")]
+mod firmware {
+    use core::sync::atomic::Ordering;
+
+    use cyw43::{Control, NetDriver};
+    use embassy_futures::select::{select, Either};
+    use embassy_net::Stack;
+    use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+    use super::{backoff, SupervisorConfig, ONLINE};
+    use crate::config::NetworkConfig;
+    use crate::queue::{Message, NORMAL};
+    use crate::status;
+    use crate::QUEUE;
+
+    /// how often the link is checked while it's fine
+    const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+    /// a link that drops for less than this is left to come back on its own,
+    /// e.g. while the lease is renewed
+    const GRACE: Duration = Duration::from_secs(10);
+    /// how long to wait for a lease after joining
+    const DHCP_TIMEOUT: Duration = Duration::from_secs(20);
+    const BLINK: Duration = Duration::from_millis(250);
+
+    const NO_WIFI_MESSAGE: &str = "NO WIFI";
+
+    fn is_up(stack: &Stack<NetDriver<'static>>) -> bool {
+        stack.is_link_up() && stack.is_config_up()
+    }
+
+    /// blinks the led for `duration`, or until the stack comes back up
+    async fn blink(
+        stack: &Stack<NetDriver<'static>>,
+        ctrl: &mut Control<'static>,
+        duration: Duration,
+    ) {
+        let until = Instant::now() + duration;
+        let mut on = false;
+        while Instant::now() < until && !is_up(stack) {
+            on = !on;
+            ctrl.gpio_set(0, on).await;
+            Timer::after(BLINK).await;
+        }
+    }
+
+    async fn join(
+        stack: &Stack<NetDriver<'static>>,
+        ctrl: &mut Control<'static>,
+        network: &NetworkConfig,
+    ) -> bool {
+        ctrl.leave().await;
+        let joined = match network.password.as_str() {
+            "" => ctrl.join_open(&network.ssid).await,
+            password => ctrl.join_wpa2(&network.ssid, password).await,
+        };
+        if joined.is_err() {
+            return false;
+        }
+        // with a static address this returns right away
+        let lease = with_timeout(DHCP_TIMEOUT, stack.wait_config_up());
+        let blinking = async {
+            loop {
+                blink(stack, ctrl, DHCP_TIMEOUT).await;
+                Timer::after(BLINK).await;
+            }
+        };
+        match select(lease, blinking).await {
+            Either::First(lease) => lease.is_ok(),
+            Either::Second(()) => false,
+        }
+    }
+
+    async fn show_outage() -> Option<u16> {
+        let mut message = Message::text(NO_WIFI_MESSAGE, NORMAL).ok()?;
+        message.remaining = 2;
+        message.expires_at = Some(Instant::now().as_secs() + 60 * 60);
+        // nothing would take it down again after a reboot
+        message.saved = false;
+        crate::commands::enqueue(message).await.ok()
+    }
+
+    #[embassy_executor::task]
+    pub async fn supervise(
+        stack: &'static Stack<NetDriver<'static>>,
+        mut ctrl: Control<'static>,
+        config: SupervisorConfig,
+    ) {
+        let mut failures = 0;
+        // when the connection was first seen down
+        let mut down_since: Option<Instant> = None;
+        // the `NO WIFI` message, while it's queued
+        let mut notice: Option<u16> = None;
+
+        loop {
+            if is_up(stack) {
+                let was_down = down_since.take().is_some();
+                if was_down || !ONLINE.load(Ordering::Relaxed) {
+                    ONLINE.store(true, Ordering::Relaxed);
+                    ctrl.gpio_set(0, true).await;
+                    failures = 0;
+                    if let Some(id) = notice.take() {
+                        let _ = QUEUE.lock().await.remove(id);
+                    }
+                }
+                // the lease may have moved us
+                status::set_address(stack.config_v4().map(|c| c.address));
+                Timer::after(CHECK_INTERVAL).await;
+                continue;
+            }
+
+            let since = *down_since.get_or_insert_with(Instant::now);
+            if Instant::now() - since < GRACE {
+                blink(stack, &mut ctrl, CHECK_INTERVAL).await;
+                continue;
+            }
+
+            if ONLINE.swap(false, Ordering::Relaxed) {
+                status::set_address(None);
+                if config.show_outages {
+                    notice = show_outage().await;
+                }
+            }
+
+            if join(stack, &mut ctrl, config.network).await {
+                continue;
+            }
+            failures += 1;
+            status::record_error("wifi-join");
+            blink(stack, &mut ctrl, backoff(failures)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let secs: std::vec::Vec<u64> =
+            (0..9).map(|n| backoff(n).as_secs()).collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(backoff(u32::MAX).as_secs(), MAX_BACKOFF_SECS);
+    }
+}
@@ -0,0 +1,234 @@
+//! firmware updates over the air. memory.x splits the flash between the
+//! bootloader (see bootloader/), the running firmware, a dfu partition the
+//! next firmware is written to, and the config store. an update is only
+//! swapped in if it's signed by the key in `firmware.pub`, and newer than
+//! the running firmware, so an old one can't be put back. after the swap
+//! the new firmware is on trial: unless it passes `confirm`'s self-test in
+//! time, `liveness` lets the watchdog reset it and the bootloader swaps the
+//! old one back
+
+use core::sync::atomic::Ordering;
+
+use embassy_boot_rp::{
+    AlignedBuffer, FirmwareUpdater, FirmwareUpdaterConfig, State,
+};
+use embassy_embedded_hal::flash::partition::Partition;
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::liveness::{self, Task};
+use crate::network::supervisor;
+use crate::status;
+
+// the key from firmware.pub, if there was one at build time
+include!(concat!(env!("OUT_DIR"), "/firmware_key.rs"));
+// this firmware's version as one number, see build.rs
+include!(concat!(env!("OUT_DIR"), "/release.rs"));
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// updates are written a flash page at a time
+pub const PAGE_SIZE: usize = 4096;
+/// an ed25519 signature over the sha-512 of the image
+pub const SIGNATURE_LEN: usize = 64;
+/// the image ends with the release it is, little-endian
+pub const RELEASE_LEN: usize = 4;
+
+/// how long a new firmware has to pass its self-test
+const TRIAL: Duration = Duration::from_secs(120);
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub type OnboardFlash = Flash<'static, FLASH, Async, FLASH_SIZE>;
+/// the store, the updater and the self-test all take turns on the flash
+pub type SharedFlash = Mutex<NoopRawMutex, OnboardFlash>;
+type Part = Partition<'static, NoopRawMutex, OnboardFlash>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// built without `firmware.pub`
+    Disabled,
+    /// the running firmware hasn't confirmed itself yet
+    OnTrial,
+    TooBig,
+    /// the image ended early, or went on past its length
+    Length,
+    BadSignature,
+    /// not newer than the running firmware
+    Old,
+    Flash,
+}
+
+impl From<embassy_boot_rp::FirmwareUpdaterError> for Error {
+    fn from(e: embassy_boot_rp::FirmwareUpdaterError) -> Self {
+        match e {
+            embassy_boot_rp::FirmwareUpdaterError::Signature(_) => {
+                Self::BadSignature
+            }
+            _ => Self::Flash,
+        }
+    }
+}
+
+/// an image being written to the dfu partition. it arrives as the image
+/// followed by its signature, in pieces of any size. the image is the
+/// firmware and then its release, so the signature covers both
+pub struct Update<'d> {
+    updater: FirmwareUpdater<'d, Part, Part>,
+    page: AlignedBuffer<PAGE_SIZE>,
+    page_len: usize,
+    /// bytes of the image written out so far
+    written: usize,
+    image_len: usize,
+    release: [u8; RELEASE_LEN],
+    signature: [u8; SIGNATURE_LEN],
+    signature_len: usize,
+}
+
+impl<'d> Update<'d> {
+    /// `state` is scratch space for the bootloader state
+    pub async fn begin(
+        flash: &'static SharedFlash,
+        state: &'d mut AlignedBuffer<1>,
+        image_len: usize,
+    ) -> Result<Self, Error> {
+        if PUBLIC_KEY.is_none() {
+            return Err(Error::Disabled);
+        }
+        if image_len < RELEASE_LEN {
+            return Err(Error::Length);
+        }
+        let config = FirmwareUpdaterConfig::from_linkerfile(flash);
+        // the bootloader needs a spare page to swap through
+        if image_len + PAGE_SIZE > config.dfu.size() as usize {
+            return Err(Error::TooBig);
+        }
+        let mut updater = FirmwareUpdater::new(config, &mut state.0);
+        if updater.get_state().await? != State::Boot {
+            return Err(Error::OnTrial);
+        }
+        Ok(Self {
+            updater,
+            page: AlignedBuffer([0; PAGE_SIZE]),
+            page_len: 0,
+            written: 0,
+            image_len,
+            release: [0; RELEASE_LEN],
+            signature: [0; SIGNATURE_LEN],
+            signature_len: 0,
+        })
+    }
+
+    /// takes the next piece of the image, or of the signature after it
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let image_left = self.image_len - self.written - self.page_len;
+            if image_left == 0 {
+                let signature = &mut self.signature[self.signature_len..];
+                if data.len() > signature.len() {
+                    return Err(Error::Length);
+                }
+                signature[..data.len()].copy_from_slice(data);
+                self.signature_len += data.len();
+                return Ok(());
+            }
+
+            let room = &mut self.page.0[self.page_len..];
+            let n = room.len().min(image_left).min(data.len());
+            room[..n].copy_from_slice(&data[..n]);
+            // the last few bytes of the image are kept for `finish`
+            let at = self.written + self.page_len;
+            for (i, &b) in (at..at + n).zip(&data[..n]) {
+                if let Some(j) = i.checked_sub(self.image_len - RELEASE_LEN) {
+                    self.release[j] = b;
+                }
+            }
+            self.page_len += n;
+            data = &data[n..];
+            if self.page_len == PAGE_SIZE {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        // a short last page is padded out like erased flash
+        self.page.0[self.page_len..].fill(0xff);
+        self.updater
+            .write_firmware(self.written, &self.page.0)
+            .await?;
+        self.written += self.page_len;
+        self.page_len = 0;
+        Ok(())
+    }
+
+    /// checks the release and the signature, and has the bootloader swap
+    /// the update in on the next boot
+    pub async fn finish(mut self) -> Result<(), Error> {
+        let key = PUBLIC_KEY.ok_or(Error::Disabled)?;
+        if self.page_len > 0 {
+            self.flush().await?;
+        }
+        if self.written != self.image_len
+            || self.signature_len != SIGNATURE_LEN
+        {
+            return Err(Error::Length);
+        }
+        // before the signature, since checking that marks it for the swap.
+        // a made up release is no use, the signature covers it
+        if u32::from_le_bytes(self.release) <= RELEASE {
+            return Err(Error::Old);
+        }
+        self.updater
+            .verify_and_mark_updated(
+                key,
+                &self.signature,
+                self.image_len as u32,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// whether the new firmware is working. it has to reach wifi again when
+/// wifi is set up, or it couldn't be updated any further
+fn self_test(needs_network: bool) -> bool {
+    !needs_network || supervisor::ONLINE.load(Ordering::Relaxed)
+}
+
+/// confirms a freshly swapped in firmware once it passes its self-test.
+/// the trial is watched like any other task, so running out of time gets
+/// it reset and rolled back, same as a firmware that hangs
+#[embassy_executor::task]
+pub async fn confirm(flash: &'static SharedFlash, needs_network: bool) {
+    let mut state = AlignedBuffer([0; 1]);
+    let config = FirmwareUpdaterConfig::from_linkerfile(flash);
+    let mut updater = FirmwareUpdater::new(config, &mut state.0);
+    match updater.get_state().await {
+        Ok(State::Swap) => {}
+        Ok(_) => return,
+        Err(_) => {
+            status::record_error("ota-state");
+            return;
+        }
+    }
+
+    let deadline = Instant::now() + TRIAL;
+    while !self_test(needs_network) {
+        if Instant::now() > deadline {
+            // stop checking in, and let the watchdog roll us back
+            status::record_error("ota-self-test");
+            core::future::pending::<()>().await;
+        }
+        liveness::check_in(Task::Trial);
+        Timer::after(CHECK_INTERVAL).await;
+    }
+
+    if updater.mark_booted().await.is_err() {
+        // still on trial, so the next reset rolls back
+        status::record_error("ota-confirm");
+    }
+    liveness::pause(Task::Trial);
+}
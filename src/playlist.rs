@@ -0,0 +1,495 @@
+use core::fmt::{self, Write};
+
+use heapless::{String, Vec};
+
+use crate::graphics::{self, Bitmap};
+
+pub const MAX_SLOTS: usize = 16;
+/// in bytes. text is scrolled a glyph at a time, so this is only about
+/// memory. there are only glyphs for ascii, so it's as many chars too
+pub const MAX_TEXT_LEN: usize = 512;
+/// the whole playlist has to fit in one stored value
+const MAX_ENCODED_LEN: usize = crate::storage::MAX_VALUE_LEN;
+
+const DEFAULT_DURATION_SECS: u16 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Animation {
+    Pulse,
+    Alert,
+}
+
+// no allocator to box the text with, and slots are only ever kept in the
+// playlist's own array
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Content {
+    Text(String<MAX_TEXT_LEN>),
+    Clock,
+    Weather,
+    Image(Bitmap),
+    Animation(Animation),
+}
+
+impl Content {
+    /// what kind of content this is, as named in playlist commands
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Text(_) => "text",
+            Self::Clock => "clock",
+            Self::Weather => "weather",
+            Self::Image(_) => "image",
+            Self::Animation(Animation::Pulse) => "pulse",
+            Self::Animation(Animation::Alert) => "alert",
+        }
+    }
+
+    /// `text <message>`, `clock`, `weather`, `image <64 hex digits>`,
+    /// `pulse` or `alert`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (kind, arg) = spec.split_once(' ').unwrap_or((spec, ""));
+        let content = match (kind, arg.trim_start()) {
+            ("text", "") => return None,
+            ("text", text) => Self::Text(String::try_from(text).ok()?),
+            ("image", hex) => Self::Image(graphics::bitmap_from_hex(hex)?),
+            ("clock", "") => Self::Clock,
+            ("weather", "") => Self::Weather,
+            ("pulse", "") => Self::Animation(Animation::Pulse),
+            ("alert", "") => Self::Animation(Animation::Alert),
+            _ => return None,
+        };
+        Some(content)
+    }
+
+    /// the inverse of `parse`
+    pub fn write(&self, out: &mut impl Write) -> fmt::Result {
+        match self {
+            Self::Text(text) => write!(out, "text {}", text),
+            Self::Image(bitmap) => {
+                out.write_str("image ")?;
+                graphics::write_hex(out, bitmap)
+            }
+            _ => out.write_str(self.kind()),
+        }
+    }
+}
+
+/// minutes since local midnight, from `start` up to (not including) `end`.
+/// wraps around midnight if `end` is before `start`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Window {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Window {
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// `HHMM-HHMM`
+    pub fn parse(s: &str) -> Option<Self> {
+        fn minutes(hhmm: &str) -> Option<u16> {
+            let digits = hhmm.bytes().all(|b| b.is_ascii_digit());
+            if hhmm.len() != 4 || !digits {
+                return None;
+            }
+            let h: u16 = hhmm[..2].parse().ok()?;
+            let m: u16 = hhmm[2..].parse().ok()?;
+            let minutes = h * 60 + m;
+            (m < 60 && minutes <= 24 * 60).then_some(minutes)
+        }
+        let (start, end) = s.split_once('-')?;
+        Some(Self {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}{:02}-{:02}{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Slot {
+    pub content: Content,
+    /// how long images and weather reports stay up, each time.
+    /// text, the clock and animations take as long as they take
+    pub duration_secs: u16,
+    /// how many times in a row the slot is played
+    pub repeat: u8,
+    pub enabled: bool,
+    /// only played inside this window, and only once the clock is synced
+    pub window: Option<Window>,
+}
+
+impl Slot {
+    pub fn new(content: Content) -> Self {
+        Self {
+            content,
+            duration_secs: DEFAULT_DURATION_SECS,
+            repeat: 1,
+            enabled: true,
+            window: None,
+        }
+    }
+
+    /// `[d=<secs>] [r=<repeat>] [w=<HHMM-HHMM>] [off] <content>`, where
+    /// content is as `Content::parse` takes it.
+    /// e.g. `d=10 w=0900-1800 text HELLO WORLD`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut slot = Slot::new(Content::Clock);
+        let mut rest = spec.trim_start();
+
+        let content = loop {
+            let (token, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            match token.split_once('=') {
+                Some(("d", v)) => slot.duration_secs = v.parse().ok()?,
+                Some(("r", v)) => slot.repeat = v.parse().ok()?,
+                Some(("w", v)) => slot.window = Some(Window::parse(v)?),
+                Some(_) => return None,
+                None if token == "off" => slot.enabled = false,
+                None => break rest,
+            }
+            rest = tail.trim_start();
+        };
+
+        slot.content = Content::parse(content)?;
+        if slot.repeat == 0 {
+            return None;
+        }
+        Some(slot)
+    }
+
+    /// the inverse of `parse`
+    pub fn write(&self, out: &mut impl Write) -> fmt::Result {
+        write!(out, "d={} r={} ", self.duration_secs, self.repeat)?;
+        if let Some(w) = self.window {
+            write!(out, "w={} ", w)?;
+        }
+        if !self.enabled {
+            out.write_str("off ")?;
+        }
+        self.content.write(out)
+    }
+
+    fn playable(&self, minute_of_day: Option<u16>) -> bool {
+        self.enabled
+            && match (self.window, minute_of_day) {
+                (None, _) => true,
+                (Some(w), Some(m)) => w.contains(m),
+                (Some(_), None) => false,
+            }
+    }
+}
+
+/// counts what's written, and keeps none of it
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// the slot's line, and the newline after it
+fn encoded_len(slot: &Slot) -> usize {
+    let mut counter = Counter(1);
+    let _ = slot.write(&mut counter);
+    counter.0
+}
+
+/// the slots to cycle through, and where we are in them.
+/// this knows nothing about time except what `next` is told, so it can be
+/// stepped through with a made-up clock
+#[derive(Default)]
+pub struct Playlist {
+    slots: Vec<Slot, MAX_SLOTS>,
+    cursor: usize,
+    /// how many times the slot at `cursor` has been played in a row
+    played: u8,
+}
+
+impl Playlist {
+    pub const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            cursor: 0,
+            played: 0,
+        }
+    }
+
+    /// what the sign used to show before playlists existed
+    pub fn with_defaults() -> Self {
+        let mut playlist = Self::new();
+        let mut text = String::new();
+        let _ = text.push_str("AKIHABARA");
+        for content in [
+            Content::Text(text),
+            Content::Animation(Animation::Pulse),
+            Content::Clock,
+            Content::Weather,
+        ] {
+            let _ = playlist.add(Slot::new(content));
+        }
+        playlist
+    }
+
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
+
+    /// the slot to play now, given the local time in minutes since
+    /// midnight (None if the clock isn't synced). None if nothing is
+    /// playable right now
+    pub fn next(&mut self, minute_of_day: Option<u16>) -> Option<Slot> {
+        for _ in 0..self.slots.len() {
+            let slot = &self.slots[self.cursor];
+            if slot.playable(minute_of_day) {
+                let slot = slot.clone();
+                self.played += 1;
+                if self.played >= slot.repeat {
+                    self.advance();
+                }
+                return Some(slot);
+            }
+            self.advance();
+        }
+        None
+    }
+
+    fn advance(&mut self) {
+        self.played = 0;
+        self.cursor = (self.cursor + 1) % self.slots.len();
+    }
+
+    /// fails with `Full` when there's no slot left, or when the playlist
+    /// would get too long to be saved
+    pub fn add(&mut self, slot: Slot) -> Result<(), crate::Error> {
+        if self.encoded_len() + encoded_len(&slot) > MAX_ENCODED_LEN {
+            return Err(crate::Error::Full);
+        }
+        self.slots.push(slot).map_err(|_| crate::Error::Full)
+    }
+
+    /// how long the slots are written out one per line, like config does
+    fn encoded_len(&self) -> usize {
+        self.slots.iter().map(encoded_len).sum()
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Slot, crate::Error> {
+        if index >= self.slots.len() {
+            return Err(crate::Error::OutOfRange);
+        }
+        let slot = self.slots.remove(index);
+        if index < self.cursor {
+            self.cursor -= 1;
+        } else if index == self.cursor {
+            self.played = 0;
+        }
+        if self.cursor >= self.slots.len() {
+            self.cursor = 0;
+        }
+        Ok(slot)
+    }
+
+    pub fn set_enabled(
+        &mut self,
+        index: usize,
+        enabled: bool,
+    ) -> Result<(), crate::Error> {
+        let slot =
+            self.slots.get_mut(index).ok_or(crate::Error::OutOfRange)?;
+        slot.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(spec: &str) -> Slot {
+        Slot::parse(spec).unwrap()
+    }
+
+    fn with_slots(specs: &[&str]) -> Playlist {
+        let mut playlist = Playlist::new();
+        for spec in specs {
+            playlist.add(slot(spec)).unwrap();
+        }
+        playlist
+    }
+
+    /// the kinds `next` hands out, `n` times over at `minute`
+    fn play(
+        playlist: &mut Playlist,
+        minute: Option<u16>,
+        n: usize,
+    ) -> std::vec::Vec<&'static str> {
+        (0..n)
+            .map(|_| playlist.next(minute).map_or("-", |s| s.content.kind()))
+            .collect()
+    }
+
+    #[test]
+    fn slots_round_trip() {
+        for spec in [
+            "d=5 r=1 text HELLO WORLD",
+            "d=10 r=2 w=0900-1800 clock",
+            "d=5 r=1 w=2200-0600 off weather",
+            "d=3 r=1 pulse",
+        ] {
+            let mut out = String::<64>::new();
+            slot(spec).write(&mut out).unwrap();
+            assert_eq!(out, spec);
+        }
+        for spec in ["", "text", "r=0 clock", "w=2500-0100 clock", "x=1 clock"]
+        {
+            assert_eq!(Slot::parse(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn windows_wrap_around_midnight() {
+        let day = Window::parse("0900-1800").unwrap();
+        assert!(!day.contains(8 * 60 + 59));
+        assert!(day.contains(9 * 60));
+        assert!(!day.contains(18 * 60));
+        let night = Window::parse("2200-0600").unwrap();
+        assert!(night.contains(23 * 60) && night.contains(0));
+        assert!(!night.contains(6 * 60) && !night.contains(12 * 60));
+        assert_eq!(Window::parse("0960-1000"), None);
+        assert_eq!(Window::parse("900-1000"), None);
+    }
+
+    #[test]
+    fn an_empty_playlist_has_nothing() {
+        let mut playlist = Playlist::new();
+        assert_eq!(playlist.next(None), None);
+        assert_eq!(playlist.next(Some(600)), None);
+    }
+
+    #[test]
+    fn slots_go_round() {
+        let mut playlist = with_slots(&["text A", "clock", "weather"]);
+        assert_eq!(
+            play(&mut playlist, None, 4),
+            ["text", "clock", "weather", "text"]
+        );
+    }
+
+    #[test]
+    fn disabled_slots_are_skipped() {
+        let mut playlist = with_slots(&["text A", "off clock", "weather"]);
+        assert_eq!(play(&mut playlist, None, 3), ["text", "weather", "text"]);
+        playlist.set_enabled(1, true).unwrap();
+        playlist.set_enabled(0, false).unwrap();
+        assert_eq!(
+            play(&mut playlist, None, 3),
+            ["clock", "weather", "clock"]
+        );
+        assert_eq!(
+            playlist.set_enabled(3, true),
+            Err(crate::Error::OutOfRange)
+        );
+
+        let mut all_off = with_slots(&["off clock", "off weather"]);
+        assert_eq!(all_off.next(None), None);
+    }
+
+    #[test]
+    fn windows_need_the_clock() {
+        let mut playlist = with_slots(&[
+            "w=0900-1800 text DAY",
+            "w=2200-0600 text NIGHT",
+            "clock",
+        ]);
+        // not synced yet, so only the slot without a window
+        assert_eq!(play(&mut playlist, None, 2), ["clock", "clock"]);
+        let noon = Some(12 * 60);
+        let slots: std::vec::Vec<_> = (0..3)
+            .map(|_| playlist.next(noon).unwrap().content)
+            .collect();
+        assert!(matches!(&slots[0], Content::Text(t) if t == "DAY"));
+        assert_eq!(slots[1], Content::Clock);
+        assert!(matches!(&slots[2], Content::Text(t) if t == "DAY"));
+        let midnight = playlist.next(Some(0)).unwrap();
+        assert!(matches!(midnight.content, Content::Text(t) if t == "NIGHT"));
+    }
+
+    #[test]
+    fn repeats_play_in_a_row() {
+        let mut playlist = with_slots(&["r=3 text A", "r=2 clock"]);
+        assert_eq!(
+            play(&mut playlist, None, 6),
+            ["text", "text", "text", "clock", "clock", "text"]
+        );
+        // a repeat cut short by its window carries on with the next slot
+        let mut playlist = with_slots(&["r=3 w=0900-1000 text A", "clock"]);
+        assert_eq!(play(&mut playlist, Some(9 * 60), 1), ["text"]);
+        assert_eq!(play(&mut playlist, Some(10 * 60), 2), ["clock", "clock"]);
+        assert_eq!(
+            play(&mut playlist, Some(9 * 60), 4),
+            ["text", "text", "text", "clock"]
+        );
+    }
+
+    #[test]
+    fn removing_keeps_the_place() {
+        let mut playlist =
+            with_slots(&["text A", "clock", "weather", "pulse"]);
+        assert_eq!(play(&mut playlist, None, 2), ["text", "clock"]);
+        // before the cursor
+        playlist.remove(0).unwrap();
+        assert_eq!(play(&mut playlist, None, 1), ["weather"]);
+        // the one up next
+        playlist.remove(2).unwrap();
+        assert_eq!(play(&mut playlist, None, 2), ["clock", "weather"]);
+        assert_eq!(playlist.remove(2), Err(crate::Error::OutOfRange));
+        playlist.clear();
+        assert!(playlist.slots().is_empty());
+    }
+
+    #[test]
+    fn adding_stops_at_what_can_be_saved() {
+        // 16 slots of anything small
+        let mut playlist = Playlist::new();
+        for _ in 0..MAX_SLOTS {
+            playlist.add(slot("clock")).unwrap();
+        }
+        assert_eq!(playlist.add(slot("clock")), Err(crate::Error::Full));
+
+        // or fewer long ones, before they'd outgrow a stored value
+        let long = "W".repeat(MAX_TEXT_LEN);
+        let long = slot(&format!("text {}", long));
+        let mut playlist = Playlist::new();
+        let fits = MAX_ENCODED_LEN / encoded_len(&long);
+        for _ in 0..fits {
+            playlist.add(long.clone()).unwrap();
+        }
+        assert!(fits < MAX_SLOTS);
+        assert_eq!(playlist.add(long), Err(crate::Error::Full));
+        assert_eq!(playlist.slots().len(), fits);
+        assert!(playlist.encoded_len() <= MAX_ENCODED_LEN);
+    }
+}
@@ -0,0 +1,573 @@
+use core::fmt::{self, Write};
+
+use heapless::{String, Vec};
+
+use crate::playlist::{Content, MAX_TEXT_LEN};
+
+pub const MAX_MESSAGES: usize = 8;
+/// a message as `write` puts it: its options, then its content
+pub const MAX_LINE_LEN: usize = MAX_TEXT_LEN + 64;
+/// the whole queue has to fit in one stored value
+const MAX_ENCODED_LEN: usize = crate::storage::MAX_VALUE_LEN;
+
+/// messages at or above this priority interrupt whatever is showing
+pub const URGENT: u8 = 200;
+pub const NORMAL: u8 = 100;
+
+/// how an echoed message is introduced on the displays
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Effect {
+    /// flash all panels a few times, then scroll
+    Alert,
+    /// just scroll
+    Scroll,
+}
+
+impl Effect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "alert" => Some(Self::Alert),
+            "scroll" => Some(Self::Scroll),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Alert => "alert",
+            Self::Scroll => "scroll",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Message {
+    /// assigned when queued
+    pub id: u16,
+    pub content: Content,
+    pub effect: Effect,
+    pub priority: u8,
+    /// seconds on the uptime clock after which the message is dropped
+    pub expires_at: Option<u64>,
+    /// how many more times the message will be shown
+    pub remaining: u8,
+    /// whether it's saved, to come back after a reboot. the sign's own
+    /// notices, like `NO WIFI`, are only true for this boot
+    pub saved: bool,
+}
+
+impl Message {
+    pub fn new(content: Content, priority: u8) -> Self {
+        Self {
+            id: 0,
+            content,
+            effect: Effect::Alert,
+            priority,
+            expires_at: None,
+            remaining: 1,
+            saved: true,
+        }
+    }
+
+    pub fn text(text: &str, priority: u8) -> Result<Self, crate::Error> {
+        let text: String<MAX_TEXT_LEN> =
+            String::try_from(text).map_err(|_| crate::Error::Truncated)?;
+        Ok(Self::new(Content::Text(text), priority))
+    }
+
+    pub fn is_urgent(&self) -> bool {
+        self.priority >= URGENT
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// the options in front of a message, and what's left after them
+    fn parse_options(spec: &str, now: u64) -> Option<(Self, &str)> {
+        let mut message = Self::new(Content::Clock, NORMAL);
+        let mut rest = spec.trim_start();
+
+        loop {
+            let (token, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            match token.split_once('=') {
+                Some(("p", v)) => message.priority = v.parse().ok()?,
+                Some(("t", v)) => {
+                    message.expires_at = Some(now + v.parse::<u64>().ok()?)
+                }
+                Some(("n", v)) => message.remaining = v.parse().ok()?,
+                Some(("e", v)) => message.effect = Effect::from_name(v)?,
+                _ => break,
+            }
+            rest = tail.trim_start();
+        }
+
+        if rest.is_empty() || message.remaining == 0 {
+            return None;
+        }
+        Some((message, rest))
+    }
+
+    /// `[p=<priority>] [t=<ttl secs>] [n=<times>] [e=<alert|scroll>] <text>`
+    /// e.g. `p=250 t=60 n=3 FIRE DRILL`. `now` is the uptime in seconds
+    pub fn parse(spec: &str, now: u64) -> Option<Self> {
+        let (mut message, text) = Self::parse_options(spec, now)?;
+        message.content = Content::Text(String::try_from(text).ok()?);
+        Some(message)
+    }
+
+    /// the inverse of `write`. version 1 only kept text messages that
+    /// don't expire, as `p=<priority> n=<times> e=<effect> <text>`
+    pub fn decode(version: u8, line: &str, now: u64) -> Option<Self> {
+        match version {
+            1 => Self::parse(line, now),
+            2 => {
+                let (mut message, content) = Self::parse_options(line, now)?;
+                message.content = Content::parse(content)?;
+                Some(message)
+            }
+            _ => None,
+        }
+    }
+
+    /// e.g. `p=250 n=3 e=alert t=42 text FIRE DRILL`, the content as
+    /// `Content::parse` takes it. `t` is the time left at `now`
+    pub fn write(&self, out: &mut impl Write, now: u64) -> fmt::Result {
+        write!(
+            out,
+            "p={} n={} e={} ",
+            self.priority,
+            self.remaining,
+            self.effect.name()
+        )?;
+        if let Some(t) = self.expires_at {
+            write!(out, "t={} ", t.saturating_sub(now))?;
+        }
+        self.content.write(out)
+    }
+
+    /// one line for the queue status, e.g. `3 p=100 t=42 n=1 HELLO`
+    pub fn write_status(&self, out: &mut impl Write, now: u64) -> fmt::Result {
+        write!(out, "{} p={} ", self.id, self.priority)?;
+        if let Some(t) = self.expires_at {
+            write!(out, "t={} ", t.saturating_sub(now))?;
+        }
+        write!(out, "n={} e={} ", self.remaining, self.effect.name())?;
+        match &self.content {
+            Content::Text(text) => out.write_str(text),
+            Content::Image(_) => out.write_str("<image>"),
+            _ => out.write_str("<other>"),
+        }
+    }
+}
+
+/// the message's line at `now`, and the newline after it, or nothing if
+/// it isn't saved. it only gets shorter as time goes on and showings are
+/// used up
+fn encoded_len(message: &Message, now: u64) -> usize {
+    if !message.saved {
+        return 0;
+    }
+    let mut line: String<MAX_LINE_LEN> = String::new();
+    match message.write(&mut line, now) {
+        Ok(()) => line.len() + 1,
+        Err(_) => usize::MAX,
+    }
+}
+
+/// messages waiting to be shown, most important first. like `Playlist`,
+/// time only comes in through the `now` arguments (uptime in seconds)
+pub struct MessageQueue {
+    /// in the order they were queued
+    messages: Vec<Message, MAX_MESSAGES>,
+    next_id: u16,
+}
+
+impl Default for MessageQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageQueue {
+    pub const fn new() -> Self {
+        Self {
+            messages: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// the messages that are kept across reboots
+    pub fn saved(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter().filter(|m| m.saved)
+    }
+
+    fn drop_expired(&mut self, now: u64) {
+        self.messages.retain(|m| !m.expired(now));
+    }
+
+    /// queues a message and returns its id. when the queue is full, or
+    /// the message wouldn't fit in what's saved of it, the least important
+    /// messages make room if they're less important than this one
+    pub fn push(
+        &mut self,
+        mut message: Message,
+        now: u64,
+    ) -> Result<u16, crate::Error> {
+        self.drop_expired(now);
+
+        // the newest of the least important go first
+        let mut order: Vec<usize, MAX_MESSAGES> =
+            (0..self.messages.len()).collect();
+        order.sort_unstable_by_key(|&i| {
+            (self.messages[i].priority, core::cmp::Reverse(i))
+        });
+        let mut count = self.messages.len() + 1;
+        let mut len = self.encoded_len(now) + encoded_len(&message, now);
+        let mut evict = 0;
+        while count > MAX_MESSAGES || len > MAX_ENCODED_LEN {
+            let lowest = order
+                .get(evict)
+                .map(|&i| &self.messages[i])
+                .ok_or(crate::Error::QueueFull)?;
+            if lowest.priority >= message.priority {
+                return Err(crate::Error::QueueFull);
+            }
+            count -= 1;
+            len -= encoded_len(lowest, now);
+            evict += 1;
+        }
+        let evicted: Vec<u16, MAX_MESSAGES> = order[..evict]
+            .iter()
+            .map(|&i| self.messages[i].id)
+            .collect();
+        self.messages.retain(|m| !evicted.contains(&m.id));
+
+        message.id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let id = message.id;
+        let _ = self.messages.push(message);
+        Ok(id)
+    }
+
+    /// how long the messages are written out one per line, like config
+    /// does
+    fn encoded_len(&self, now: u64) -> usize {
+        self.messages.iter().map(|m| encoded_len(m, now)).sum()
+    }
+
+    /// the most important message that's still valid, oldest first among
+    /// equals. it stays queued until it's been `shown`, so one that gets
+    /// cut short is up again once whatever cut in is done
+    pub fn next(&mut self, now: u64) -> Option<Message> {
+        self.drop_expired(now);
+
+        self.messages
+            .iter()
+            .rev()
+            .max_by_key(|m| m.priority)
+            .cloned()
+    }
+
+    /// counts a showing of message `id` all the way through. messages with
+    /// more showings left go to the back
+    pub fn shown(&mut self, id: u16) {
+        let Some(index) = self.messages.iter().position(|m| m.id == id) else {
+            // removed while it was showing
+            return;
+        };
+        let mut message = self.messages.remove(index);
+        message.remaining = message.remaining.saturating_sub(1);
+        if message.remaining > 0 {
+            let _ = self.messages.push(message);
+        }
+    }
+
+    /// whether an urgent message is waiting
+    pub fn has_urgent(&self, now: u64) -> bool {
+        self.messages
+            .iter()
+            .any(|m| m.is_urgent() && !m.expired(now))
+    }
+
+    pub fn remove(&mut self, id: u16) -> Result<Message, crate::Error> {
+        let index = self
+            .messages
+            .iter()
+            .position(|m| m.id == id)
+            .ok_or(crate::Error::OutOfRange)?;
+        Ok(self.messages.remove(index))
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: &Message) -> &str {
+        match &message.content {
+            Content::Text(text) => text,
+            _ => "<not text>",
+        }
+    }
+
+    /// shows whatever is next all the way through, `n` times
+    fn show(
+        queue: &mut MessageQueue,
+        now: u64,
+        n: usize,
+    ) -> std::vec::Vec<String<8>> {
+        let mut shown = std::vec::Vec::new();
+        for _ in 0..n {
+            let text = match queue.next(now) {
+                Some(message) => {
+                    queue.shown(message.id);
+                    String::try_from(text(&message)).unwrap()
+                }
+                None => String::try_from("-").unwrap(),
+            };
+            shown.push(text);
+        }
+        shown
+    }
+
+    fn push(queue: &mut MessageQueue, spec: &str, now: u64) -> u16 {
+        queue.push(Message::parse(spec, now).unwrap(), now).unwrap()
+    }
+
+    #[test]
+    fn messages_parse_and_write_back() {
+        let message =
+            Message::parse("p=250 t=60 n=3 e=scroll FIRE DRILL", 10).unwrap();
+        assert_eq!(message.priority, 250);
+        assert_eq!(message.expires_at, Some(70));
+        assert_eq!(message.remaining, 3);
+        assert_eq!(message.effect, Effect::Scroll);
+        assert_eq!(text(&message), "FIRE DRILL");
+        assert!(message.is_urgent());
+
+        let plain = Message::parse("HELLO p=1", 0).unwrap();
+        assert_eq!((plain.priority, plain.remaining), (NORMAL, 1));
+        assert_eq!(text(&plain), "HELLO p=1");
+        let mut out = String::<64>::new();
+        plain.write(&mut out, 0).unwrap();
+        assert_eq!(out, "p=100 n=1 e=alert text HELLO p=1");
+
+        for spec in ["", "p=1", "n=0 HI", "p=300 HI", "e=blink HI"] {
+            assert_eq!(Message::parse(spec, 0), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn stored_messages_read_back() {
+        let mut message = Message::parse("p=250 t=60 n=3 FIRE", 10).unwrap();
+        message.id = 7;
+        let mut line = String::<64>::new();
+        message.write(&mut line, 40).unwrap();
+        assert_eq!(line, "p=250 n=3 e=alert t=30 text FIRE");
+        // a reboot later, with the time that was left
+        let back = Message::decode(2, &line, 5).unwrap();
+        assert_eq!(back.expires_at, Some(35));
+        assert_eq!(back.content, message.content);
+        assert_eq!((back.priority, back.remaining), (250, 3));
+
+        let mut hex = String::<64>::new();
+        for _ in 0..64 {
+            hex.push('f').unwrap();
+        }
+        let image = Content::parse(&std::format!("image {}", hex)).unwrap();
+        let picture = Message::new(image, URGENT);
+        let mut line = String::<128>::new();
+        picture.write(&mut line, 0).unwrap();
+        assert_eq!(
+            Message::decode(2, &line, 0).unwrap().content,
+            picture.content
+        );
+    }
+
+    #[test]
+    fn version_1_messages_are_text() {
+        let v1 = Message::decode(1, "p=150 n=2 e=scroll text me", 0).unwrap();
+        assert_eq!(text(&v1), "text me");
+        assert_eq!((v1.priority, v1.remaining), (150, 2));
+        assert_eq!(v1.effect, Effect::Scroll);
+        // the same line is a text message in version 2
+        let v2 = Message::decode(2, "p=150 n=2 e=scroll text me", 0).unwrap();
+        assert_eq!(text(&v2), "me");
+        assert_eq!(Message::decode(2, "p=150 n=2 e=scroll me", 0), None);
+        assert_eq!(Message::decode(3, "p=150 n=2 text me", 0), None);
+    }
+
+    #[test]
+    fn most_important_first() {
+        let mut queue = MessageQueue::new();
+        push(&mut queue, "p=10 LOW", 0);
+        push(&mut queue, "p=250 HIGH", 0);
+        push(&mut queue, "p=100 MID", 0);
+        assert!(queue.has_urgent(0));
+        assert_eq!(show(&mut queue, 0, 4), ["HIGH", "MID", "LOW", "-"]);
+        assert!(!queue.has_urgent(0));
+    }
+
+    #[test]
+    fn ties_go_oldest_first() {
+        let mut queue = MessageQueue::new();
+        push(&mut queue, "A", 0);
+        push(&mut queue, "B", 0);
+        assert_eq!(show(&mut queue, 0, 2), ["A", "B"]);
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let mut queue = MessageQueue::new();
+        push(&mut queue, "p=250 t=10 HIGH", 0);
+        push(&mut queue, "t=20 A", 0);
+        push(&mut queue, "B", 0);
+        assert!(!queue.has_urgent(10));
+        assert_eq!(show(&mut queue, 10, 1), ["A"]);
+        assert_eq!(queue.len(), 1);
+        push(&mut queue, "t=5 A", 30);
+        assert_eq!(show(&mut queue, 35, 2), ["B", "-"]);
+    }
+
+    #[test]
+    fn repeats_take_turns() {
+        let mut queue = MessageQueue::new();
+        push(&mut queue, "n=3 A", 0);
+        push(&mut queue, "n=2 B", 0);
+        assert_eq!(show(&mut queue, 0, 6), ["A", "B", "A", "B", "A", "-"]);
+    }
+
+    #[test]
+    fn cut_short_means_shown_again() {
+        let mut queue = MessageQueue::new();
+        let a = push(&mut queue, "n=2 A", 0);
+        // A starts, and an urgent message comes in before it's done
+        assert_eq!(queue.next(0).unwrap().id, a);
+        push(&mut queue, "p=200 HIGH", 1);
+        assert_eq!(show(&mut queue, 1, 4), ["HIGH", "A", "A", "-"]);
+    }
+
+    #[test]
+    fn notices_are_not_saved() {
+        let mut queue = MessageQueue::new();
+        let kept = push(&mut queue, "KEPT", 0);
+        let mut notice = Message::text("NO WIFI", NORMAL).unwrap();
+        notice.saved = false;
+        queue.push(notice, 0).unwrap();
+        assert_eq!(queue.len(), 2);
+        let saved: std::vec::Vec<u16> = queue.saved().map(|m| m.id).collect();
+        assert_eq!(saved, [kept]);
+        assert_eq!(queue.encoded_len(0), encoded_len(&queue.messages()[0], 0));
+    }
+
+    #[test]
+    fn removed_while_showing() {
+        let mut queue = MessageQueue::new();
+        let a = push(&mut queue, "A", 0);
+        assert_eq!(queue.next(0).unwrap().id, a);
+        queue.remove(a).unwrap();
+        queue.shown(a);
+        assert!(queue.is_empty());
+        assert_eq!(queue.remove(a), Err(crate::Error::OutOfRange));
+    }
+
+    #[test]
+    fn no_showings_left_is_not_an_underflow() {
+        let mut queue = MessageQueue::new();
+        let mut message = Message::text("A", NORMAL).unwrap();
+        message.remaining = 0;
+        let id = queue.push(message, 0).unwrap();
+        queue.shown(id);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn a_full_queue_makes_room_for_more_important_messages() {
+        let mut queue = MessageQueue::new();
+        for _ in 0..MAX_MESSAGES {
+            push(&mut queue, "p=50 A", 0);
+        }
+        let full = Message::parse("p=50 B", 0).unwrap();
+        assert_eq!(queue.push(full, 0), Err(crate::Error::QueueFull));
+        let newest = queue.messages().last().unwrap().id;
+        push(&mut queue, "p=100 MID", 0);
+        assert_eq!(queue.len(), MAX_MESSAGES);
+        // the newest of the least important went
+        assert!(queue.messages().iter().all(|m| m.id != newest));
+        assert_eq!(show(&mut queue, 0, 1), ["MID"]);
+    }
+
+    #[test]
+    fn the_queue_never_outgrows_what_can_be_saved() {
+        let long = |p: u8, c: &str| {
+            let text = c.repeat(MAX_TEXT_LEN);
+            Message::parse(&std::format!("p={} t=600 {}", p, text), 0).unwrap()
+        };
+        let mut queue = MessageQueue::new();
+        let fits = MAX_ENCODED_LEN / encoded_len(&long(50, "A"), 0);
+        assert!(fits < MAX_MESSAGES);
+        for _ in 0..fits {
+            queue.push(long(50, "A"), 0).unwrap();
+        }
+        // short ones still fit in what's left
+        push(&mut queue, "p=50 S", 0);
+        assert!(queue.encoded_len(0) <= MAX_ENCODED_LEN);
+
+        // refused instead of queued and then not saved
+        let refused = queue.push(long(50, "B"), 0);
+        assert_eq!(refused, Err(crate::Error::QueueFull));
+        assert_eq!(queue.len(), fits + 1);
+
+        // a more important one pushes out as many as it takes, newest of
+        // the least important first
+        let first = queue.messages()[0].id;
+        queue.push(long(100, "C"), 0).unwrap();
+        assert!(queue.encoded_len(0) <= MAX_ENCODED_LEN);
+        assert_eq!(queue.len(), fits);
+        assert_eq!(queue.messages()[0].id, first);
+        assert!(queue.messages().iter().all(|m| text(m) != "S"));
+        assert_eq!(text(queue.messages().last().unwrap()).len(), MAX_TEXT_LEN);
+
+        // and nothing is dropped on the way to being saved
+        let mut saved = std::string::String::new();
+        for message in queue.messages() {
+            message.write(&mut saved, 0).unwrap();
+            saved.push('\n');
+        }
+        assert!(saved.len() <= MAX_ENCODED_LEN);
+    }
+
+    #[test]
+    fn nothing_is_pushed_out_for_a_message_that_cant_fit() {
+        let long = "A".repeat(MAX_TEXT_LEN);
+        let mut queue = MessageQueue::new();
+        push(&mut queue, "p=10 SHORT", 0);
+        for p in [11, 11, 12] {
+            push(&mut queue, &std::format!("p={} {}", p, long), 0);
+        }
+        // room for it means pushing out the short one and one that's just
+        // as important, so neither goes
+        let message = Message::parse(&std::format!("p=11 {}", long), 0);
+        let refused = queue.push(message.unwrap(), 0);
+        assert_eq!(refused, Err(crate::Error::QueueFull));
+        assert_eq!(queue.len(), 4);
+        assert_eq!(text(&queue.messages()[0]), "SHORT");
+    }
+}
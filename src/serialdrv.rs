@@ -6,21 +6,30 @@ use embassy_sync::mutex::Mutex;
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
 use embassy_usb::driver::EndpointError;
 use embassy_usb::Config;
+use heapless::Vec;
 
-pub static USB_DRIVER: Mutex<CriticalSectionRawMutex, Option<Driver<USB>>> =
-    Mutex::new(None);
+pub static USB_DRIVER: Mutex<
+    CriticalSectionRawMutex,
+    Option<Driver<'static, USB>>,
+> = Mutex::new(None);
 
-use crate::copy_str_bytes;
-use crate::graphics::{self, Graphic};
-use crate::{DATA, DISPLAYS};
+use crate::commands::{handle_command, CommandStatus};
+use crate::playlist::MAX_TEXT_LEN;
 
 use core::str;
 
+const PACKET_SIZE: usize = 64;
+/// longest command line accepted over serial, the longest message and
+/// room for its options
+const MAX_LINE_LEN: usize = MAX_TEXT_LEN + 64;
+
 // used in setting up usb-serial
 bind_interrupts!(pub struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
+/// a usb serial port that takes the same commands as the tcp server, one
+/// per line. it works without wifi, so it's how a new sign is set up
 #[embassy_executor::task]
 pub async fn setup_serial() {
     let mut config = Config::new(0xc0de, 0xcafe);
@@ -52,7 +61,8 @@ pub async fn setup_serial() {
         &mut control_buf,
     );
 
-    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut class =
+        CdcAcmClass::new(&mut builder, &mut state, PACKET_SIZE as u16);
     let mut usb = builder.build();
 
     let usb_fut = usb.run();
@@ -67,70 +77,52 @@ pub async fn setup_serial() {
     embassy_futures::join::join(usb_fut, serial_loop).await;
 }
 
+async fn write_all<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    data: &[u8],
+) -> Result<(), EndpointError> {
+    for chunk in data.chunks(PACKET_SIZE) {
+        class.write_packet(chunk).await?;
+    }
+    // a full last packet has to be followed by an empty one, or the host
+    // keeps waiting for more
+    if data.len() % PACKET_SIZE == 0 {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
 async fn handle_commands<'d, T: Instance + 'd>(
     class: &mut CdcAcmClass<'d, Driver<'d, T>>,
 ) -> Result<(), EndpointError> {
-    let mut buf = [0; 64];
+    let mut buf = [0; PACKET_SIZE];
+    let mut line: Vec<u8, MAX_LINE_LEN> = Vec::new();
+    // set when a line got too long; the rest of it is skipped
+    let mut overflow = false;
+
+    write_all(class, b"[*] welcome~\n").await?;
     loop {
         let n = class.read_packet(&mut buf).await?;
-        let data = &buf[..n];
-        // find null
-        let mut null_i = n;
-        for i in 0..n {
-            if data[i] == 0 {
-                null_i = i;
+        for &byte in &buf[..n] {
+            if byte != b'\n' && byte != b'\r' {
+                overflow |= line.push(byte).is_err();
+                continue;
             }
-        }
-        // string
-        let string = match str::from_utf8(&data[..null_i]) {
-            Ok(s) => s,
-            Err(_) => continue,
-        };
-
-        let (c, a) = string.split_at(1);
-        match c {
-            "0" => {
-                //echo message
-                let mut alert: [&Graphic; 8] = [&graphics::EMPTY; 8];
-                alert[1] = &graphics::FULL;
-                alert[3] = &graphics::FULL;
-                alert[5] = &graphics::FULL;
-                alert[7] = &graphics::FULL;
-                let panorama = graphics::Panorama {
-                    graphics: alert,
-                    len: 8,
-                };
-                for d in &*DISPLAYS {
-                    d.set_override(true).await;
+            if overflow {
+                let status = CommandStatus::error(crate::Error::Truncated);
+                write_all(class, status.mesg.as_bytes()).await?;
+            } else if let Ok(string) = str::from_utf8(&line) {
+                let string = string.trim();
+                if !string.is_empty() {
+                    let status = handle_command(string).await;
+                    write_all(class, status.mesg.as_bytes()).await?;
                 }
-                DISPLAYS[0].flash(panorama, true).await;
-                DISPLAYS.panorama(a, true).await;
-                for d in &*DISPLAYS {
-                    d.set_override(false).await;
-                }
-            }
-            "1" => {
-                //store clock
-                let clock_data = match copy_str_bytes(a.as_bytes()) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        let _ = class.write_packet(b"ERROR").await;
-                        continue;
-                    }
-                };
-                let mut data = DATA.lock().await;
-                data.clock = Some(clock_data);
-            }
-            "2" => {
-                //store weather
-                let weather_data = match copy_str_bytes(a.as_bytes()) {
-                    Ok(v) => v,
-                    Err(_) => continue,
-                };
-                let mut data = DATA.lock().await;
-                data.weather = Some(weather_data);
+            } else {
+                let status = CommandStatus::error(crate::Error::Utf8);
+                write_all(class, status.mesg.as_bytes()).await?;
             }
-            _ => {}
+            line.clear();
+            overflow = false;
         }
     }
 }
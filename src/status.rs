@@ -0,0 +1,378 @@
+//! what the sign is up to, for the `STATUS` command. the report is one
+//! line of space separated `key=value` pairs, with no spaces in values.
+//!
+//! two things it would be nice to have aren't there. the wifi signal
+//! strength: cyw43 0.1 keeps its ioctls to itself, so `Control` has no
+//! way to ask the chip for it. and the free task arena: embassy-executor
+//! doesn't say how much of it is used, and every task is spawned at boot
+//! anyway, so an arena that's too small panics then rather than running
+//! low later
+
+use core::cell::Cell;
+use core::fmt::{self, Write};
+
+use embassy_net::Ipv4Cidr;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::crash::{Crash, LAST_CRASH};
+use crate::network::{ddp, supervisor};
+use crate::QUEUE;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// the longest report there can be, with a crash message that's all
+/// escapes
+pub const MAX_REPORT_LEN: usize = 768;
+
+/// a render pass longer than this made the next one late
+const RENDER_BUDGET_US: u32 = 500;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Showing {
+    Idle,
+    /// a playlist slot, by content kind
+    Playlist(&'static str),
+    /// a queued message, by id
+    Message(u16),
+}
+
+impl fmt::Display for Showing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle => f.write_str("idle"),
+            Self::Playlist(kind) => write!(f, "playlist:{}", kind),
+            Self::Message(id) => write!(f, "message:{}", id),
+        }
+    }
+}
+
+static SHOWING: Mutex<CriticalSectionRawMutex, Cell<Showing>> =
+    Mutex::new(Cell::new(Showing::Idle));
+/// the address while online
+static ADDRESS: Mutex<CriticalSectionRawMutex, Cell<Option<Ipv4Cidr>>> =
+    Mutex::new(Cell::new(None));
+/// what went wrong last, and when (seconds since boot)
+static LAST_ERROR: Mutex<CriticalSectionRawMutex, Cell<Option<LastError>>> =
+    Mutex::new(Cell::new(None));
+
+type LastError = (u64, &'static str);
+
+/// the average render pass, times 16
+static RENDER_AVG_US_X16: AtomicU32 = AtomicU32::new(0);
+static RENDER_MAX_US: AtomicU32 = AtomicU32::new(0);
+/// render passes that went over `RENDER_BUDGET_US`
+static RENDER_LATE: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_showing(showing: Showing) {
+    SHOWING.lock(|s| s.set(showing));
+}
+
+pub fn set_address(address: Option<Ipv4Cidr>) {
+    ADDRESS.lock(|a| a.set(address));
+}
+
+/// `what` should be short, with no spaces, e.g. `flash-save`
+pub fn record_error(what: &'static str) {
+    let now = Instant::now().as_secs();
+    LAST_ERROR.lock(|e| e.set(Some((now, what))));
+}
+
+/// called by the render loop after every pass
+pub fn record_render(micros: u32) {
+    // only the render loop writes these, so load then store is fine
+    let avg = RENDER_AVG_US_X16.load(Ordering::Relaxed);
+    RENDER_AVG_US_X16.store(avg - avg / 16 + micros, Ordering::Relaxed);
+    if micros > RENDER_MAX_US.load(Ordering::Relaxed) {
+        RENDER_MAX_US.store(micros, Ordering::Relaxed);
+    }
+    if micros > RENDER_BUDGET_US {
+        RENDER_LATE.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// everything in the report, read in one go
+struct Report {
+    uptime: u64,
+    /// None while a ddp stream is showing
+    showing: Option<Showing>,
+    queued: usize,
+    online: bool,
+    address: Option<Ipv4Cidr>,
+    render_avg_us: u32,
+    render_max_us: u32,
+    render_late: u32,
+    error: Option<LastError>,
+    crash: Option<Crash>,
+}
+
+impl Report {
+    fn write(&self, out: &mut impl Write) -> fmt::Result {
+        write!(out, "version={} uptime={} ", VERSION, self.uptime)?;
+        match self.showing {
+            Some(showing) => write!(out, "showing={} ", showing)?,
+            None => out.write_str("showing=stream ")?,
+        }
+        write!(out, "queue={} ", self.queued)?;
+
+        match self.online {
+            true => out.write_str("wifi=up ")?,
+            false => out.write_str("wifi=down ")?,
+        }
+        match self.address {
+            Some(address) => write!(out, "ip={} ", address)?,
+            None => out.write_str("ip=none ")?,
+        }
+
+        write!(
+            out,
+            "render_avg_us={} render_max_us={} render_late={} ",
+            self.render_avg_us, self.render_max_us, self.render_late,
+        )?;
+        match self.error {
+            Some((at, what)) => {
+                write!(out, "error={} error_at={} ", what, at)?
+            }
+            None => out.write_str("error=none ")?,
+        }
+
+        match &self.crash {
+            Some(crash) => {
+                out.write_str("crash=")?;
+                match crash.location() {
+                    "" => out.write_str("unknown")?,
+                    location => write_escaped(out, location)?,
+                }
+                out.write_str(" crash_message=")?;
+                write_escaped(out, crash.message())
+            }
+            None => out.write_str("crash=none"),
+        }
+    }
+}
+
+pub async fn report(out: &mut impl Write) -> fmt::Result {
+    let report = Report {
+        uptime: Instant::now().as_secs(),
+        showing: (!ddp::is_live()).then(|| SHOWING.lock(Cell::get)),
+        queued: QUEUE.lock().await.len(),
+        online: supervisor::ONLINE.load(Ordering::Relaxed),
+        address: ADDRESS.lock(Cell::get),
+        render_avg_us: RENDER_AVG_US_X16.load(Ordering::Relaxed) / 16,
+        render_max_us: RENDER_MAX_US.load(Ordering::Relaxed),
+        render_late: RENDER_LATE.load(Ordering::Relaxed),
+        error: LAST_ERROR.lock(Cell::get),
+        crash: LAST_CRASH.lock().await.clone(),
+    };
+    report.write(out)
+}
+
+/// `%xx` for spaces, `%` and anything that isn't printable ascii, so a
+/// value stays one word
+fn write_escaped(out: &mut impl Write, s: &str) -> fmt::Result {
+    for b in s.bytes() {
+        match b {
+            b'%' | b'=' => write!(out, "%{:02x}", b)?,
+            b if b.is_ascii_graphic() => out.write_char(b as char)?,
+            b => write!(out, "%{:02x}", b)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_net::Ipv4Address;
+
+    fn quiet() -> Report {
+        Report {
+            uptime: 5321,
+            showing: Some(Showing::Idle),
+            queued: 0,
+            online: false,
+            address: None,
+            render_avg_us: 0,
+            render_max_us: 0,
+            render_late: 0,
+            error: None,
+            crash: None,
+        }
+    }
+
+    fn written(report: &Report) -> std::string::String {
+        let mut out = std::string::String::new();
+        report.write(&mut out).unwrap();
+        out
+    }
+
+    /// the report as key, value pairs, checking it's one line of them
+    fn pairs(report: &str) -> std::vec::Vec<(&str, &str)> {
+        assert!(!report.contains(['\n', '\r']), "{:?}", report);
+        report
+            .split(' ')
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').expect(pair);
+                assert!(!value.is_empty() && !value.contains('='), "{}", pair);
+                (key, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nothing_going_on() {
+        assert_eq!(
+            written(&quiet()),
+            std::format!(
+                "version={} uptime=5321 showing=idle queue=0 wifi=down \
+                 ip=none render_avg_us=0 render_max_us=0 render_late=0 \
+                 error=none crash=none",
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn everything_going_on() {
+        let report = Report {
+            showing: Some(Showing::Message(12)),
+            queued: 3,
+            online: true,
+            address: Some(Ipv4Cidr::new(Ipv4Address::new(192, 168, 1, 5), 24)),
+            render_avg_us: 38,
+            render_max_us: 612,
+            render_late: 2,
+            error: Some((4410, "mqtt")),
+            crash: Some(Crash(
+                heapless::String::try_from(
+                    "src/main.rs:120:9 index out of bounds: the len is 4",
+                )
+                .unwrap(),
+            )),
+            ..quiet()
+        };
+        let written = written(&report);
+        let pairs = pairs(&written);
+        let keys: std::vec::Vec<_> = pairs.iter().map(|(k, _)| *k).collect();
+        assert_eq!(
+            keys,
+            [
+                "version",
+                "uptime",
+                "showing",
+                "queue",
+                "wifi",
+                "ip",
+                "render_avg_us",
+                "render_max_us",
+                "render_late",
+                "error",
+                "error_at",
+                "crash",
+                "crash_message",
+            ]
+        );
+        assert_eq!(pairs[2].1, "message:12");
+        assert_eq!(pairs[5].1, "192.168.1.5/24");
+        assert_eq!(pairs[9..11], [("error", "mqtt"), ("error_at", "4410")]);
+        assert_eq!(pairs[11].1, "src/main.rs:120:9");
+        assert_eq!(
+            pairs[12].1,
+            "index%20out%20of%20bounds:%20the%20len%20is%204"
+        );
+    }
+
+    #[test]
+    fn the_longest_report_fits() {
+        let report = Report {
+            uptime: u64::MAX,
+            showing: Some(Showing::Playlist("weather")),
+            queued: usize::MAX,
+            address: Some(Ipv4Cidr::new(
+                Ipv4Address::new(255, 255, 255, 255),
+                32,
+            )),
+            render_avg_us: u32::MAX,
+            render_max_us: u32::MAX,
+            render_late: u32::MAX,
+            error: Some((u64::MAX, "ota-self-test")),
+            crash: Some(Crash(
+                heapless::String::try_from(" ".repeat(160).as_str()).unwrap(),
+            )),
+            ..quiet()
+        };
+        let mut out = heapless::String::<MAX_REPORT_LEN>::new();
+        report.write(&mut out).unwrap();
+        // or with a location that's all escapes
+        let report = Report {
+            crash: Some(Crash(
+                heapless::String::try_from("%".repeat(160).as_str()).unwrap(),
+            )),
+            ..report
+        };
+        let mut out = heapless::String::<MAX_REPORT_LEN>::new();
+        report.write(&mut out).unwrap();
+    }
+
+    #[test]
+    fn streams_and_playlists_show_up() {
+        let stream = Report {
+            showing: None,
+            ..quiet()
+        };
+        assert!(written(&stream).contains(" showing=stream "));
+        let playlist = Report {
+            showing: Some(Showing::Playlist("clock")),
+            ..quiet()
+        };
+        assert!(written(&playlist).contains(" showing=playlist:clock "));
+    }
+
+    #[test]
+    fn escaping_keeps_a_value_one_word() {
+        let escaped = |s: &str| {
+            let mut out = std::string::String::new();
+            write_escaped(&mut out, s).unwrap();
+            out
+        };
+        assert_eq!(escaped(""), "");
+        assert_eq!(escaped("plain-text_1.2:3"), "plain-text_1.2:3");
+        assert_eq!(escaped("a b"), "a%20b");
+        assert_eq!(escaped("100%"), "100%25");
+        assert_eq!(escaped("k=v"), "k%3dv");
+        assert_eq!(escaped("tab\there\r\n"), "tab%09here%0d%0a");
+        assert_eq!(escaped("\x7f"), "%7f");
+        // each byte of a multi-byte char
+        assert_eq!(escaped("é"), "%c3%a9");
+    }
+
+    #[test]
+    fn crash_messages_stay_in_their_value() {
+        let report = Report {
+            crash: Some(Crash(
+                heapless::String::try_from("watchdog render stalled").unwrap(),
+            )),
+            ..quiet()
+        };
+        let written = written(&report);
+        let pairs = pairs(&written);
+        assert_eq!(
+            pairs[pairs.len() - 2..],
+            [("crash", "watchdog"), ("crash_message", "render%20stalled"),]
+        );
+    }
+
+    #[test]
+    fn crash_locations_are_one_word_and_never_empty() {
+        let crash = |text: &str| Report {
+            crash: Some(Crash(heapless::String::try_from(text).unwrap())),
+            ..quiet()
+        };
+        assert!(written(&crash("a=b%\tc oops"))
+            .ends_with(" crash=a%3db%25%09c crash_message=oops"));
+        assert!(written(&crash(" oops"))
+            .ends_with(" crash=unknown crash_message=oops"));
+        assert!(written(&crash("")).ends_with(" crash=unknown crash_message="));
+    }
+}
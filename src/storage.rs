@@ -0,0 +1,615 @@
+//! a small key/value store on a few sectors of nor flash.
+//!
+//! the store is a ring of `PAGES` pages. only one page is active at a
+//! time, and values are appended to it as records; the newest record for
+//! a key wins. when the active page fills up, the newest value of every
+//! key is copied into the next page in the ring, which becomes the active
+//! one. that way erases are spread over all the pages.
+//!
+//! page:   magic (4) | sequence (4) | records...
+//! record: key (1) | version (1) | len (2) | crc32 (4) | value, padded to 4
+//!
+//! all numbers are little endian. a record with an empty value means the
+//! key was removed. a record that doesn't check out (power went while it
+//! was being written) ends the page; the next write compacts past it
+
+use embedded_storage_async::nor_flash::NorFlash;
+
+/// bytes per page. a multiple of the flash's erase size
+pub const PAGE_SIZE: u32 = 2 * 4096;
+pub const PAGES: u32 = 4;
+/// how much flash the store needs, starting at its base offset
+pub const STORE_SIZE: u32 = PAGE_SIZE * PAGES;
+pub const MAX_VALUE_LEN: usize = 2048;
+/// keys are 0 up to (not including) this
+pub const MAX_KEYS: u8 = 16;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"DMKV");
+const PAGE_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: u32 = 8;
+/// records start on multiples of this. the flash's write size must
+/// divide it
+const ALIGN: u32 = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Flash,
+    BadKey,
+    TooLarge,
+    /// everything stored doesn't fit in one page
+    Full,
+}
+
+/// crc-32 (ieee), continuing from `crc`. start from 0
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    key: u8,
+    version: u8,
+    len: u16,
+    crc: u32,
+}
+
+impl Header {
+    fn new(key: u8, version: u8, value: &[u8]) -> Self {
+        let mut header = Self {
+            key,
+            version,
+            len: value.len() as u16,
+            crc: 0,
+        };
+        header.crc = header.checksum(value);
+        header
+    }
+
+    fn checksum(&self, value: &[u8]) -> u32 {
+        let len = self.len.to_le_bytes();
+        let crc = crc32(0, &[self.key, self.version, len[0], len[1]]);
+        crc32(crc, value)
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_HEADER_LEN as usize] {
+        let mut raw = [0; RECORD_HEADER_LEN as usize];
+        raw[0] = self.key;
+        raw[1] = self.version;
+        raw[2..4].copy_from_slice(&self.len.to_le_bytes());
+        raw[4..8].copy_from_slice(&self.crc.to_le_bytes());
+        raw
+    }
+
+    fn from_bytes(raw: &[u8; RECORD_HEADER_LEN as usize]) -> Self {
+        Self {
+            key: raw[0],
+            version: raw[1],
+            len: u16::from_le_bytes([raw[2], raw[3]]),
+            crc: u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]),
+        }
+    }
+
+    /// the whole record, padding included
+    fn size(&self) -> u32 {
+        RECORD_HEADER_LEN + (self.len as u32).next_multiple_of(ALIGN)
+    }
+}
+
+enum Entry {
+    Record(Header),
+    /// erased flash, or the end of the page
+    End,
+    /// a record that doesn't check out
+    Torn,
+}
+
+/// writes a record at `addr`, header first
+async fn program<F: NorFlash>(
+    flash: &mut F,
+    addr: u32,
+    header: &Header,
+    value: &[u8],
+) -> Result<(), Error> {
+    flash
+        .write(addr, &header.to_bytes())
+        .await
+        .map_err(|_| Error::Flash)?;
+
+    let aligned = value.len() / ALIGN as usize * ALIGN as usize;
+    let (body, tail) = value.split_at(aligned);
+    let addr = addr + RECORD_HEADER_LEN;
+    if !body.is_empty() {
+        flash.write(addr, body).await.map_err(|_| Error::Flash)?;
+    }
+    if !tail.is_empty() {
+        let mut padded = [0xff; ALIGN as usize];
+        padded[..tail.len()].copy_from_slice(tail);
+        let addr = addr + aligned as u32;
+        flash.write(addr, &padded).await.map_err(|_| Error::Flash)?;
+    }
+    Ok(())
+}
+
+pub struct Store<F> {
+    flash: F,
+    /// offset of the first page in `flash`
+    base: u32,
+    /// the active page
+    page: u32,
+    seq: u32,
+    /// where the next record goes in the active page
+    end: u32,
+    buf: [u8; MAX_VALUE_LEN],
+}
+
+impl<F: NorFlash> Store<F> {
+    /// opens the store at `base`, formatting it if nothing is there yet
+    pub async fn mount(flash: F, base: u32) -> Result<Self, Error> {
+        let mut store = Self {
+            flash,
+            base,
+            page: 0,
+            seq: 0,
+            end: PAGE_SIZE,
+            buf: [0; MAX_VALUE_LEN],
+        };
+
+        let mut newest: Option<(u32, u32)> = None;
+        for page in 0..PAGES {
+            let mut raw = [0; PAGE_HEADER_LEN as usize];
+            store
+                .flash
+                .read(store.page_addr(page), &mut raw)
+                .await
+                .map_err(|_| Error::Flash)?;
+            let magic = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+            let seq = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+            if magic == MAGIC && newest.is_none_or(|(_, s)| seq > s) {
+                newest = Some((page, seq));
+            }
+        }
+
+        match newest {
+            Some((page, seq)) => {
+                store.page = page;
+                store.seq = seq;
+                store.end = store.scan_end().await?;
+            }
+            None => store.format().await?,
+        }
+        Ok(store)
+    }
+
+    /// erases everything
+    pub async fn format(&mut self) -> Result<(), Error> {
+        let start = self.page_addr(0);
+        self.flash
+            .erase(start, start + STORE_SIZE)
+            .await
+            .map_err(|_| Error::Flash)?;
+        self.page = 0;
+        self.seq = 1;
+        self.write_page_header(0, 1).await?;
+        self.end = PAGE_HEADER_LEN;
+        Ok(())
+    }
+
+    /// the newest value stored under `key`, and the version it was
+    /// written with
+    pub async fn read(
+        &mut self,
+        key: u8,
+    ) -> Result<Option<(u8, &[u8])>, Error> {
+        match self.find(self.page, key).await? {
+            Some(h) if h.len > 0 => {
+                Ok(Some((h.version, &self.buf[..h.len as usize])))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// stores `value` under `key`. an empty value removes the key.
+    /// nothing is written if the value is already stored
+    pub async fn write(
+        &mut self,
+        key: u8,
+        version: u8,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        if key >= MAX_KEYS {
+            return Err(Error::BadKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::TooLarge);
+        }
+
+        let unchanged = match self.find(self.page, key).await? {
+            Some(h) => {
+                (h.version == version || h.len == 0)
+                    && &self.buf[..h.len as usize] == value
+            }
+            None => value.is_empty(),
+        };
+        if unchanged {
+            return Ok(());
+        }
+
+        let header = Header::new(key, version, value);
+        if self.end + header.size() > PAGE_SIZE {
+            self.compact().await?;
+            if self.end + header.size() > PAGE_SIZE {
+                return Err(Error::Full);
+            }
+        }
+        let addr = self.page_addr(self.page) + self.end;
+        // if this fails halfway, the record is torn and the next write
+        // compacts past it
+        self.end = PAGE_SIZE;
+        program(&mut self.flash, addr, &header, value).await?;
+        self.end = addr - self.page_addr(self.page) + header.size();
+        Ok(())
+    }
+
+    pub async fn remove(&mut self, key: u8) -> Result<(), Error> {
+        self.write(key, 0, &[]).await
+    }
+
+    fn page_addr(&self, page: u32) -> u32 {
+        self.base + page * PAGE_SIZE
+    }
+
+    async fn write_page_header(
+        &mut self,
+        page: u32,
+        seq: u32,
+    ) -> Result<(), Error> {
+        // the sequence number first: a page with the magic is in use, so
+        // the magic can't go in before what comes after it
+        let addr = self.page_addr(page);
+        self.flash
+            .write(addr + 4, &seq.to_le_bytes())
+            .await
+            .map_err(|_| Error::Flash)?;
+        self.flash
+            .write(addr, &MAGIC.to_le_bytes())
+            .await
+            .map_err(|_| Error::Flash)
+    }
+
+    /// reads the record at `offset` in `page`. its value is left in `buf`
+    async fn entry(&mut self, page: u32, offset: u32) -> Result<Entry, Error> {
+        if offset + RECORD_HEADER_LEN > PAGE_SIZE {
+            return Ok(Entry::End);
+        }
+        let addr = self.page_addr(page) + offset;
+        let mut raw = [0; RECORD_HEADER_LEN as usize];
+        self.flash
+            .read(addr, &mut raw)
+            .await
+            .map_err(|_| Error::Flash)?;
+        if raw == [0xff; RECORD_HEADER_LEN as usize] {
+            return Ok(Entry::End);
+        }
+
+        let header = Header::from_bytes(&raw);
+        let len = header.len as usize;
+        if header.key >= MAX_KEYS
+            || len > MAX_VALUE_LEN
+            || offset + header.size() > PAGE_SIZE
+        {
+            return Ok(Entry::Torn);
+        }
+        self.flash
+            .read(addr + RECORD_HEADER_LEN, &mut self.buf[..len])
+            .await
+            .map_err(|_| Error::Flash)?;
+        if header.checksum(&self.buf[..len]) != header.crc {
+            return Ok(Entry::Torn);
+        }
+        Ok(Entry::Record(header))
+    }
+
+    /// the newest record for `key` in `page`. its value is left in `buf`
+    async fn find(
+        &mut self,
+        page: u32,
+        key: u8,
+    ) -> Result<Option<Header>, Error> {
+        let mut found = None;
+        let mut offset = PAGE_HEADER_LEN;
+        while let Entry::Record(h) = self.entry(page, offset).await? {
+            if h.key == key {
+                found = Some((offset, h));
+            }
+            offset += h.size();
+        }
+
+        let Some((offset, header)) = found else {
+            return Ok(None);
+        };
+        // the scan went past it, so read the value again
+        let addr = self.page_addr(page) + offset + RECORD_HEADER_LEN;
+        self.flash
+            .read(addr, &mut self.buf[..header.len as usize])
+            .await
+            .map_err(|_| Error::Flash)?;
+        Ok(Some(header))
+    }
+
+    /// where free space starts in the active page, or `PAGE_SIZE` if a
+    /// torn record means it has to be compacted before the next write
+    async fn scan_end(&mut self) -> Result<u32, Error> {
+        let mut offset = PAGE_HEADER_LEN;
+        loop {
+            match self.entry(self.page, offset).await? {
+                Entry::Record(h) => offset += h.size(),
+                Entry::End => return Ok(offset),
+                Entry::Torn => return Ok(PAGE_SIZE),
+            }
+        }
+    }
+
+    /// copies the newest value of every key into the next page, and makes
+    /// it the active one. its page header is written last, so if power
+    /// goes in the middle, the old page is still the active one on the
+    /// next boot
+    async fn compact(&mut self) -> Result<(), Error> {
+        let next = (self.page + 1) % PAGES;
+        let start = self.page_addr(next);
+        self.flash
+            .erase(start, start + PAGE_SIZE)
+            .await
+            .map_err(|_| Error::Flash)?;
+
+        let mut offset = PAGE_HEADER_LEN;
+        for key in 0..MAX_KEYS {
+            let header = match self.find(self.page, key).await? {
+                Some(h) if h.len > 0 => h,
+                _ => continue,
+            };
+            let value = &self.buf[..header.len as usize];
+            program(&mut self.flash, start + offset, &header, value).await?;
+            offset += header.size();
+        }
+
+        self.write_page_header(next, self.seq + 1).await?;
+        self.page = next;
+        self.seq += 1;
+        self.end = offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_flash::MockFlash;
+    use embassy_futures::block_on;
+    use std::vec::Vec;
+
+    /// somewhere that isn't the start of the flash
+    const BASE: u32 = 4096;
+
+    fn flash() -> MockFlash {
+        MockFlash::new((BASE + STORE_SIZE) as usize)
+    }
+
+    fn mount(flash: &MockFlash) -> Store<MockFlash> {
+        block_on(Store::mount(flash.clone(), BASE)).unwrap()
+    }
+
+    fn read(store: &mut Store<MockFlash>, key: u8) -> Option<(u8, Vec<u8>)> {
+        let value = block_on(store.read(key)).unwrap();
+        value.map(|(version, bytes)| (version, bytes.to_vec()))
+    }
+
+    fn write(store: &mut Store<MockFlash>, key: u8, value: &[u8]) {
+        block_on(store.write(key, 1, value)).unwrap();
+    }
+
+    /// where the active page starts in the flash
+    fn active_page(store: &Store<MockFlash>) -> u32 {
+        store.page_addr(store.page)
+    }
+
+    #[test]
+    fn crc32_is_the_usual_one() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn values_survive_a_remount() {
+        let flash = flash();
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 0), None);
+        write(&mut store, 0, b"hello");
+        block_on(store.write(3, 7, b"versioned")).unwrap();
+        write(&mut store, 0, b"hello again");
+
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 0), Some((1, b"hello again".to_vec())));
+        assert_eq!(read(&mut store, 3), Some((7, b"versioned".to_vec())));
+
+        block_on(store.remove(0)).unwrap();
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 0), None);
+        assert!(read(&mut store, 3).is_some());
+    }
+
+    #[test]
+    fn what_doesnt_go_in() {
+        let mut store = mount(&flash());
+        let big = [0; MAX_VALUE_LEN + 1];
+        assert_eq!(block_on(store.write(0, 1, &big)), Err(Error::TooLarge));
+        assert_eq!(
+            block_on(store.write(MAX_KEYS, 1, b"x")),
+            Err(Error::BadKey)
+        );
+    }
+
+    #[test]
+    fn the_same_value_isnt_written_twice() {
+        let flash = flash();
+        let mut store = mount(&flash);
+        write(&mut store, 1, b"same");
+        let writes = flash.writes();
+        write(&mut store, 1, b"same");
+        block_on(store.remove(2)).unwrap();
+        assert_eq!(flash.writes(), writes);
+        // but a new version of it is
+        block_on(store.write(1, 2, b"same")).unwrap();
+        assert!(flash.writes() > writes);
+    }
+
+    #[test]
+    fn full_pages_roll_over_to_the_next() {
+        let flash = flash();
+        let mut store = mount(&flash);
+        write(&mut store, 5, b"kept all along");
+        let mut pages = Vec::new();
+        // enough to go round the ring a few times
+        for i in 0..(PAGES * PAGE_SIZE / 1000 * 3) {
+            let value = [i as u8; 1000];
+            write(&mut store, 0, &value);
+            if pages.last() != Some(&store.page) {
+                pages.push(store.page);
+            }
+            let mut store = mount(&flash);
+            assert_eq!(read(&mut store, 0), Some((1, value.to_vec())));
+        }
+        assert_eq!(pages[..PAGES as usize + 1], [0, 1, 2, 3, 0]);
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 5), Some((1, b"kept all along".to_vec())));
+
+        // and every page took its share of the erases
+        let erases = flash.erases();
+        let per_page = PAGE_SIZE as usize / MockFlash::ERASE_SIZE;
+        let store_blocks = &erases[BASE as usize / MockFlash::ERASE_SIZE..];
+        let counts: Vec<usize> =
+            store_blocks.chunks(per_page).map(|page| page[0]).collect();
+        let (min, max) = (counts.iter().min(), counts.iter().max());
+        assert!(max.unwrap() - min.unwrap() <= 1, "{:?}", counts);
+    }
+
+    #[test]
+    fn everything_has_to_fit_in_one_page() {
+        let mut store = mount(&flash());
+        for key in 0..3 {
+            write(&mut store, key, &[key; MAX_VALUE_LEN]);
+        }
+        let last = [3; MAX_VALUE_LEN];
+        assert_eq!(block_on(store.write(3, 1, &last)), Err(Error::Full));
+        // and the rest is still there
+        for key in 0..3 {
+            assert_eq!(read(&mut store, key).unwrap().1, [key; MAX_VALUE_LEN]);
+        }
+    }
+
+    #[test]
+    fn a_torn_record_is_skipped_and_cleaned_up() {
+        // power goes while the header is written, or the value
+        for writes in [0, 1] {
+            let flash = flash();
+            let mut store = mount(&flash);
+            write(&mut store, 0, b"old value");
+            write(&mut store, 1, b"other");
+
+            flash.lose_power_after(writes);
+            let torn = block_on(store.write(0, 1, &[7; 100]));
+            assert_eq!(torn, Err(Error::Flash));
+
+            let mut store = mount(&flash);
+            assert_eq!(read(&mut store, 0), Some((1, b"old value".to_vec())));
+            assert_eq!(read(&mut store, 1), Some((1, b"other".to_vec())));
+            // the next write moves past it to a clean page
+            let page = store.page;
+            write(&mut store, 2, b"new");
+            assert_ne!(store.page, page);
+
+            let mut store = mount(&flash);
+            assert_eq!(read(&mut store, 0), Some((1, b"old value".to_vec())));
+            assert_eq!(read(&mut store, 2), Some((1, b"new".to_vec())));
+        }
+    }
+
+    #[test]
+    fn a_bad_crc_is_as_good_as_torn() {
+        let flash = flash();
+        let mut store = mount(&flash);
+        write(&mut store, 0, b"first");
+        let addr = active_page(&store) + store.end;
+        write(&mut store, 0, b"second");
+        // a bit in the value
+        flash.corrupt(addr + RECORD_HEADER_LEN + 2, 0x04);
+
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 0), Some((1, b"first".to_vec())));
+        write(&mut store, 1, b"x");
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 0), Some((1, b"first".to_vec())));
+        assert_eq!(read(&mut store, 1), Some((1, b"x".to_vec())));
+
+        // or in the header
+        let addr = active_page(&store) + store.end;
+        write(&mut store, 1, b"y");
+        flash.corrupt(addr + 2, 0x01);
+        let mut store = mount(&flash);
+        assert_eq!(read(&mut store, 1), Some((1, b"x".to_vec())));
+    }
+
+    #[test]
+    fn power_lost_while_compacting() {
+        // a page with no room left for another 1000 bytes
+        let flash = flash();
+        let mut store = mount(&flash);
+        write(&mut store, 1, b"one");
+        write(&mut store, 2, &[2; 500]);
+        let mut fill = 0;
+        while store.end + 1008 <= PAGE_SIZE {
+            fill += 1;
+            write(&mut store, 0, &[fill; 1000]);
+        }
+
+        // the power going at any write along the way: three records, then
+        // the page header in two
+        for writes in 0..8 {
+            let flash = flash.copy();
+            let mut store = mount(&flash);
+            let page = store.page;
+            flash.lose_power_after(writes);
+            let done = block_on(store.write(0, 1, &[9; 1000])).is_ok();
+
+            let mut store = mount(&flash);
+            let expected = match done {
+                true => [9; 1000],
+                false => {
+                    // the old page is still the one in use
+                    assert_eq!(store.page, page, "{}", writes);
+                    [fill; 1000]
+                }
+            };
+            assert_eq!(read(&mut store, 0).unwrap().1, expected);
+            assert_eq!(read(&mut store, 1).unwrap().1, b"one");
+            assert_eq!(read(&mut store, 2).unwrap().1, [2; 500]);
+        }
+    }
+
+    #[test]
+    fn an_unformatted_flash_is_formatted() {
+        let flash = flash();
+        flash.corrupt(BASE, 0x55);
+        let mut store = mount(&flash);
+        assert_eq!(store.page, 0);
+        write(&mut store, 0, b"x");
+        assert_eq!(read(&mut mount(&flash), 0), Some((1, b"x".to_vec())));
+    }
+}
@@ -0,0 +1,174 @@
+//! an embassy-time driver for host tests that only moves when told to.
+//! every test thread has a clock of its own, starting at 0, so tests
+//! running side by side can't get in each other's way. `run` jumps
+//! straight to whatever the future waits for next, so a minute of
+//! animation takes no time at all
+
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::Wake;
+use std::vec::Vec;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embassy_time_driver::{AlarmHandle, Driver};
+use embassy_time_queue_driver::TimerQueue;
+
+thread_local! {
+    static NOW: Cell<u64> = const { Cell::new(0) };
+    /// who to wake, and when
+    static TIMERS: RefCell<Vec<(u64, Waker)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+struct VirtualClock;
+
+impl Driver for VirtualClock {
+    fn now(&self) -> u64 {
+        NOW.get()
+    }
+
+    // timers go straight to the queue below, which needs no alarms
+    unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+        None
+    }
+
+    fn set_alarm_callback(&self, _: AlarmHandle, _: fn(*mut ()), _: *mut ()) {}
+
+    fn set_alarm(&self, _: AlarmHandle, _: u64) -> bool {
+        false
+    }
+}
+
+embassy_time_driver::time_driver_impl!(
+    static CLOCK: VirtualClock = VirtualClock
+);
+
+struct VirtualQueue;
+
+impl TimerQueue for VirtualQueue {
+    fn schedule_wake(&'static self, at: u64, waker: &Waker) {
+        TIMERS.with_borrow_mut(|timers| timers.push((at, waker.clone())));
+    }
+}
+
+embassy_time_queue_driver::timer_queue_impl!(
+    static QUEUE: VirtualQueue = VirtualQueue
+);
+
+/// moves this thread's clock on to `at`, waking every timer due by then
+fn set_now(at: u64) {
+    NOW.set(NOW.get().max(at));
+    let due: Vec<Waker> = TIMERS.with_borrow_mut(|timers| {
+        let now = NOW.get();
+        let (due, later) = timers.drain(..).partition(|(t, _)| *t <= now);
+        *timers = later;
+        due.into_iter().map(|(_, waker)| waker).collect()
+    });
+    due.into_iter().for_each(Waker::wake);
+}
+
+pub fn advance(duration: Duration) {
+    set_now(NOW.get() + duration.as_ticks());
+}
+
+/// the earliest anything is waiting for, if anything is
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS
+        .with_borrow(|timers| timers.iter().map(|(at, _)| *at).min())
+        .map(Instant::from_ticks)
+}
+
+#[derive(Default)]
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// runs `future` to the end. it's polled again straight away when it
+/// wakes itself up, and otherwise the clock jumps to the next timer.
+/// `polled` gets a look after every poll, e.g. to see what's on the
+/// displays
+pub fn run<F: Future>(future: F, mut polled: impl FnMut()) -> F::Output {
+    let mut future = pin!(future);
+    let woken = Arc::new(Flag::default());
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        woken.0.store(false, Ordering::Relaxed);
+        let poll = future.as_mut().poll(&mut cx);
+        polled();
+        if let Poll::Ready(output) = poll {
+            return output;
+        }
+        if woken.0.load(Ordering::Relaxed) {
+            continue;
+        }
+        let next = next_deadline().expect("waiting on nothing, forever");
+        set_now(next.as_ticks());
+    }
+}
+
+#[test]
+fn timers_fire_on_time_without_waiting() {
+    let woke_at = run(
+        async {
+            Timer::after_secs(3600).await;
+            Instant::now()
+        },
+        || {},
+    );
+    assert_eq!(woke_at, Instant::from_secs(3600));
+}
+
+#[test]
+fn each_thread_has_its_own_clock() {
+    advance(Duration::from_secs(5));
+    let other = std::thread::spawn(Instant::now).join().unwrap();
+    assert_eq!(other, Instant::from_ticks(0));
+    assert_eq!(Instant::now(), Instant::from_secs(5));
+}
+
+#[test]
+fn tickers_step_through_every_tick() {
+    let mut ticks = Vec::new();
+    run(
+        async {
+            let mut ticker = Ticker::every(Duration::from_millis(30));
+            for _ in 0..4 {
+                ticker.next().await;
+                ticks.push(Instant::now().as_millis());
+            }
+        },
+        || {},
+    );
+    assert_eq!(ticks, [30, 60, 90, 120]);
+}
+
+#[test]
+fn a_signal_wins_before_the_timer() {
+    static DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+    let which = run(
+        async {
+            let signal = async {
+                Timer::after_millis(250).await;
+                DONE.signal(());
+                Timer::after_secs(3600).await;
+            };
+            let waiting = select(DONE.wait(), Timer::after_secs(10));
+            select(waiting, signal).await
+        },
+        || {},
+    );
+    assert!(matches!(which, Either::First(Either::First(()))));
+    assert_eq!(Instant::now(), Instant::from_millis(250));
+}
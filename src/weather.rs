@@ -0,0 +1,347 @@
+use core::fmt::{self, Write};
+use core::ops::RangeInclusive;
+
+use heapless::String;
+
+use crate::graphics::{self, Graphic};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl Unit {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c.to_ascii_uppercase() {
+            'C' => Some(Self::Celsius),
+            'F' => Some(Self::Fahrenheit),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(self) -> char {
+        match self {
+            Self::Celsius => 'C',
+            Self::Fahrenheit => 'F',
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Condition {
+    Sun,
+    Cloud,
+    Rain,
+    Snow,
+}
+
+impl Condition {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sun" => Some(Self::Sun),
+            "cloud" => Some(Self::Cloud),
+            "rain" => Some(Self::Rain),
+            "snow" => Some(Self::Snow),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sun => "sun",
+            Self::Cloud => "cloud",
+            Self::Rain => "rain",
+            Self::Snow => "snow",
+        }
+    }
+
+    pub fn icon(self) -> &'static Graphic {
+        match self {
+            Self::Sun => &graphics::ICON_SUN,
+            Self::Cloud => &graphics::ICON_CLOUD,
+            Self::Rain => &graphics::ICON_RAIN,
+            Self::Snow => &graphics::ICON_SNOW,
+        }
+    }
+}
+
+/// a weather report. temperatures are whole degrees in `unit`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Weather {
+    pub temperature: i16,
+    pub unit: Unit,
+    pub condition: Condition,
+    /// percent
+    pub humidity: Option<u8>,
+    /// whatever unit the sender uses; shown as-is
+    pub wind: Option<u16>,
+    pub high: Option<i16>,
+    pub low: Option<i16>,
+}
+
+/// what fits on the three panels after the icon
+const DRAWABLE: RangeInclusive<i16> = -99..=999;
+
+fn convert(degrees: i16, from: Unit, to: Unit) -> i16 {
+    let d = degrees as i32;
+    // rounded to the nearest degree
+    let converted = match (from, to) {
+        (Unit::Celsius, Unit::Fahrenheit) => (d * 18 + 320 + 5).div_euclid(10),
+        (Unit::Fahrenheit, Unit::Celsius) => {
+            ((d - 32) * 10 + (d - 32).signum() * 9) / 18
+        }
+        _ => d,
+    };
+    let clamped = if converted < 0 { i16::MIN } else { i16::MAX };
+    i16::try_from(converted).unwrap_or(clamped)
+}
+
+/// whether `degrees` can be shown in either unit, since the unit to show
+/// can change after the report comes in
+fn drawable(degrees: i16, unit: Unit) -> bool {
+    [Unit::Celsius, Unit::Fahrenheit]
+        .into_iter()
+        .all(|to| DRAWABLE.contains(&convert(degrees, unit, to)))
+}
+
+impl Weather {
+    pub fn to_unit(self, unit: Unit) -> Self {
+        let c = |d| convert(d, self.unit, unit);
+        Self {
+            temperature: c(self.temperature),
+            unit,
+            high: self.high.map(c),
+            low: self.low.map(c),
+            ..self
+        }
+    }
+
+    /// parses the compact encoding:
+    /// `<temp><C|F>,<condition>[,<humidity>[,<wind>[,<high>[,<low>]]]]`
+    /// e.g. `23C,sun,45,12,25,18`. optional fields may be left empty.
+    /// temperatures have to fit on the panels in both units, so -73 to
+    /// 537C and -99 to 999F
+    pub fn decode(s: &str) -> Option<Self> {
+        let mut fields = s.split(',').map(str::trim);
+
+        // the unit is the last char, whatever its length in bytes
+        let mut temp = fields.next()?.chars();
+        let unit = Unit::from_char(temp.next_back()?)?;
+        let temperature = temp.as_str().parse().ok()?;
+        let condition = Condition::from_name(fields.next()?)?;
+
+        fn optional<T: core::str::FromStr>(
+            field: Option<&str>,
+        ) -> Option<Option<T>> {
+            match field {
+                None | Some("") => Some(None),
+                Some(f) => f.parse().ok().map(Some),
+            }
+        }
+        let humidity = optional(fields.next())?;
+        let wind = optional(fields.next())?;
+        let high = optional(fields.next())?;
+        let low = optional(fields.next())?;
+        if fields.next().is_some() {
+            return None;
+        }
+        let temperatures = [Some(temperature), high, low];
+        if !temperatures
+            .into_iter()
+            .flatten()
+            .all(|t| drawable(t, unit))
+        {
+            return None;
+        }
+
+        Some(Self {
+            temperature,
+            unit,
+            condition,
+            humidity,
+            wind,
+            high,
+            low,
+        })
+    }
+
+    /// the inverse of `decode`
+    pub fn encode(&self, out: &mut impl Write) -> fmt::Result {
+        write!(
+            out,
+            "{}{},{}",
+            self.temperature,
+            self.unit.as_char(),
+            self.condition.name()
+        )?;
+        fn field(
+            out: &mut impl Write,
+            v: Option<impl fmt::Display>,
+        ) -> fmt::Result {
+            match v {
+                Some(v) => write!(out, ",{}", v),
+                None => out.write_char(','),
+            }
+        }
+        field(out, self.humidity)?;
+        field(out, self.wind)?;
+        field(out, self.high)?;
+        field(out, self.low)
+    }
+
+    /// icon on the first panel, and the temperature on the other three.
+    /// the unit is dropped if it doesn't fit
+    pub fn frame(&self) -> [&'static Graphic; 4] {
+        let mut temp: String<8> = String::new();
+        let _ = write!(temp, "{}", self.temperature);
+        if temp.len() < 3 {
+            let _ = temp.push(self.unit.as_char());
+        }
+
+        let mut frame = [&graphics::EMPTY; 4];
+        frame[0] = self.condition.icon();
+        for (g, c) in frame[1..].iter_mut().zip(temp.chars()) {
+            *g = graphics::from_char(c);
+        }
+        frame
+    }
+
+    /// the rest of the report as scrolling text, e.g. `H45% W12 25/18`
+    pub fn details(&self) -> String<32> {
+        let mut out = String::new();
+        if let Some(h) = self.humidity {
+            let _ = write!(out, "H{}% ", h);
+        }
+        if let Some(w) = self.wind {
+            let _ = write!(out, "W{} ", w);
+        }
+        match (self.high, self.low) {
+            (Some(h), Some(l)) => {
+                let _ = write!(out, "{}/{}", h, l);
+            }
+            (Some(h), None) => {
+                let _ = write!(out, "HI{}", h);
+            }
+            (None, Some(l)) => {
+                let _ = write!(out, "LO{}", l);
+            }
+            (None, None) => {}
+        }
+        let len = out.trim_end().len();
+        out.truncate(len);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn full_and_short_reports() {
+        let full = Weather::decode("23C,sun,45,12,25,18").unwrap();
+        assert_eq!(full.temperature, 23);
+        assert_eq!(full.unit, Unit::Celsius);
+        assert_eq!(full.condition, Condition::Sun);
+        assert_eq!(full.humidity, Some(45));
+        assert_eq!(full.wind, Some(12));
+        assert_eq!((full.high, full.low), (Some(25), Some(18)));
+
+        let short = Weather::decode(" -4f , snow ,,, 1").unwrap();
+        assert_eq!(short.temperature, -4);
+        assert_eq!(short.unit, Unit::Fahrenheit);
+        assert_eq!((short.humidity, short.wind), (None, None));
+        assert_eq!((short.high, short.low), (Some(1), None));
+    }
+
+    #[test]
+    fn broken_reports() {
+        for report in ["", "23", "C,sun", "23K,sun", "23C", "23C,hail"] {
+            assert_eq!(Weather::decode(report), None, "{}", report);
+        }
+        for report in ["23C,sun,lots", "23C,sun,1,2,3,4,5", "2é,sun"] {
+            assert_eq!(Weather::decode(report), None, "{}", report);
+        }
+    }
+
+    #[test]
+    fn conversions_round_to_the_nearest_degree() {
+        let c = Weather::decode("0C,rain,,,100,-40").unwrap();
+        let f = c.to_unit(Unit::Fahrenheit);
+        assert_eq!(f.temperature, 32);
+        assert_eq!((f.high, f.low), (Some(212), Some(-40)));
+        assert_eq!(f.to_unit(Unit::Celsius), c);
+        assert_eq!(convert(70, Unit::Fahrenheit, Unit::Celsius), 21);
+        assert_eq!(convert(-1, Unit::Celsius, Unit::Fahrenheit), 30);
+        // clamped rather than wrapped around
+        let hot = convert(i16::MAX, Unit::Celsius, Unit::Fahrenheit);
+        assert_eq!(hot, i16::MAX);
+        let cold = convert(i16::MIN, Unit::Celsius, Unit::Fahrenheit);
+        assert_eq!(cold, i16::MIN);
+    }
+
+    #[test]
+    fn only_temperatures_that_can_be_drawn() {
+        for report in ["-99F,sun", "999F,sun", "-73C,sun", "537C,sun"] {
+            let weather = Weather::decode(report).unwrap();
+            let f = weather.to_unit(Unit::Fahrenheit).frame();
+            let c = weather.to_unit(Unit::Celsius).frame();
+            assert!(f[1..]
+                .iter()
+                .chain(&c[1..])
+                .all(|g| *g != &graphics::EMPTY));
+        }
+        for report in ["-100F,sun", "1000F,sun", "-74C,sun", "538C,sun"] {
+            assert_eq!(Weather::decode(report), None, "{}", report);
+        }
+        for report in ["20000C,sun", "23C,sun,,,1000", "23C,sun,,,,-100"] {
+            assert_eq!(Weather::decode(report), None, "{}", report);
+        }
+    }
+
+    fn condition() -> impl Strategy<Value = Condition> {
+        prop::sample::select(vec![
+            Condition::Sun,
+            Condition::Cloud,
+            Condition::Rain,
+            Condition::Snow,
+        ])
+    }
+
+    proptest! {
+        #[test]
+        fn reports_round_trip(
+            temperature in -73i16..=537,
+            fahrenheit in any::<bool>(),
+            condition in condition(),
+            humidity in any::<Option<u8>>(),
+            wind in any::<Option<u16>>(),
+            high in prop::option::of(-73i16..=537),
+            low in prop::option::of(-73i16..=537),
+        ) {
+            let unit = match fahrenheit {
+                true => Unit::Fahrenheit,
+                false => Unit::Celsius,
+            };
+            let weather = Weather {
+                temperature,
+                unit,
+                condition,
+                humidity,
+                wind,
+                high,
+                low,
+            };
+            let mut encoded = String::<64>::new();
+            weather.encode(&mut encoded).unwrap();
+            prop_assert_eq!(Weather::decode(&encoded), Some(weather));
+        }
+
+        #[test]
+        fn any_text_decodes_or_not(report in "\\PC{0,40}") {
+            let _ = Weather::decode(&report);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! a sign without the sign: the firmware's command handling, behind the
+//! same command port session the sign has (banner, keys, one command per
+//! read), hanging up after a quiet spell like it does. nothing is drawn,
+//! but the queue, the playlist, the settings and `STATUS` all keep up
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use dotmatrix::auth::{self, Key, Limiter, KEYRING, NONCE_LEN};
+use dotmatrix::network::session::{Session, Step};
+use embassy_futures::block_on;
+
+#[derive(Parser)]
+#[command(version, about = "stands in for a dotmatrix sign")]
+struct Cli {
+    /// 0 picks a free one. the address is printed once it's listening
+    #[arg(long, default_value_t = 1234)]
+    port: u16,
+    /// `<name> <ro|rw> <secret>`, as `kadd` takes it. can be given more
+    /// than once
+    #[arg(long)]
+    key: Vec<String>,
+    /// seconds of quiet before the sign hangs up, like the real one does
+    #[arg(long, default_value_t = 10)]
+    idle: u64,
+}
+
+/// not for anything real, but different every time like the sign's
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    for chunk in nonce.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish();
+        chunk.copy_from_slice(&random.to_le_bytes()[..chunk.len()]);
+    }
+    nonce
+}
+
+fn session(
+    mut socket: TcpStream,
+    limiter: &mut Limiter<IpAddr>,
+    start: Instant,
+) -> std::io::Result<()> {
+    let peer = socket.peer_addr()?.ip();
+    let open = block_on(auth::open());
+    let mut session = Session::new(peer, &nonce(), open, limiter);
+    socket.write_all(session.banner().mesg.as_bytes())?;
+
+    let mut buf = [0; 4096];
+    loop {
+        let n = match socket.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            // quiet for too long
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        };
+        let command = String::from_utf8_lossy(&buf[..n]);
+        if !command.trim().is_empty() {
+            eprintln!("> {}", command.trim());
+        }
+
+        let now = start.elapsed().as_millis() as u64;
+        match block_on(session.read(&buf[..n], now)) {
+            Step::Quiet => {}
+            Step::Reply(reply) => socket.write_all(reply.mesg.as_bytes())?,
+            Step::Last(reply) => {
+                return socket.write_all(reply.mesg.as_bytes());
+            }
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    for key in &cli.key {
+        let Some(key) = Key::parse(key) else {
+            eprintln!("dotmatrix-sim: keys are `<name> <ro|rw> <secret>`");
+            return ExitCode::from(2);
+        };
+        block_on(KEYRING.lock())
+            .add(key)
+            .expect("room for a few keys");
+    }
+
+    let listener = match TcpListener::bind(("127.0.0.1", cli.port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("dotmatrix-sim: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let address = listener.local_addr().expect("it's bound");
+    println!("listening on {}", address);
+
+    let start = Instant::now();
+    let mut limiter = Limiter::new();
+    // one connection at a time, like the sign
+    for socket in listener.incoming() {
+        let Ok(socket) = socket else {
+            continue;
+        };
+        let idle = Some(Duration::from_secs(cli.idle));
+        if socket.set_read_timeout(idle).is_err() {
+            continue;
+        }
+        if let Err(e) = session(socket, &mut limiter, start) {
+            eprintln!("dotmatrix-sim: {}", e);
+        }
+    }
+    ExitCode::SUCCESS
+}
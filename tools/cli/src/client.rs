@@ -0,0 +1,270 @@
+//! the sign's command port, from the other end: a welcome banner (with a
+//! nonce to sign when the sign has keys), then one command and one reply at
+//! a time. the sign takes each read as a whole command, so nothing is sent
+//! until the last reply is in
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use dotmatrix::auth;
+
+pub const PORT: u16 = 1234;
+/// how long the sign gets to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// replies come in one go, so once this passes without another line the
+/// reply is over
+const GAP: Duration = Duration::from_millis(150);
+/// waits before connecting again, doubling each time
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// no answer within `TIMEOUT`
+    Timeout,
+    /// the sign wants a key, as `name:secret`
+    NoKey,
+    /// the sign didn't take the key
+    Auth(String),
+    /// what the sign said when it refused, see "errors" in the README
+    Sign {
+        code: u8,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Timeout => write!(f, "the sign didn't answer"),
+            Self::NoKey => write!(f, "the sign wants a key, pass --key"),
+            Self::Auth(reply) => write!(f, "{}", reply),
+            Self::Sign { code, message } => {
+                write!(f, "error {}: {}", code, message)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+/// `[*] error <code>: <what went wrong> :c`, as the sign writes errors
+fn parse_error(line: &str) -> Option<Error> {
+    let rest = line.strip_prefix("[*] error ")?;
+    let (code, message) = rest.split_once(": ")?;
+    Some(Error::Sign {
+        code: code.parse().ok()?,
+        message: message.trim_end_matches(" :c").to_string(),
+    })
+}
+
+/// a connection that's up and signed in
+struct Connection {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn open(address: &str, key: Option<&str>) -> Result<Self, Error> {
+        let addresses: Vec<_> = address.to_socket_addrs()?.collect();
+        let mut last = io::Error::new(ErrorKind::NotFound, "no address");
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(stream) => return Self::greet(stream, key),
+                Err(e) => last = e,
+            }
+        }
+        Err(last.into())
+    }
+
+    fn greet(stream: TcpStream, key: Option<&str>) -> Result<Self, Error> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut connection = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+        };
+        let banner = connection.read_line()?.ok_or(Error::Timeout)?;
+        if let Some((_, nonce)) = banner.split_once("nonce=") {
+            let key = key.ok_or(Error::NoKey)?;
+            let (name, secret) = key.split_once(':').ok_or(Error::NoKey)?;
+            let tag = auth::sign(secret.as_bytes(), nonce.trim());
+            let mut line = format!("auth {} ", name);
+            for b in tag {
+                line.push_str(&format!("{:02x}", b));
+            }
+            let reply = connection.exchange(&line)?.ok_or(Error::Timeout)?;
+            if !reply.contains("authenticated") {
+                return Err(Error::Auth(reply.trim().to_string()));
+            }
+        }
+        Ok(connection)
+    }
+
+    /// a line, or None if the sign hung up
+    fn read_line(&mut self) -> Result<Option<String>, Error> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+
+    /// sends `command` and reads the whole reply. None if it couldn't be
+    /// written, or the sign hung up before a byte of reply: a connection
+    /// it had already dropped, so it never ran the command
+    fn exchange(&mut self, command: &str) -> Result<Option<String>, Error> {
+        let line = format!("{}\n", command);
+        if self.stream.write_all(line.as_bytes()).is_err() {
+            return Ok(None);
+        }
+        let Some(mut reply) = self.read_line()? else {
+            return Ok(None);
+        };
+        self.stream.set_read_timeout(Some(GAP))?;
+        loop {
+            match self.read_line() {
+                Ok(Some(line)) => reply.push_str(&line),
+                Ok(None) | Err(Error::Timeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.stream.set_read_timeout(Some(TIMEOUT))?;
+        Ok(Some(reply))
+    }
+}
+
+/// a sign, connected to when there's something to send, and again
+/// whenever it drops the connection (it does after 10s of quiet)
+pub struct Sign {
+    address: String,
+    key: Option<String>,
+    /// how many more times to try connecting after the first
+    pub retries: u32,
+    connection: Option<Connection>,
+}
+
+impl Sign {
+    /// `host` may come with a `:port`, otherwise it's 1234. `key` is a
+    /// `name:secret` pair, for signs with keys
+    pub fn new(host: &str, key: Option<&str>) -> Self {
+        let address = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:{}", host, PORT),
+        };
+        Self {
+            address,
+            key: key.map(str::to_string),
+            retries: 3,
+            connection: None,
+        }
+    }
+
+    fn connect(&mut self) -> Result<&mut Connection, Error> {
+        if self.connection.is_none() {
+            let mut delay = RETRY_DELAY;
+            let mut tries = 0;
+            let connection = loop {
+                match Connection::open(&self.address, self.key.as_deref()) {
+                    Ok(connection) => break connection,
+                    // a wrong key won't get any better
+                    Err(Error::Io(_) | Error::Timeout)
+                        if tries < self.retries =>
+                    {
+                        tries += 1;
+                        thread::sleep(delay);
+                        delay *= 2;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("just connected"))
+    }
+
+    /// sends one command and returns the reply, a line or more. errors
+    /// the sign answers with come back as `Error::Sign`
+    pub fn send(&mut self, command: &str) -> Result<String, Error> {
+        // a connection the sign has dropped can still look fine until the
+        // command goes out, so that's sent again. anything else, like a
+        // reset or a late reply, might come after the sign ran it, and
+        // running it twice could add something twice
+        let reply = match self.connect()?.exchange(command) {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) => {
+                self.connection = None;
+                self.connect()?.exchange(command)?.ok_or(Error::Timeout)
+            }
+            Err(e) => Err(e),
+        };
+        let reply = reply.inspect_err(|_| self.connection = None)?;
+        match parse_error(reply.trim_end()) {
+            Some(e) => Err(e),
+            None => Ok(reply),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_read_back() {
+        let e = parse_error("[*] error 5: malformed :c").unwrap();
+        assert!(matches!(
+            e,
+            Error::Sign { code: 5, ref message } if message == "malformed"
+        ));
+        assert_eq!(e.to_string(), "error 5: malformed");
+        assert!(parse_error("[*] queued 3 ^-^").is_none());
+        assert!(parse_error("[*] error x: what :c").is_none());
+    }
+
+    #[test]
+    fn a_command_that_went_out_is_not_sent_again() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.write_all(b"[*] welcome~\n").unwrap();
+            // wait for the command, then hang up without reading it,
+            // which resets the connection
+            while socket.peek(&mut [0]).unwrap() == 0 {}
+            drop(socket);
+            // anyone coming back would be sending it again
+            listener.set_nonblocking(true).unwrap();
+            thread::sleep(Duration::from_millis(500));
+            let again = listener.accept().map(|(mut s, _)| {
+                let mut buf = [0; 64];
+                s.read(&mut buf).unwrap_or(0)
+            });
+            again.is_ok()
+        });
+
+        let mut sign = Sign::new(&address, None);
+        sign.retries = 0;
+        assert!(matches!(sign.send("0hi"), Err(Error::Io(_))));
+        assert!(!server.join().unwrap());
+    }
+
+    #[test]
+    fn the_port_is_1234_unless_given() {
+        assert_eq!(Sign::new("sign.local", None).address, "sign.local:1234");
+        assert_eq!(Sign::new("10.0.0.2:99", None).address, "10.0.0.2:99");
+    }
+}
@@ -0,0 +1,51 @@
+//! finds signs with the same mdns query `dns-sd -B _dotmatrix._tcp` sends
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use dotmatrix::network::mdns::{self, Found};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+/// every sign that answers within `wait`, each once, and where the
+/// answer came from
+pub fn discover(wait: Duration) -> io::Result<Vec<(Found, SocketAddr)>> {
+    // from a port of its own, so the answers come straight back
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(255)?;
+    let mut query = [0; 512];
+    let len = mdns::browse(&mut query).expect("the query fits");
+    socket.send_to(&query[..len], (GROUP, PORT))?;
+
+    let mut signs: Vec<(Found, SocketAddr)> = Vec::new();
+    let mut buf = [0; 1500];
+    let end = Instant::now() + wait;
+    loop {
+        let left = end.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(signs);
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        // anything else on the network is none of our business
+        let Ok(Some(found)) = mdns::read_reply(&buf[..n]) else {
+            continue;
+        };
+        if !signs.iter().any(|(known, _)| known.host == found.host) {
+            signs.push((found, from));
+        }
+    }
+}
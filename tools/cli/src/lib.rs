@@ -0,0 +1,4 @@
+//! the client side of the sign's protocols, shared by the tools in here
+
+pub mod client;
+pub mod discover;
@@ -0,0 +1,324 @@
+//! `dotmatrix-cli`: everything `nc <sign> 1234` does, with options instead
+//! of one-letter commands. see "command line" in the main README
+
+use std::io::{self, BufRead};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use dotmatrix_cli::client::{Error, Sign};
+use dotmatrix_cli::discover;
+
+#[derive(Parser)]
+#[command(version, about = "controls dotmatrix signs over the command port")]
+struct Cli {
+    /// the sign, as `host` or `host:port`
+    #[arg(short, long, env = "DOTMATRIX_SIGN", global = true)]
+    sign: Option<String>,
+    /// `name:secret`, for signs that want a key
+    #[arg(
+        short,
+        long,
+        env = "DOTMATRIX_KEY",
+        hide_env_values = true,
+        global = true
+    )]
+    key: Option<String>,
+    /// how many more times to try connecting before giving up
+    #[arg(long, default_value_t = 3, global = true)]
+    retries: u32,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// shows a message. without options it's urgent and flashes first,
+    /// like command `0`; with any, it's queued like command `8`
+    Message {
+        #[arg(required = true, trailing_var_arg = true)]
+        text: Vec<String>,
+        /// 0-255, 200 and up cut short whatever is showing
+        #[arg(short, long)]
+        priority: Option<u8>,
+        /// how many seconds it stays queued
+        #[arg(short, long)]
+        ttl: Option<u32>,
+        /// how many times it's shown
+        #[arg(short = 'n', long)]
+        times: Option<u8>,
+        /// `alert` or `scroll`
+        #[arg(short, long)]
+        effect: Option<String>,
+    },
+    /// pushes a clock string, or goes back to the synced clock without one
+    Clock { text: Option<String> },
+    /// the clock's timezone and format, e.g. `-300 us 12`
+    #[command(allow_negative_numbers = true)]
+    Timezone {
+        /// minutes from utc
+        offset: i16,
+        /// `none`, `eu` or `us`
+        dst: String,
+        /// `12` or `24`
+        hours: u8,
+    },
+    /// pushes the weather, free-form or as `=<temp><C|F>,<sky>,...`
+    Weather {
+        #[arg(required = true, trailing_var_arg = true)]
+        text: Vec<String>,
+    },
+    /// the unit weather reports are shown in, `C` or `F`
+    Unit { unit: char },
+    /// 0 (off) to 8
+    Brightness { level: u8 },
+    /// draws a 32x8 picture, as 64 hex digits (dotmatrix-frames makes
+    /// them from pngs)
+    Image { hex: String },
+    /// what the sign is up to
+    Status,
+    /// what the displays are showing, in hex or as text
+    Read {
+        #[arg(long)]
+        art: bool,
+    },
+    /// what plays between messages
+    #[command(subcommand)]
+    Playlist(Playlist),
+    /// messages waiting to be shown
+    #[command(subcommand)]
+    Queue(Queue),
+    /// signs on the local network that answer mdns
+    Discover {
+        /// how long to listen for answers, in seconds
+        #[arg(long, default_value_t = 2)]
+        wait: u64,
+    },
+    /// any command, as it would be typed into nc
+    Send { command: String },
+    /// commands from stdin, one per line, over one connection
+    Batch {
+        /// carries on after a command fails
+        #[arg(long)]
+        keep_going: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum Playlist {
+    List,
+    /// e.g. `d=10 r=2 w=0900-1800 text HELLO`, see the README
+    Add {
+        #[arg(required = true, trailing_var_arg = true)]
+        slot: Vec<String>,
+    },
+    Del {
+        index: usize,
+    },
+    On {
+        index: usize,
+    },
+    Off {
+        index: usize,
+    },
+    Clear,
+    /// back to the built-in playlist
+    Reset,
+}
+
+#[derive(Subcommand)]
+enum Queue {
+    List,
+    Del { id: u16 },
+    Clear,
+}
+
+impl Command {
+    /// the command as the sign takes it, for those that are one
+    fn line(&self) -> Option<String> {
+        let line = match self {
+            Self::Message {
+                text,
+                priority,
+                ttl,
+                times,
+                effect,
+            } => {
+                let text = text.join(" ");
+                let mut options = String::new();
+                if let Some(p) = priority {
+                    options.push_str(&format!("p={} ", p));
+                }
+                if let Some(t) = ttl {
+                    options.push_str(&format!("t={} ", t));
+                }
+                if let Some(n) = times {
+                    options.push_str(&format!("n={} ", n));
+                }
+                if let Some(e) = effect {
+                    options.push_str(&format!("e={} ", e));
+                }
+                match options.is_empty() {
+                    true => format!("0{}", text),
+                    false => format!("8{}{}", options, text),
+                }
+            }
+            Self::Clock { text } => {
+                format!("1{}", text.as_deref().unwrap_or(""))
+            }
+            Self::Timezone { offset, dst, hours } => {
+                format!("5{} {} {}", offset, dst, hours)
+            }
+            Self::Weather { text } => format!("2{}", text.join(" ")),
+            Self::Unit { unit } => format!("6{}", unit),
+            Self::Brightness { level } => format!("4{}", level),
+            Self::Image { hex } => format!("3{}", hex),
+            Self::Status => "STATUS".to_string(),
+            Self::Read { art: false } => "r".to_string(),
+            Self::Read { art: true } => "rart".to_string(),
+            Self::Playlist(verb) => match verb {
+                Playlist::List => "7list".to_string(),
+                Playlist::Add { slot } => format!("7add {}", slot.join(" ")),
+                Playlist::Del { index } => format!("7del {}", index),
+                Playlist::On { index } => format!("7on {}", index),
+                Playlist::Off { index } => format!("7off {}", index),
+                Playlist::Clear => "7clear".to_string(),
+                Playlist::Reset => "7reset".to_string(),
+            },
+            Self::Queue(verb) => match verb {
+                Queue::List => "9list".to_string(),
+                Queue::Del { id } => format!("9del {}", id),
+                Queue::Clear => "9clear".to_string(),
+            },
+            Self::Send { command } => command.clone(),
+            Self::Discover { .. } | Self::Batch { .. } => return None,
+        };
+        Some(line)
+    }
+}
+
+/// a reply without the decorations, e.g. `queued 3` for
+/// `[*] queued 3 ^-^`
+fn tidy(reply: &str) -> String {
+    let mut tidy = String::new();
+    for line in reply.lines() {
+        let line = line.strip_prefix("[*] ").unwrap_or(line);
+        tidy.push_str(line.strip_suffix(" ^-^").unwrap_or(line));
+        tidy.push('\n');
+    }
+    tidy
+}
+
+/// how the process exits when something goes wrong: 3 when the sign said
+/// no, 1 for anything else
+fn exit_code(e: &Error) -> ExitCode {
+    match e {
+        Error::Sign { .. } => ExitCode::from(3),
+        _ => ExitCode::FAILURE,
+    }
+}
+
+fn fail(e: Error) -> ExitCode {
+    eprintln!("dotmatrix-cli: {}", e);
+    exit_code(&e)
+}
+
+fn batch(sign: &mut Sign, keep_going: bool) -> ExitCode {
+    let mut status = ExitCode::SUCCESS;
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return fail(e.into()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match sign.send(line.trim()) {
+            Ok(reply) => print!("{}", tidy(&reply)),
+            Err(e) if keep_going => status = fail(e),
+            Err(e) => return fail(e),
+        }
+    }
+    status
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    if let Command::Discover { wait } = cli.command {
+        return match discover::discover(Duration::from_secs(wait)) {
+            Ok(signs) => {
+                for (found, from) in signs {
+                    let ip = from.ip();
+                    println!("{}.local {}:{}", found.host, ip, found.port);
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => fail(e.into()),
+        };
+    }
+
+    let Some(host) = cli.sign else {
+        eprintln!("dotmatrix-cli: which sign? pass --sign or DOTMATRIX_SIGN");
+        return ExitCode::from(2);
+    };
+    let mut sign = Sign::new(&host, cli.key.as_deref());
+    sign.retries = cli.retries;
+
+    if let Command::Batch { keep_going } = cli.command {
+        return batch(&mut sign, keep_going);
+    }
+    let line = cli.command.line().expect("everything else is a command");
+    match sign.send(&line) {
+        Ok(reply) => {
+            print!("{}", tidy(&reply));
+            ExitCode::SUCCESS
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(args: &[&str]) -> String {
+        let args = ["dotmatrix-cli"].iter().chain(args);
+        Cli::try_parse_from(args).unwrap().command.line().unwrap()
+    }
+
+    #[test]
+    fn messages_are_urgent_unless_told_otherwise() {
+        assert_eq!(line(&["message", "HELLO", "WORLD"]), "0HELLO WORLD");
+        assert_eq!(
+            line(&["message", "-p", "250", "-t", "60", "-n", "3", "DRILL"]),
+            "8p=250 t=60 n=3 DRILL"
+        );
+        assert_eq!(line(&["message", "-e", "scroll", "HI"]), "8e=scroll HI");
+    }
+
+    #[test]
+    fn commands_map_to_the_protocol() {
+        assert_eq!(line(&["clock"]), "1");
+        assert_eq!(line(&["clock", "12:34"]), "112:34");
+        assert_eq!(line(&["timezone", "-300", "us", "12"]), "5-300 us 12");
+        assert_eq!(line(&["weather", "=23C,sun"]), "2=23C,sun");
+        assert_eq!(line(&["unit", "F"]), "6F");
+        assert_eq!(line(&["brightness", "3"]), "43");
+        assert_eq!(line(&["status"]), "STATUS");
+        assert_eq!(line(&["read", "--art"]), "rart");
+        assert_eq!(
+            line(&["playlist", "add", "d=5", "text", "HI"]),
+            "7add d=5 text HI"
+        );
+        assert_eq!(line(&["playlist", "off", "2"]), "7off 2");
+        assert_eq!(line(&["queue", "del", "7"]), "9del 7");
+        assert_eq!(line(&["send", "wshow"]), "wshow");
+    }
+
+    #[test]
+    fn replies_lose_their_decorations() {
+        assert_eq!(tidy("[*] queued 3 ^-^\n"), "queued 3\n");
+        assert_eq!(tidy("0 clock\n1 weather\n"), "0 clock\n1 weather\n");
+    }
+}
@@ -0,0 +1,205 @@
+//! the cli against the simulator, both as built. every test gets a
+//! simulator of its own, since each one is a whole sign's worth of state
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+struct Sim {
+    child: Child,
+    address: String,
+}
+
+impl Sim {
+    fn start(args: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_dotmatrix-sim"))
+            .args(["--port", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        let stdout = child.stdout.as_mut().unwrap();
+        BufReader::new(stdout).read_line(&mut line).unwrap();
+        let address = line.trim().strip_prefix("listening on ").unwrap();
+        Self {
+            address: address.to_string(),
+            child,
+        }
+    }
+
+    fn cli(&self, args: &[&str]) -> Output {
+        self.cli_with(args, |_| {})
+    }
+
+    /// runs the cli with `feed` writing its stdin
+    fn cli_with(
+        &self,
+        args: &[&str],
+        feed: impl FnOnce(&mut dyn Write),
+    ) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_dotmatrix-cli"))
+            .args(["--sign", &self.address, "--retries", "0"])
+            .args(args)
+            .env_remove("DOTMATRIX_KEY")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        feed(&mut child.stdin.take().unwrap());
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn messages_are_queued() {
+    let sim = Sim::start(&[]);
+    let queued = sim.cli(&["message", "-p", "150", "-t", "60", "FIRE DRILL"]);
+    assert!(stdout(&queued).starts_with("queued "));
+    let list = stdout(&sim.cli(&["queue", "list"]));
+    assert!(
+        list.contains("p=150 t=60 n=1 e=alert FIRE DRILL"),
+        "{}",
+        list
+    );
+
+    assert_eq!(stdout(&sim.cli(&["queue", "clear"])), "queue updated!\n");
+    assert_eq!(stdout(&sim.cli(&["queue", "list"])), "nothing queued\n");
+}
+
+#[test]
+fn the_playlist_is_edited() {
+    let sim = Sim::start(&[]);
+    stdout(&sim.cli(&["playlist", "clear"]));
+    stdout(&sim.cli(&["playlist", "add", "d=5", "text", "HI THERE"]));
+    stdout(&sim.cli(&["playlist", "add", "clock"]));
+    stdout(&sim.cli(&["playlist", "off", "1"]));
+    let list = stdout(&sim.cli(&["playlist", "list"]));
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", list);
+    assert!(lines[0].starts_with("0 ") && lines[0].ends_with("HI THERE"));
+    assert!(lines[1].contains("off"), "{}", list);
+}
+
+#[test]
+fn settings_and_pictures() {
+    let sim = Sim::start(&[]);
+    stdout(&sim.cli(&["clock", "12:34"]));
+    stdout(&sim.cli(&["weather", "=23C,rain,45"]));
+    stdout(&sim.cli(&["unit", "F"]));
+    stdout(&sim.cli(&["timezone", "-300", "us", "12"]));
+    stdout(&sim.cli(&["brightness", "3"]));
+    let hex = format!("ff{}", "0".repeat(62));
+    assert_eq!(stdout(&sim.cli(&["image", &hex])), "picture drawn!\n");
+    let list = stdout(&sim.cli(&["queue", "list"]));
+    assert!(list.contains("<image>"), "{}", list);
+
+    let status = stdout(&sim.cli(&["status"]));
+    assert!(status.starts_with("version="), "{}", status);
+    assert!(status.contains(" queue=1 "), "{}", status);
+    assert_eq!(stdout(&sim.cli(&["read", "--art"])).lines().count(), 8);
+}
+
+#[test]
+fn errors_come_back_with_their_code() {
+    let sim = Sim::start(&[]);
+    let refused = sim.cli(&["playlist", "del", "99"]);
+    assert_eq!(refused.status.code(), Some(3));
+    assert!(stderr(&refused).contains("error 4: out of range"));
+
+    let refused = sim.cli(&["weather", "=hot"]);
+    assert_eq!(refused.status.code(), Some(3));
+    assert!(stderr(&refused).contains("error 5"), "{:?}", refused);
+
+    // and the connection is still good for the next one
+    stdout(&sim.cli(&["status"]));
+}
+
+#[test]
+fn keys_are_asked_for() {
+    let sim = Sim::start(&["--key", "laptop rw hunter2"]);
+    let no_key = sim.cli(&["status"]);
+    assert_eq!(no_key.status.code(), Some(1));
+    assert!(stderr(&no_key).contains("pass --key"));
+
+    let status = sim.cli(&["--key", "laptop:hunter2", "status"]);
+    assert!(stdout(&status).starts_with("version="));
+
+    let wrong = sim.cli(&["--key", "laptop:hunter3", "status"]);
+    assert_eq!(wrong.status.code(), Some(1));
+    assert!(stderr(&wrong).contains("auth failed"), "{:?}", wrong);
+}
+
+#[test]
+fn read_only_keys_only_look() {
+    let sim = Sim::start(&["--key", "dash ro s3cret"]);
+    let key = ["--key", "dash:s3cret"];
+    stdout(&sim.cli(&[&key[..], &["queue", "list"]].concat()));
+    let denied = sim.cli(&[&key[..], &["message", "HI"]].concat());
+    assert_eq!(denied.status.code(), Some(3));
+    assert!(stderr(&denied).contains("error 9"), "{:?}", denied);
+}
+
+#[test]
+fn a_batch_survives_the_sign_hanging_up() {
+    let sim = Sim::start(&["--idle", "1"]);
+    let output = sim.cli_with(&["batch"], |stdin| {
+        writeln!(stdin, "8p=10 FIRST").unwrap();
+        stdin.flush().unwrap();
+        // long enough for the sign to drop the connection
+        thread::sleep(Duration::from_millis(1500));
+        writeln!(stdin, "8p=10 SECOND").unwrap();
+        writeln!(stdin, "9list").unwrap();
+    });
+    let out = stdout(&output);
+    assert!(out.contains("FIRST") && out.contains("SECOND"), "{}", out);
+    assert_eq!(out.matches("queued").count(), 2, "{}", out);
+}
+
+#[test]
+fn a_batch_stops_at_the_first_error_unless_told() {
+    let sim = Sim::start(&[]);
+    let feed = |stdin: &mut dyn Write| {
+        writeln!(stdin, "7del 99\n4 3").unwrap();
+    };
+    let stopped = sim.cli_with(&["batch"], feed);
+    assert_eq!(stopped.status.code(), Some(3));
+    assert!(!String::from_utf8_lossy(&stopped.stdout).contains("brightness"));
+
+    let kept_going = sim.cli_with(&["batch", "--keep-going"], feed);
+    assert_eq!(kept_going.status.code(), Some(3));
+    let out = String::from_utf8_lossy(&kept_going.stdout);
+    assert_eq!(out, "brightness set!\n");
+}
+
+#[test]
+fn nothing_listening() {
+    let sim = Sim::start(&[]);
+    let address = sim.address.clone();
+    drop(sim);
+    let output = Command::new(env!("CARGO_BIN_EXE_dotmatrix-cli"))
+        .args(["--sign", &address, "--retries", "1", "status"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr(&output).is_empty());
+}
@@ -0,0 +1,207 @@
+//! pictures in, 1-bit frames out. the panels are either on or off (the
+//! brightness is one setting for the whole sign), so there's no grayscale
+//! to keep: each pixel is thresholded, or dithered to fake one
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use dotmatrix::graphics::{self, Bitmap, Graphic, EMPTY, STRIP_WIDTH};
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, ImageFormat, RgbaImage};
+
+use crate::Error;
+
+/// how far each pixel is from lighting up, as in a 4x4 bayer matrix.
+/// spread over 0-255 it makes a steady pattern, so frames of an animation
+/// don't shimmer like they would with error diffusion
+const BAYER: [[u8; 4]; 4] =
+    [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// lit when at least this bright
+    Threshold(u8),
+    Dither,
+}
+
+/// one picture, a pixel per bool, row by row
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub lit: Vec<bool>,
+    /// how long it stays up, 0 for a still picture
+    pub delay_ms: u32,
+}
+
+/// the frames of a png (just one) or a gif, in full colour
+pub fn load(path: &Path) -> Result<Vec<(RgbaImage, u32)>, Error> {
+    if ImageFormat::from_path(path)? == ImageFormat::Gif {
+        let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+        // the decoder draws each frame over the ones before it
+        let frames = decoder.into_frames().collect_frames()?;
+        return Ok(frames
+            .into_iter()
+            .map(|f| {
+                let (numer, denom) = f.delay().numer_denom_ms();
+                (f.into_buffer(), numer / denom.max(1))
+            })
+            .collect());
+    }
+    Ok(vec![(image::open(path)?.into_rgba8(), 0)])
+}
+
+/// how bright a pixel looks, with see-through counting as dark
+fn brightness([r, g, b, a]: [u8; 4]) -> u8 {
+    let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+    (luma * a as u32 / 255) as u8
+}
+
+pub fn to_frame(
+    image: &RgbaImage,
+    delay_ms: u32,
+    mode: Mode,
+    invert: bool,
+) -> Frame {
+    let lit = image
+        .enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            let level = brightness(pixel.0);
+            let on = match mode {
+                Mode::Threshold(t) => level >= t,
+                Mode::Dither => {
+                    let step = BAYER[y as usize % 4][x as usize % 4];
+                    level > step * 16 + 8
+                }
+            };
+            on != invert
+        })
+        .collect();
+    Frame {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        lit,
+        delay_ms,
+    }
+}
+
+impl Frame {
+    fn is_lit(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.lit[y * self.width + x]
+    }
+
+    /// cut into panel sized graphics, left to right and then top to
+    /// bottom. the last row and column of them are padded with dark
+    /// pixels
+    pub fn graphics(&self) -> Vec<Graphic> {
+        let mut tiles = Vec::new();
+        for top in (0..self.height).step_by(8) {
+            for left in (0..self.width).step_by(8) {
+                let mut tile = EMPTY;
+                for (y, row) in tile.iter_mut().enumerate() {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        *pixel = self.is_lit(left + x, top + y) as u8;
+                    }
+                }
+                tiles.push(tile);
+            }
+        }
+        tiles
+    }
+
+    /// the whole sign at once, for frames that are exactly its size
+    pub fn bitmap(&self) -> Result<Bitmap, Error> {
+        if (self.width, self.height) != (STRIP_WIDTH, 8) {
+            return Err(Error::Size(self.width, self.height));
+        }
+        let tiles = self.graphics();
+        let panels = [tiles[0], tiles[1], tiles[2], tiles[3]];
+        Ok(graphics::join_graphics(&panels))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /// a frame with only its top left pixel lit
+    pub(crate) fn frame(width: usize, height: usize, delay_ms: u32) -> Frame {
+        let mut lit = vec![false; width * height];
+        lit[0] = true;
+        Frame {
+            width,
+            height,
+            lit,
+            delay_ms,
+        }
+    }
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(16, 8, |x, _| {
+            let level = (x * 17) as u8;
+            Rgba([level, level, level, 255])
+        })
+    }
+
+    #[test]
+    fn threshold_splits_at_the_level() {
+        let frame = to_frame(&gradient(), 0, Mode::Threshold(128), false);
+        let row: Vec<bool> = frame.lit[..16].to_vec();
+        assert_eq!(row.iter().position(|&on| on), Some(8));
+        assert!(row[8..].iter().all(|&on| on));
+
+        let inverted = to_frame(&gradient(), 0, Mode::Threshold(128), true);
+        assert!(frame.lit.iter().zip(&inverted.lit).all(|(a, b)| a != b));
+    }
+
+    #[test]
+    fn see_through_pixels_stay_dark() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 0]));
+        let frame = to_frame(&image, 0, Mode::Threshold(1), false);
+        assert!(frame.lit.iter().all(|&on| !on));
+    }
+
+    #[test]
+    fn dithering_lights_more_of_brighter_pixels() {
+        let lit_at = |level| {
+            let image =
+                RgbaImage::from_pixel(8, 8, Rgba([level, level, level, 255]));
+            let frame = to_frame(&image, 0, Mode::Dither, false);
+            frame.lit.iter().filter(|&&on| on).count()
+        };
+        assert_eq!(lit_at(0), 0);
+        assert_eq!(lit_at(255), 64);
+        assert!((28..=36).contains(&lit_at(128)));
+    }
+
+    #[test]
+    fn graphics_are_cut_left_to_right_then_down() {
+        let mut image = RgbaImage::new(12, 9);
+        image.put_pixel(0, 0, Rgba([255; 4]));
+        image.put_pixel(9, 1, Rgba([255; 4]));
+        image.put_pixel(2, 8, Rgba([255; 4]));
+        let frame = to_frame(&image, 0, Mode::Threshold(128), false);
+        let tiles = frame.graphics();
+        assert_eq!(tiles.len(), 4);
+        assert_eq!(tiles[0][0][0], 1);
+        assert_eq!(tiles[1][1][1], 1);
+        assert_eq!(tiles[2][0][2], 1);
+        assert_eq!(tiles[3], EMPTY);
+    }
+
+    #[test]
+    fn only_sign_sized_frames_make_a_bitmap() {
+        let mut image = RgbaImage::new(32, 8);
+        image.put_pixel(0, 0, Rgba([255; 4]));
+        image.put_pixel(31, 7, Rgba([255; 4]));
+        let frame = to_frame(&image, 0, Mode::Threshold(128), false);
+        let bitmap = frame.bitmap().unwrap();
+        assert_eq!(bitmap[0], 0x80);
+        assert_eq!(bitmap[31], 0x01);
+
+        let small = to_frame(&gradient(), 0, Mode::Dither, false);
+        assert!(matches!(small.bitmap(), Err(Error::Size(16, 8))));
+    }
+}
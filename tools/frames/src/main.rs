@@ -0,0 +1,203 @@
+//! turns pixel art into frames for the sign: a png is one frame, and a gif
+//! one per frame of its animation. see "pixel art" in the main README
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use dotmatrix_cli::client::Sign;
+
+mod convert;
+mod output;
+mod upload;
+
+use convert::{Frame, Mode};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "turns png and gif pixel art into dotmatrix frames"
+)]
+struct Cli {
+    #[command(subcommand)]
+    output: Output,
+}
+
+#[derive(Subcommand)]
+enum Output {
+    /// `Graphic` constants to paste into src/graphics.rs, one per 8x8 piece
+    Rust {
+        #[command(flatten)]
+        input: Input,
+        /// what the constants are called
+        #[arg(long, default_value = "picture")]
+        name: String,
+    },
+    /// a binary blob of 32x8 frames, see the README for the layout
+    Blob {
+        #[command(flatten)]
+        input: Input,
+        /// where to write it, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// draws a 32x8 picture on a sign, or adds an animation's frames to its
+    /// playlist. the playlist counts in whole seconds, so faster frames are
+    /// slowed down to one second each
+    Upload {
+        #[command(flatten)]
+        input: Input,
+        /// the sign, as `host` or `host:port`
+        #[arg(long)]
+        to: String,
+        /// `name:secret`, for signs that want a key
+        #[arg(long, env = "DOTMATRIX_KEY", hide_env_values = true)]
+        key: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct Input {
+    /// a png or gif
+    path: PathBuf,
+    /// how bright a pixel has to be to light up, 0-255
+    #[arg(long, default_value_t = 128, conflicts_with = "dither")]
+    threshold: u8,
+    /// dithers instead, for pictures with shades in them
+    #[arg(long)]
+    dither: bool,
+    /// lights the dark pixels instead
+    #[arg(long)]
+    invert: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Image(image::ImageError),
+    /// the frame is this wide and high, instead of 32x8
+    Size(usize, usize),
+    /// more frames than a blob can count
+    TooMany,
+    /// a line of the sign's playlist that couldn't be read back
+    Listing(String),
+    /// only `fits` of the animation's frames would fit in the playlist
+    NoRoom {
+        frames: usize,
+        fits: usize,
+    },
+    /// the sign, or the way to it
+    Sign(dotmatrix_cli::client::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Image(e) => write!(f, "{}", e),
+            Self::Size(w, h) => {
+                write!(f, "the frames are {}x{}, but the sign is 32x8", w, h)
+            }
+            Self::TooMany => write!(f, "too many frames"),
+            Self::Listing(line) => {
+                write!(f, "couldn't read the sign's playlist at {:?}", line)
+            }
+            Self::NoRoom { frames, fits } => write!(
+                f,
+                "the playlist has room for {} of the {} frames, \
+                 nothing was added",
+                fits, frames
+            ),
+            Self::Sign(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<dotmatrix_cli::client::Error> for Error {
+    fn from(e: dotmatrix_cli::client::Error) -> Self {
+        Self::Sign(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+impl Input {
+    fn frames(&self) -> Result<Vec<Frame>, Error> {
+        let mode = match self.dither {
+            true => Mode::Dither,
+            false => Mode::Threshold(self.threshold),
+        };
+        let frames = convert::load(&self.path)?
+            .iter()
+            .map(|(image, delay_ms)| {
+                convert::to_frame(image, *delay_ms, mode, self.invert)
+            })
+            .collect();
+        Ok(frames)
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    match cli.output {
+        Output::Rust { input, name } => {
+            let mut consts = String::new();
+            output::write_consts(&mut consts, &name, &input.frames()?)
+                .expect("a string takes anything");
+            io::stdout().write_all(consts.as_bytes())?;
+        }
+        Output::Blob { input, output } => {
+            let blob = output::blob(&input.frames()?)?;
+            match output {
+                Some(path) => std::fs::write(path, blob)?,
+                None => io::stdout().write_all(&blob)?,
+            }
+        }
+        Output::Upload { input, to, key } => {
+            let frames = input.frames()?;
+            let mut sign = Sign::new(&to, key.as_deref());
+            let commands = match frames.as_slice() {
+                [frame] => vec![upload::picture(frame)?],
+                frames => {
+                    let listing = sign.send("7list")?;
+                    let playlist = upload::playlist(&listing)?;
+                    upload::animation(frames, playlist)?
+                }
+            };
+            let slowed = upload::slowed(&frames);
+            if commands.len() > 1 && slowed > 0 {
+                eprintln!(
+                    "dotmatrix-frames: {} of the {} frames are slowed down \
+                     to whole seconds",
+                    slowed,
+                    frames.len()
+                );
+            }
+            for command in commands {
+                eprint!("{}", sign.send(&command)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("dotmatrix-frames: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
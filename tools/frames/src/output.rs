@@ -0,0 +1,118 @@
+//! what the frames turn into: rust constants to paste into
+//! `src/graphics.rs`, or a blob for anything that wants them whole
+
+use std::fmt::{self, Write};
+
+use dotmatrix::graphics::Graphic;
+
+use crate::convert::Frame;
+use crate::Error;
+
+/// starts every blob, followed by a format version
+pub const BLOB_MAGIC: &[u8; 4] = b"DMXA";
+pub const BLOB_VERSION: u8 = 1;
+
+/// one `Graphic` constant per panel sized piece, written out like the
+/// glyphs in `graphics.rs`. they're called `<name>` when there's only the
+/// one, and numbered by frame and piece otherwise
+pub fn write_consts(
+    out: &mut impl Write,
+    name: &str,
+    frames: &[Frame],
+) -> fmt::Result {
+    let name = name.to_uppercase();
+    for (f, frame) in frames.iter().enumerate() {
+        let graphics = frame.graphics();
+        for (g, graphic) in graphics.iter().enumerate() {
+            let suffix = match (frames.len(), graphics.len()) {
+                (1, 1) => String::new(),
+                (1, _) => format!("_{}", g),
+                (_, 1) => format!("_{}", f),
+                _ => format!("_{}_{}", f, g),
+            };
+            if f + g > 0 {
+                writeln!(out)?;
+            }
+            if frame.delay_ms > 0 && g == 0 {
+                writeln!(out, "// frame {}, {}ms", f, frame.delay_ms)?;
+            }
+            write_graphic(out, &format!("{}{}", name, suffix), graphic)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_graphic(
+    out: &mut impl Write,
+    name: &str,
+    graphic: &Graphic,
+) -> fmt::Result {
+    writeln!(out, "pub const {}: Graphic = [", name)?;
+    for row in graphic {
+        let pixels: Vec<String> = row.iter().map(u8::to_string).collect();
+        writeln!(out, "    [{}],", pixels.join(", "))?;
+    }
+    writeln!(out, "];")
+}
+
+/// `DMXA`, the version, the number of frames (u16, little endian), then
+/// for each frame how long it stays up in ms (u16, little endian) and the
+/// 32 bytes of the picture, 4 per row with the msb leftmost (the same as
+/// command `3` takes in hex). frames have to be 32x8
+pub fn blob(frames: &[Frame]) -> Result<Vec<u8>, Error> {
+    let count = u16::try_from(frames.len()).map_err(|_| Error::TooMany)?;
+    let mut blob = BLOB_MAGIC.to_vec();
+    blob.push(BLOB_VERSION);
+    blob.extend(count.to_le_bytes());
+    for frame in frames {
+        let delay = frame.delay_ms.min(u16::MAX.into()) as u16;
+        blob.extend(delay.to_le_bytes());
+        blob.extend(frame.bitmap()?);
+    }
+    Ok(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::tests::frame;
+
+    #[test]
+    fn one_graphic_keeps_the_name() {
+        let mut out = String::new();
+        write_consts(&mut out, "heart", &[frame(8, 8, 0)]).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("pub const HEART: Graphic = ["));
+        assert_eq!(lines.next(), Some("    [1, 0, 0, 0, 0, 0, 0, 0],"));
+        assert_eq!(lines.last(), Some("];"));
+    }
+
+    #[test]
+    fn animations_are_numbered_by_frame_and_piece() {
+        let mut out = String::new();
+        let frames = [frame(16, 8, 100), frame(16, 8, 250)];
+        write_consts(&mut out, "wave", &frames).unwrap();
+        let names: Vec<&str> = out
+            .lines()
+            .filter_map(|l| l.strip_prefix("pub const "))
+            .filter_map(|l| l.split(':').next())
+            .collect();
+        assert_eq!(names, ["WAVE_0_0", "WAVE_0_1", "WAVE_1_0", "WAVE_1_1"]);
+        assert!(out.contains("// frame 1, 250ms"));
+    }
+
+    #[test]
+    fn blob_layout() {
+        let blob = blob(&[frame(32, 8, 100), frame(32, 8, 70000)]).unwrap();
+        assert_eq!(blob.len(), 4 + 1 + 2 + 2 * (2 + 32));
+        assert_eq!(&blob[..7], b"DMXA\x01\x02\x00");
+        assert_eq!(&blob[7..10], [100, 0, 0x80]);
+        // too long for a u16, so it's as long as it can be
+        assert_eq!(&blob[41..43], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn blobs_are_sign_sized() {
+        assert!(matches!(blob(&[frame(8, 8, 0)]), Err(Error::Size(8, 8))));
+    }
+}
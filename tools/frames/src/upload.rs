@@ -0,0 +1,142 @@
+//! what to send a sign for some frames. a still picture is drawn with
+//! command `3`; the frames of an animation are added to the playlist, since
+//! that's the only place the sign keeps more than one. the playlist counts
+//! in whole seconds, so each frame stays up for at least one, rounded up
+
+use dotmatrix::graphics::{self, Bitmap};
+use dotmatrix::playlist::{Content, Playlist, Slot};
+
+use crate::convert::Frame;
+use crate::Error;
+
+fn hex(bitmap: &Bitmap) -> String {
+    let mut hex = String::new();
+    graphics::write_hex(&mut hex, bitmap).expect("a string takes anything");
+    hex
+}
+
+/// command `3`, to draw one picture
+pub fn picture(frame: &Frame) -> Result<String, Error> {
+    Ok(format!("3{}", hex(&frame.bitmap()?)))
+}
+
+/// the playlist back from what `7list` answered, so what's added can be
+/// checked against the same limits the sign has
+pub fn playlist(listing: &str) -> Result<Playlist, Error> {
+    let mut playlist = Playlist::new();
+    for line in listing.lines() {
+        if line.starts_with("[*]") {
+            continue;
+        }
+        let unreadable = || Error::Listing(line.to_string());
+        let slot = line.split_once(' ').and_then(|(_, s)| Slot::parse(s));
+        playlist
+            .add(slot.ok_or_else(unreadable)?)
+            .map_err(|_| unreadable())?;
+    }
+    Ok(playlist)
+}
+
+/// the `7add` commands for an animation, one slot a frame. nothing comes
+/// back unless all of them fit after what's in `playlist` already, so an
+/// animation is never half added
+pub fn animation(
+    frames: &[Frame],
+    mut playlist: Playlist,
+) -> Result<Vec<String>, Error> {
+    let mut commands = Vec::new();
+    for frame in frames {
+        let mut slot = Slot::new(Content::Image(frame.bitmap()?));
+        slot.duration_secs = secs(frame);
+        let mut command = String::from("7add ");
+        slot.write(&mut command).expect("a string takes anything");
+        playlist.add(slot).map_err(|_| Error::NoRoom {
+            frames: frames.len(),
+            fits: commands.len(),
+        })?;
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+fn secs(frame: &Frame) -> u16 {
+    frame.delay_ms.div_ceil(1000).clamp(1, u16::MAX.into()) as u16
+}
+
+/// how many of `frames` the playlist will show for longer than asked
+pub fn slowed(frames: &[Frame]) -> usize {
+    frames
+        .iter()
+        .filter(|f| u64::from(secs(f)) * 1000 > u64::from(f.delay_ms))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use dotmatrix::playlist::MAX_SLOTS;
+
+    use super::*;
+
+    fn frame(delay_ms: u32) -> Frame {
+        crate::convert::tests::frame(32, 8, delay_ms)
+    }
+
+    #[test]
+    fn a_still_picture_is_drawn() {
+        let command = picture(&frame(0)).unwrap();
+        assert_eq!(command, format!("380{}", "0".repeat(62)));
+    }
+
+    #[test]
+    fn animations_go_to_the_playlist() {
+        let frames = [frame(100), frame(2500), frame(3000)];
+        let commands = animation(&frames, Playlist::new()).unwrap();
+        assert_eq!(commands.len(), 3);
+        assert!(commands[0].starts_with("7add d=1 r=1 image 80"));
+        assert!(commands[1].starts_with("7add d=3 r=1 image 80"));
+        assert!(commands[2].starts_with("7add d=3 r=1 image 80"));
+        // the first two are stretched to whole seconds
+        assert_eq!(slowed(&frames), 2);
+    }
+
+    #[test]
+    fn only_frames_shown_longer_are_slowed() {
+        let long = [frame(0), frame(1000), frame(300_000), frame(u32::MAX)];
+        let commands = animation(&long, Playlist::new()).unwrap();
+        assert!(commands[2].starts_with("7add d=300 r=1 image"));
+        // the longest a slot can be, so this one is cut short instead
+        assert!(commands[3].starts_with("7add d=65535 r=1 image"));
+        assert_eq!(slowed(&long), 1);
+    }
+
+    #[test]
+    fn the_playlist_is_read_back_from_a_listing() {
+        let listing = "0 d=5 r=1 clock\n1 d=5 r=2 off text HI THERE\n";
+        let read = playlist(listing).unwrap();
+        assert_eq!(read.slots().len(), 2);
+        assert_eq!(read.slots()[1].repeat, 2);
+        assert!(!read.slots()[1].enabled);
+
+        let empty = playlist("[*] the playlist is empty\n").unwrap();
+        assert!(empty.slots().is_empty());
+        assert!(matches!(
+            playlist("0 d=5 r=1 sparkles\n"),
+            Err(Error::Listing(line)) if line == "0 d=5 r=1 sparkles"
+        ));
+    }
+
+    #[test]
+    fn nothing_is_added_unless_every_frame_fits() {
+        let listing: String = (0..MAX_SLOTS - 2)
+            .map(|i| format!("{} d=5 r=1 clock\n", i))
+            .collect();
+        let frames = [frame(100), frame(100), frame(100)];
+        let full = playlist(&listing).unwrap();
+        assert!(matches!(
+            animation(&frames, full),
+            Err(Error::NoRoom { frames: 3, fits: 2 })
+        ));
+        let full = playlist(&listing).unwrap();
+        assert_eq!(animation(&frames[..2], full).unwrap().len(), 2);
+    }
+}